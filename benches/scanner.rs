@@ -0,0 +1,71 @@
+// Measures the scanner in tokens per second, and the allocations made for
+// building tokens. Run with `cargo bench`, or pass a source file to measure it
+// instead of the built-in sample.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const SAMPLE: &str = r#"
+main() {
+    extern printf;
+    auto i, sum;
+
+    i = 0;
+    sum = 0;
+    while (i < 100) {
+        if (i % 3 == 0 || i % 5 == 0)
+            sum = sum + i;
+        i++;
+    }
+
+    printf("sum: %d\n", sum);
+    return (sum & 0377);
+}
+"#;
+
+const ITERATIONS: usize = 200;
+
+fn measure(name: &str, data: &[u8], scan: fn(&[u8]) -> usize) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let mut count = 0;
+
+    for _ in 0..ITERATIONS {
+        count += scan(data);
+    }
+
+    let seconds = start.elapsed().as_secs_f64();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    println!("{}: {} tokens in {:.3} s, {:.0} tokens/s, {:.3} allocations/token",
+             name, count, seconds, count as f64 / seconds, allocations as f64 / count as f64);
+}
+
+fn main() {
+    let data = match std::env::args().skip(1).find(|arg| !arg.starts_with('-')) {
+        Some(path) => std::fs::read(path).expect("can't read the input file"),
+        None => SAMPLE.repeat(100).into_bytes()
+    };
+
+    measure("count", &data, blang::count_tokens);
+    measure("tokens", &data, blang::scan_tokens);
+}
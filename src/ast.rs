@@ -1,11 +1,54 @@
 use crate::{file::FilePosition, token::TokenType};
+use std::rc::Rc;
 
 pub struct Decl {
     pub pos: FilePosition,
     pub kind: DeclKind
 }
 
-pub struct Variable(pub String, pub Option<Expr>);
+impl Decl {
+    // Where diagnostics about the node point, only the start for now.
+    pub fn span(&self) -> &FilePosition {
+        &self.pos
+    }
+}
+
+#[derive(Clone)]
+pub struct Variable {
+    pub name: String,
+    pub size: VariableSize,
+    pub initial: Vec<Expr>
+}
+
+impl Variable {
+    // `auto name[] "text";` is a local buffer holding the bytes of the string.
+    pub fn string_buffer(&self) -> Option<(&str, StringKind)> {
+        match (&self.size, self.initial.as_slice()) {
+            (VariableSize::Vector(None), [Expr { kind: ExprKind::StringLit(value, kind), .. }]) => Some((value, *kind)),
+            _ => None
+        }
+    }
+}
+
+impl Variable {
+    pub fn same_shape(&self, other: &Variable) -> bool {
+        let same_size = match (&self.size, &other.size) {
+            (VariableSize::Scalar, VariableSize::Scalar) => true,
+            (VariableSize::Vector(Some(a)), VariableSize::Vector(Some(b))) => a.same_shape(b),
+            (VariableSize::Vector(None), VariableSize::Vector(None)) => true,
+            _ => false
+        };
+
+        self.name == other.name && same_size && all_same_shape(&self.initial, &other.initial, Expr::same_shape)
+    }
+}
+
+#[derive(Clone)]
+pub enum VariableSize {
+    Scalar,
+    // The size of `name[]` comes from its initializers.
+    Vector(Option<Expr>)
+}
 
 pub enum DeclKind {
     Function {
@@ -25,26 +68,260 @@ pub struct Stmt {
 pub enum StmtKind {
     Block(Vec<Stmt>),
     Expr(Expr),
-    Auto(Variable),
+    // `auto a, b[2], c 1;` declares all of them in one statement.
+    Auto(Vec<Variable>),
     Extern(String),
     If(Expr, Box<Stmt>, Option<Box<Stmt>>),
     While(Expr, Option<Box<Stmt>>),
     DoWhile(Expr, Box<Stmt>),
     Return(Option<Expr>),
-    Break,
-    Continue
+    // Copied verbatim into the generated assembly.
+    Asm(String),
+    // The number of loops to exit, `break 2;` leaves two. Plain `break;` is 1.
+    Break(usize),
+    Continue(usize),
+    Empty
+}
+
+impl Stmt {
+    pub fn span(&self) -> &FilePosition {
+        &self.pos
+    }
+
+    // Structural equality, positions are ignored.
+    pub fn same_shape(&self, other: &Stmt) -> bool {
+        match (&self.kind, &other.kind) {
+            (StmtKind::Block(a), StmtKind::Block(b)) => all_same_shape(a, b, Stmt::same_shape),
+            (StmtKind::Expr(a), StmtKind::Expr(b)) => a.same_shape(b),
+            (StmtKind::Auto(a), StmtKind::Auto(b)) => all_same_shape(a, b, Variable::same_shape),
+            (StmtKind::Extern(a), StmtKind::Extern(b)) => a == b,
+
+            (StmtKind::If(cond_a, then_a, else_a), StmtKind::If(cond_b, then_b, else_b)) =>
+                cond_a.same_shape(cond_b) && then_a.same_shape(then_b) &&
+                same_shape_opt(else_a.as_deref(), else_b.as_deref(), Stmt::same_shape),
+
+            (StmtKind::While(cond_a, body_a), StmtKind::While(cond_b, body_b)) =>
+                cond_a.same_shape(cond_b) && same_shape_opt(body_a.as_deref(), body_b.as_deref(), Stmt::same_shape),
+
+            (StmtKind::DoWhile(cond_a, body_a), StmtKind::DoWhile(cond_b, body_b)) =>
+                cond_a.same_shape(cond_b) && body_a.same_shape(body_b),
+
+            (StmtKind::Return(a), StmtKind::Return(b)) => same_shape_opt(a.as_ref(), b.as_ref(), Expr::same_shape),
+            (StmtKind::Asm(a), StmtKind::Asm(b)) => a == b,
+
+            (StmtKind::Break(a), StmtKind::Break(b)) | (StmtKind::Continue(a), StmtKind::Continue(b)) => a == b,
+            (StmtKind::Empty, StmtKind::Empty) => true,
+
+            _ => false
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct Expr {
     pub pos: FilePosition,
     pub kind: ExprKind
 }
 
+#[derive(Clone)]
 pub enum ExprKind {
-    IntLit(i32),
-    StringLit(String),
+    // The value and the spelling in the source, `'a'` for a character.
+    // Literals made by folding have no spelling.
+    IntLit(i32, Option<Rc<str>>),
+    StringLit(String, StringKind),
     Var(String),
-    UnaryOp(TokenType, bool, Box<Expr>),
+    UnaryOp(TokenType, Fixity, Box<Expr>),
     BinOp(Box<Expr>, TokenType, Box<Expr>),
-    Ternary(Box<Expr>, Box<Expr>, Box<Expr>)
+    Assign(Box<Expr>, Box<Expr>),
+    Ternary(Box<Expr>, Box<Expr>, Box<Expr>),
+    Call(Box<Expr>, Vec<Expr>),
+    Index(Box<Expr>, Box<Expr>)
+}
+
+// `a[i]` is `*(a + i)`, so `i[v]` and `2[v]` index `v` too. Only the index is
+// scaled, so the operands are put in that order: the address is the operand
+// naming a vector or a string, else the one that is not an integer literal.
+pub fn index_operands<'a>(base: &'a Expr, index: &'a Expr, is_vector: impl Fn(&str) -> bool) -> (&'a Expr, &'a Expr) {
+    let is_address = |expr: &Expr| match &expr.kind {
+        ExprKind::Var(name) => is_vector(name),
+        ExprKind::StringLit(..) => true,
+        _ => false
+    };
+
+    let swap = if is_address(base) || is_address(index) {
+        is_address(index) && !is_address(base)
+    } else {
+        matches!(base.kind, ExprKind::IntLit(..)) && !matches!(index.kind, ExprKind::IntLit(..))
+    };
+
+    if swap { (index, base) } else { (base, index) }
+}
+
+impl Expr {
+    pub fn span(&self) -> &FilePosition {
+        &self.pos
+    }
+
+    // Structural equality, positions are ignored.
+    pub fn same_shape(&self, other: &Expr) -> bool {
+        match (&self.kind, &other.kind) {
+            (ExprKind::IntLit(a, _), ExprKind::IntLit(b, _)) => a == b,
+            (ExprKind::StringLit(a, kind_a), ExprKind::StringLit(b, kind_b)) => a == b && kind_a == kind_b,
+            (ExprKind::Var(a), ExprKind::Var(b)) => a == b,
+
+            (ExprKind::UnaryOp(op_a, fixity_a, a), ExprKind::UnaryOp(op_b, fixity_b, b)) =>
+                op_a == op_b && fixity_a == fixity_b && a.same_shape(b),
+
+            (ExprKind::BinOp(left_a, op_a, right_a), ExprKind::BinOp(left_b, op_b, right_b)) =>
+                op_a == op_b && left_a.same_shape(left_b) && right_a.same_shape(right_b),
+
+            (ExprKind::Assign(left_a, right_a), ExprKind::Assign(left_b, right_b)) |
+            (ExprKind::Index(left_a, right_a), ExprKind::Index(left_b, right_b)) =>
+                left_a.same_shape(left_b) && right_a.same_shape(right_b),
+
+            (ExprKind::Ternary(cond_a, then_a, else_a), ExprKind::Ternary(cond_b, then_b, else_b)) =>
+                cond_a.same_shape(cond_b) && then_a.same_shape(then_b) && else_a.same_shape(else_b),
+
+            (ExprKind::Call(callee_a, args_a), ExprKind::Call(callee_b, args_b)) =>
+                callee_a.same_shape(callee_b) && all_same_shape(args_a, args_b, Expr::same_shape),
+
+            _ => false
+        }
+    }
+}
+
+fn all_same_shape<T>(a: &[T], b: &[T], same: fn(&T, &T) -> bool) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same(a, b))
+}
+
+fn same_shape_opt<T>(a: Option<&T>, b: Option<&T>, same: fn(&T, &T) -> bool) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => same(a, b),
+        (None, None) => true,
+        _ => false
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Fixity {
+    Prefix,
+    // `x++` and `x--` yield the value before the update.
+    Postfix
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum StringKind {
+    NulTerminated,
+    // Written as b"...", stored without the trailing NUL.
+    Raw
+}
+
+// Walks the tree in source order. Implementations override what they need and
+// call the matching `walk_*` function to continue into the children.
+pub trait Visitor {
+    fn visit_decl(&mut self, decl: &Decl) {
+        walk_decl(self, decl);
+    }
+
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        walk_stmt(self, stmt);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr);
+    }
+}
+
+pub fn walk<V: Visitor + ?Sized>(visitor: &mut V, decls: &[Decl]) {
+    for decl in decls {
+        visitor.visit_decl(decl);
+    }
+}
+
+pub fn walk_decl<V: Visitor + ?Sized>(visitor: &mut V, decl: &Decl) {
+    match &decl.kind {
+        DeclKind::Function { body, .. } => visitor.visit_stmt(body),
+        DeclKind::External(var) => walk_var(visitor, var)
+    }
+}
+
+pub fn walk_stmt<V: Visitor + ?Sized>(visitor: &mut V, stmt: &Stmt) {
+    match &stmt.kind {
+        StmtKind::Block(stmts) => {
+            for inner in stmts {
+                visitor.visit_stmt(inner);
+            }
+        },
+
+        StmtKind::Expr(expr) => visitor.visit_expr(expr),
+        StmtKind::Auto(vars) => {
+            for var in vars {
+                walk_var(visitor, var);
+            }
+        },
+
+        StmtKind::If(cond, then_arm, else_arm) => {
+            visitor.visit_expr(cond);
+            visitor.visit_stmt(then_arm);
+
+            if let Some(else_arm) = else_arm {
+                visitor.visit_stmt(else_arm);
+            }
+        },
+
+        StmtKind::While(cond, body) => {
+            visitor.visit_expr(cond);
+
+            if let Some(body) = body {
+                visitor.visit_stmt(body);
+            }
+        },
+
+        StmtKind::DoWhile(cond, body) => {
+            visitor.visit_stmt(body);
+            visitor.visit_expr(cond);
+        },
+
+        StmtKind::Return(Some(expr)) => visitor.visit_expr(expr),
+
+        StmtKind::Return(None) | StmtKind::Extern(_) | StmtKind::Asm(_) |
+        StmtKind::Break(_) | StmtKind::Continue(_) | StmtKind::Empty => {}
+    }
+}
+
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match &expr.kind {
+        ExprKind::IntLit(..) | ExprKind::StringLit(..) | ExprKind::Var(_) => {},
+
+        ExprKind::UnaryOp(_, _, operand) => visitor.visit_expr(operand),
+
+        ExprKind::BinOp(left, _, right) | ExprKind::Assign(left, right) | ExprKind::Index(left, right) => {
+            visitor.visit_expr(left);
+            visitor.visit_expr(right);
+        },
+
+        ExprKind::Ternary(cond, then_arm, else_arm) => {
+            visitor.visit_expr(cond);
+            visitor.visit_expr(then_arm);
+            visitor.visit_expr(else_arm);
+        },
+
+        ExprKind::Call(callee, args) => {
+            visitor.visit_expr(callee);
+
+            for arg in args {
+                visitor.visit_expr(arg);
+            }
+        }
+    }
+}
+
+fn walk_var<V: Visitor + ?Sized>(visitor: &mut V, var: &Variable) {
+    if let VariableSize::Vector(Some(size)) = &var.size {
+        visitor.visit_expr(size);
+    }
+
+    for expr in &var.initial {
+        visitor.visit_expr(expr);
+    }
 }
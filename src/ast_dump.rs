@@ -0,0 +1,154 @@
+// Prints the tree as S-expressions, one statement per line. Expressions are
+// printed on the line of their statement.
+
+use crate::{ast::*, c_backend::{binary_operator, unary_operator}};
+use std::fmt::Write;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DumpStage {
+    Parsed,
+    // After constant folding.
+    Optimized
+}
+
+pub fn dump_decl(decl: &Decl) -> String {
+    let mut out = String::new();
+
+    match &decl.kind {
+        DeclKind::Function { name, params, body } => {
+            writeln!(out, "(function {} ({})", name, params.join(" ")).unwrap();
+            dump_stmt(&mut out, body, 1);
+            out.push_str(")\n");
+        },
+
+        DeclKind::External(var) => {
+            out.push_str(&var_to_string(var));
+            out.push('\n');
+        }
+    }
+
+    out
+}
+
+fn dump_stmt(out: &mut String, stmt: &Stmt, depth: usize) {
+    let indent = "  ".repeat(depth);
+
+    let line = match &stmt.kind {
+        StmtKind::Block(stmts) => {
+            writeln!(out, "{}(block", indent).unwrap();
+
+            for inner in stmts {
+                dump_stmt(out, inner, depth + 1);
+            }
+
+            writeln!(out, "{})", indent).unwrap();
+            return;
+        },
+
+        StmtKind::If(cond, then_arm, else_arm) => {
+            writeln!(out, "{}(if {}", indent, expr_to_string(cond)).unwrap();
+            dump_stmt(out, then_arm, depth + 1);
+
+            if let Some(else_arm) = else_arm {
+                dump_stmt(out, else_arm, depth + 1);
+            }
+
+            writeln!(out, "{})", indent).unwrap();
+            return;
+        },
+
+        StmtKind::While(cond, body) => {
+            writeln!(out, "{}(while {}", indent, expr_to_string(cond)).unwrap();
+
+            if let Some(body) = body {
+                dump_stmt(out, body, depth + 1);
+            }
+
+            writeln!(out, "{})", indent).unwrap();
+            return;
+        },
+
+        StmtKind::DoWhile(cond, body) => {
+            writeln!(out, "{}(do", indent).unwrap();
+            dump_stmt(out, body, depth + 1);
+            writeln!(out, "{}  {})", indent, expr_to_string(cond)).unwrap();
+            return;
+        },
+
+        StmtKind::Expr(expr) => expr_to_string(expr),
+
+        StmtKind::Auto(vars) => {
+            let vars: Vec<String> = vars.iter().map(var_to_string).collect();
+            format!("(auto {})", vars.join(" "))
+        },
+
+        StmtKind::Extern(name) => format!("(extern {})", name),
+        StmtKind::Return(Some(expr)) => format!("(return {})", expr_to_string(expr)),
+        StmtKind::Return(None) => String::from("(return)"),
+        StmtKind::Asm(text) => format!("(asm {:?})", text),
+        StmtKind::Break(1) => String::from("(break)"),
+        StmtKind::Break(levels) => format!("(break {})", levels),
+        StmtKind::Continue(1) => String::from("(continue)"),
+        StmtKind::Continue(levels) => format!("(continue {})", levels),
+        StmtKind::Empty => String::from("(empty)")
+    };
+
+    writeln!(out, "{}{}", indent, line).unwrap();
+}
+
+// `(var name init...)` or `(vector name size init...)`, a missing size is `_`.
+fn var_to_string(var: &Variable) -> String {
+    let mut out = match &var.size {
+        VariableSize::Scalar => format!("(var {}", var.name),
+        VariableSize::Vector(Some(size)) => format!("(vector {} {}", var.name, expr_to_string(size)),
+        VariableSize::Vector(None) => format!("(vector {} _", var.name)
+    };
+
+    for init in &var.initial {
+        out.push(' ');
+        out.push_str(&expr_to_string(init));
+    }
+
+    out.push(')');
+    out
+}
+
+fn expr_to_string(expr: &Expr) -> String {
+    match &expr.kind {
+        ExprKind::IntLit(_, Some(text)) => text.to_string(),
+        ExprKind::IntLit(value, None) => value.to_string(),
+        ExprKind::StringLit(value, StringKind::NulTerminated) => format!("{:?}", value),
+        ExprKind::StringLit(value, StringKind::Raw) => format!("b{:?}", value),
+        ExprKind::Var(name) => name.clone(),
+
+        ExprKind::UnaryOp(op, Fixity::Prefix, operand) =>
+            format!("({} {})", unary_operator(*op), expr_to_string(operand)),
+
+        ExprKind::UnaryOp(op, Fixity::Postfix, operand) =>
+            format!("(post{} {})", unary_operator(*op), expr_to_string(operand)),
+
+        ExprKind::BinOp(left, op, right) =>
+            format!("({} {} {})", binary_operator(*op), expr_to_string(left), expr_to_string(right)),
+
+        ExprKind::Assign(target, value) =>
+            format!("(= {} {})", expr_to_string(target), expr_to_string(value)),
+
+        ExprKind::Ternary(cond, then_arm, else_arm) =>
+            format!("(? {} {} {})", expr_to_string(cond), expr_to_string(then_arm), expr_to_string(else_arm)),
+
+        ExprKind::Call(callee, args) => {
+            let mut out = format!("(call {}", expr_to_string(callee));
+
+            for arg in args {
+                out.push(' ');
+                out.push_str(&expr_to_string(arg));
+            }
+
+            out.push(')');
+            out
+        },
+
+        ExprKind::Index(base, index) =>
+            format!("(index {} {})", expr_to_string(base), expr_to_string(index))
+    }
+}
@@ -0,0 +1,102 @@
+// Renders the tree of one function as a Graphviz graph, for teaching. Every
+// statement and expression is a node, children are in source order.
+
+use crate::{ast::*, c_backend::{binary_operator, unary_operator}};
+use std::fmt::Write;
+
+struct Graph {
+    out: String,
+    nodes: usize,
+    // The node of the statement or expression being visited.
+    parents: Vec<usize>
+}
+
+pub fn render(name: &str, params: &[String], body: &Stmt) -> String {
+    let mut graph = Graph {
+        out: String::from("digraph ast {\n    node [shape=box];\n"),
+        nodes: 0,
+        parents: Vec::new()
+    };
+
+    let root = graph.node(&format!("function {}({})", name, params.join(", ")));
+    graph.parents.push(root);
+    graph.visit_stmt(body);
+
+    graph.out.push_str("}\n");
+    graph.out
+}
+
+impl Graph {
+    // Adds a node with an edge from the current parent.
+    fn node(&mut self, label: &str) -> usize {
+        let id = self.nodes;
+        self.nodes += 1;
+
+        writeln!(self.out, "    n{} [label=\"{}\"];", id, escape(label)).unwrap();
+
+        if let Some(parent) = self.parents.last() {
+            writeln!(self.out, "    n{} -> n{};", parent, id).unwrap();
+        }
+
+        id
+    }
+}
+
+impl Visitor for Graph {
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        let label = match &stmt.kind {
+            StmtKind::Block(_) => String::from("block"),
+            StmtKind::Expr(_) => String::from("expression"),
+
+            StmtKind::Auto(vars) => {
+                let names: Vec<String> = vars.iter().map(|var| match var.size {
+                    VariableSize::Scalar => var.name.clone(),
+                    VariableSize::Vector(_) => format!("{}[]", var.name)
+                }).collect();
+                format!("auto {}", names.join(", "))
+            },
+
+            StmtKind::Extern(name) => format!("extern {}", name),
+            StmtKind::If(..) => String::from("if"),
+            StmtKind::While(..) => String::from("while"),
+            StmtKind::DoWhile(..) => String::from("do"),
+            StmtKind::Return(_) => String::from("return"),
+            StmtKind::Asm(text) => format!("asm {:?}", text),
+            StmtKind::Break(1) => String::from("break"),
+            StmtKind::Break(levels) => format!("break {}", levels),
+            StmtKind::Continue(1) => String::from("continue"),
+            StmtKind::Continue(levels) => format!("continue {}", levels),
+            StmtKind::Empty => String::from("empty")
+        };
+
+        let id = self.node(&label);
+        self.parents.push(id);
+        walk_stmt(self, stmt);
+        self.parents.pop();
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        let label = match &expr.kind {
+            ExprKind::IntLit(value, _) => value.to_string(),
+            ExprKind::StringLit(value, StringKind::NulTerminated) => format!("{:?}", value),
+            ExprKind::StringLit(value, StringKind::Raw) => format!("b{:?}", value),
+            ExprKind::Var(name) => name.clone(),
+            ExprKind::UnaryOp(op, Fixity::Prefix, _) => String::from(unary_operator(*op)),
+            ExprKind::UnaryOp(op, Fixity::Postfix, _) => format!("post{}", unary_operator(*op)),
+            ExprKind::BinOp(_, op, _) => String::from(binary_operator(*op)),
+            ExprKind::Assign(..) => String::from("="),
+            ExprKind::Ternary(..) => String::from("?:"),
+            ExprKind::Call(..) => String::from("call"),
+            ExprKind::Index(..) => String::from("[]")
+        };
+
+        let id = self.node(&label);
+        self.parents.push(id);
+        walk_expr(self, expr);
+        self.parents.pop();
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,570 @@
+// Translates a program to C. Every value is an `intptr_t`, pointers are byte
+// addresses like in the assembly output.
+//
+// Output order: forward declarations of all globals, prototypes of all
+// functions, then the definitions, so nothing depends on the source order.
+
+use crate::{ast::*, escape::quote_bytes, token::TokenType};
+use std::cell::{Cell, RefCell};
+use std::fmt::Write;
+
+pub struct CBackend {
+    decls: Vec<Decl>,
+    // Constants were folded for this word, so a C compiler with another one
+    // rejects the output.
+    word_size: usize,
+    // String buffers are indexed by byte instead of by word.
+    packed_chars: bool
+}
+
+// Names of the current function's parameters and locals, and of the vectors
+// visible in it.
+#[derive(Default)]
+struct Scope<'a> {
+    locals: Vec<&'a str>,
+    vectors: Vec<&'a str>,
+    byte_arrays: Vec<&'a str>,
+    // Label numbers of the enclosing loops, for `break N` and `continue N`.
+    loops: RefCell<Vec<usize>>,
+    labels: Cell<usize>
+}
+
+impl Scope<'_> {
+    fn enter_loop(&self) -> usize {
+        let label = self.labels.get();
+        self.labels.set(label + 1);
+        self.loops.borrow_mut().push(label);
+        label
+    }
+
+    fn exit_loop(&self) {
+        self.loops.borrow_mut().pop();
+    }
+
+    // Label of the loop `levels` out, 1 is the innermost.
+    fn loop_label(&self, levels: usize) -> usize {
+        let loops = self.loops.borrow();
+        loops[loops.len() - levels]
+    }
+}
+
+impl CBackend {
+    pub fn new(word_size: usize, packed_chars: bool) -> Self {
+        CBackend {
+            decls: Vec::new(),
+            word_size,
+            packed_chars
+        }
+    }
+
+    pub fn add_decl(&mut self, decl: Decl) {
+        self.decls.push(decl);
+    }
+
+    pub fn get_code(&self) -> String {
+        let mut out = String::from("#include <stdint.h>\n\n");
+        writeln!(out, "_Static_assert(sizeof(intptr_t) == {0}, \"the program was compiled for {0}-byte words\");\n", self.word_size).unwrap();
+
+        for name in self.undefined_names(true) {
+            writeln!(out, "intptr_t {}();", name).unwrap();
+        }
+
+        for name in self.undefined_names(false) {
+            writeln!(out, "extern intptr_t {};", name).unwrap();
+        }
+
+        for decl in &self.decls {
+            match &decl.kind {
+                DeclKind::External(var) if var.initial.len() > 1 && matches!(var.size, VariableSize::Scalar) => {
+                    // The extra words follow the first one, `name` stands for the first.
+                    writeln!(out, "extern intptr_t {}__words[];\n#define {} ({}__words[0])", var.name, var.name, var.name).unwrap();
+                },
+
+                DeclKind::External(var) => writeln!(out, "extern intptr_t {};", var.name).unwrap(),
+                DeclKind::Function { .. } => {}
+            }
+        }
+
+        for decl in &self.decls {
+            if let DeclKind::Function { name, params, .. } = &decl.kind {
+                writeln!(out, "{};", function_header(name, params)).unwrap();
+            }
+        }
+
+        out.push('\n');
+
+        for decl in &self.decls {
+            match &decl.kind {
+                DeclKind::External(var) => self.render_global(&mut out, var),
+                DeclKind::Function { name, params, body } => self.render_function(&mut out, name, params, body)
+            }
+        }
+
+        out
+    }
+
+    // Names used but not defined, either called directly or used otherwise.
+    fn undefined_names(&self, called: bool) -> Vec<String> {
+        let mut finder = NameFinder { defined: self.globals().collect(), called, found: Vec::new(), locals: Vec::new() };
+
+        for decl in &self.decls {
+            finder.visit_decl(decl);
+        }
+
+        finder.found
+    }
+
+    fn globals(&self) -> impl Iterator<Item = &str> {
+        self.decls.iter().map(|decl| match &decl.kind {
+            DeclKind::External(var) => var.name.as_str(),
+            DeclKind::Function { name, .. } => name.as_str()
+        })
+    }
+
+    fn global_vectors(&self) -> impl Iterator<Item = &str> {
+        self.decls.iter().filter_map(|decl| match &decl.kind {
+            DeclKind::External(var) if matches!(var.size, VariableSize::Vector(_)) => Some(var.name.as_str()),
+            _ => None
+        })
+    }
+
+    fn is_function(&self, name: &str) -> bool {
+        self.decls.iter().any(|decl| matches!(&decl.kind, DeclKind::Function { name: defined, .. } if defined == name))
+    }
+
+    fn render_global(&self, out: &mut String, var: &Variable) {
+        let values: Vec<String> = var.initial.iter().map(|expr| self.render_constant(expr)).collect();
+
+        match &var.size {
+            VariableSize::Scalar if values.len() > 1 => {
+                writeln!(out, "intptr_t {}__words[] = {{ {} }};", var.name, values.join(", ")).unwrap();
+            },
+
+            VariableSize::Scalar => {
+                let value = values.first().map_or("0", |value| value.as_str());
+                writeln!(out, "intptr_t {} = {};", var.name, value).unwrap();
+            },
+
+            VariableSize::Vector(size) => {
+                let size = match size {
+                    Some(Expr { kind: ExprKind::IntLit(size, _), .. }) => *size as usize,
+                    _ => values.len()
+                };
+
+                // C has no empty arrays.
+                let size = size.max(values.len()).max(1);
+
+                writeln!(out, "intptr_t {}__storage[{}] = {{ {} }};", var.name, size, values.join(", ")).unwrap();
+                writeln!(out, "intptr_t {} = (intptr_t){}__storage;", var.name, var.name).unwrap();
+            }
+        }
+    }
+
+    // The value of another global is its first initializer, the validator
+    // rejects cycles.
+    fn render_constant(&self, expr: &Expr) -> String {
+        match &expr.kind {
+            ExprKind::Var(name) => {
+                let initial = self.decls.iter().find_map(|decl| match &decl.kind {
+                    DeclKind::External(var) if &var.name == name => Some(var),
+                    _ => None
+                });
+
+                match initial {
+                    Some(var) if matches!(var.size, VariableSize::Vector(_)) => format!("(intptr_t){}__storage", var.name),
+                    Some(var) => var.initial.first().map_or(String::from("0"), |expr| self.render_constant(expr)),
+                    None => String::from("0")
+                }
+            },
+
+            _ => self.render_expr(expr, &Scope { vectors: self.global_vectors().collect(), ..Scope::default() })
+        }
+    }
+
+    fn render_function(&self, out: &mut String, name: &str, params: &[String], body: &Stmt) {
+        writeln!(out, "\n{} {{", function_header(name, params)).unwrap();
+
+        // B locals are visible from their declaration to the end of the
+        // function, C ones only to the end of the block, so they are all
+        // declared up front.
+        let mut collector = AutoCollector::default();
+        collector.visit_stmt(body);
+        let autos = collector.autos;
+
+        for var in &autos {
+            match var.string_buffer() {
+                Some((value, kind)) => {
+                    let len = value.len() + (kind == StringKind::NulTerminated) as usize;
+                    writeln!(out, "\tintptr_t {}__storage[({} + sizeof(intptr_t) - 1) / sizeof(intptr_t) + 1];", var.name, len).unwrap();
+                },
+
+                None => if let VariableSize::Vector(size) = &var.size {
+                    let size = match size {
+                        Some(Expr { kind: ExprKind::IntLit(size, _), .. }) => *size as usize,
+                        _ => var.initial.len()
+                    };
+
+                    writeln!(out, "\tintptr_t {}__storage[{}];", var.name, size.max(var.initial.len()).max(1)).unwrap();
+                }
+            }
+
+            writeln!(out, "\tintptr_t {};", var.name).unwrap();
+        }
+
+        let mut scope = Scope { locals: params.iter().map(|param| param.as_str()).collect(), ..Scope::default() };
+        scope.locals.extend(autos.iter().map(|var| var.name.as_str()));
+
+        let local_vectors = autos.iter().filter(|var| matches!(var.size, VariableSize::Vector(_))).map(|var| var.name.as_str());
+        let global_vectors = self.global_vectors().filter(|name| !scope.locals.contains(name));
+        scope.vectors = local_vectors.chain(global_vectors).collect();
+
+        if self.packed_chars {
+            scope.byte_arrays.extend(autos.iter().filter(|var| var.string_buffer().is_some()).map(|var| var.name.as_str()));
+        }
+
+        self.render_stmt(out, body, &scope, 1);
+        out.push_str("\treturn 0;\n}\n");
+    }
+
+    fn render_stmt(&self, out: &mut String, stmt: &Stmt, scope: &Scope, depth: usize) {
+        let indent = "\t".repeat(depth);
+
+        match &stmt.kind {
+            StmtKind::Block(stmts) => {
+                writeln!(out, "{}{{", indent).unwrap();
+
+                for inner in stmts {
+                    self.render_stmt(out, inner, scope, depth + 1);
+                }
+
+                writeln!(out, "{}}}", indent).unwrap();
+            },
+
+            StmtKind::Expr(expr) => writeln!(out, "{}{};", indent, self.render_expr(expr, scope)).unwrap(),
+
+            StmtKind::Auto(vars) => {
+                for var in vars {
+                    self.render_auto(out, var, scope, &indent);
+                }
+            },
+
+            StmtKind::Extern(_) => {},
+            StmtKind::Empty => writeln!(out, "{};", indent).unwrap(),
+
+            StmtKind::If(cond, then_arm, else_arm) => {
+                writeln!(out, "{}if ({})", indent, self.render_expr(cond, scope)).unwrap();
+                self.render_stmt(out, then_arm, scope, depth + 1);
+
+                if let Some(else_arm) = else_arm {
+                    writeln!(out, "{}else", indent).unwrap();
+                    self.render_stmt(out, else_arm, scope, depth + 1);
+                }
+            },
+
+            StmtKind::While(cond, body) => {
+                writeln!(out, "{}while ({})", indent, self.render_expr(cond, scope)).unwrap();
+
+                match body {
+                    Some(body) => self.render_loop(out, body, scope, depth),
+                    None => writeln!(out, "{}\t;", indent).unwrap()
+                }
+            },
+
+            StmtKind::DoWhile(cond, body) => {
+                writeln!(out, "{}do", indent).unwrap();
+                let label = self.render_loop_body(out, body, scope, depth);
+                writeln!(out, "{}while ({});", indent, self.render_expr(cond, scope)).unwrap();
+                render_break_label(out, body, label, &indent);
+            },
+
+            StmtKind::Return(expr) => {
+                let value = expr.as_ref().map_or(String::from("0"), |expr| self.render_expr(expr, scope));
+                writeln!(out, "{}return {};", indent, value).unwrap();
+            },
+
+            StmtKind::Asm(text) => writeln!(out, "{}__asm__({});", indent, quote_bytes(text.as_bytes())).unwrap(),
+            StmtKind::Break(1) => writeln!(out, "{}break;", indent).unwrap(),
+            StmtKind::Break(levels) => writeln!(out, "{}goto break_{};", indent, scope.loop_label(*levels)).unwrap(),
+            StmtKind::Continue(1) => writeln!(out, "{}continue;", indent).unwrap(),
+            StmtKind::Continue(levels) => writeln!(out, "{}goto continue_{};", indent, scope.loop_label(*levels)).unwrap()
+        }
+    }
+
+    fn render_loop(&self, out: &mut String, body: &Stmt, scope: &Scope, depth: usize) {
+        let label = self.render_loop_body(out, body, scope, depth);
+        render_break_label(out, body, label, &"\t".repeat(depth));
+    }
+
+    // C has no multi-level `break` and `continue`, they jump to labels. The
+    // continue label ends the body, the break one follows the loop. Loops get
+    // them only if they are the target of a jump. Returns the label number.
+    fn render_loop_body(&self, out: &mut String, body: &Stmt, scope: &Scope, depth: usize) -> usize {
+        let label = scope.enter_loop();
+
+        if exits_loop(body, false, 1) {
+            let indent = "\t".repeat(depth + 1);
+            writeln!(out, "{}{{", indent).unwrap();
+            self.render_stmt(out, body, scope, depth + 2);
+            writeln!(out, "{}continue_{}: ;\n{}}}", indent, label, indent).unwrap();
+        } else {
+            self.render_stmt(out, body, scope, depth + 1);
+        }
+
+        scope.exit_loop();
+        label
+    }
+
+    fn render_auto(&self, out: &mut String, var: &Variable, scope: &Scope, indent: &str) {
+        if let Some((value, kind)) = var.string_buffer() {
+            let len = value.len() + (kind == StringKind::NulTerminated) as usize;
+
+            writeln!(out, "{}{} = (intptr_t){}__storage;", indent, var.name, var.name).unwrap();
+            writeln!(out, "{}__builtin_memcpy({}__storage, {}, {});", indent, var.name, quote_bytes(value.as_bytes()), len).unwrap();
+            return;
+        }
+
+        match var.size {
+            VariableSize::Scalar => match var.initial.first() {
+                Some(initial) => writeln!(out, "{}{} = {};", indent, var.name, self.render_expr(initial, scope)).unwrap(),
+                None => writeln!(out, "{};", indent).unwrap()
+            },
+
+            VariableSize::Vector(_) => {
+                writeln!(out, "{}{} = (intptr_t){}__storage;", indent, var.name, var.name).unwrap();
+
+                for (i, initial) in var.initial.iter().enumerate() {
+                    writeln!(out, "{}{}__storage[{}] = {};", indent, var.name, i, self.render_expr(initial, scope)).unwrap();
+                }
+            }
+        }
+    }
+
+    fn render_expr(&self, expr: &Expr, scope: &Scope) -> String {
+        match &expr.kind {
+            ExprKind::IntLit(value, _) => value.to_string(),
+            ExprKind::StringLit(value, _) => format!("(intptr_t){}", quote_bytes(value.as_bytes())),
+
+            ExprKind::Var(name) if !scope.locals.contains(&name.as_str()) && self.is_function(name) => {
+                format!("(intptr_t){}", name)
+            },
+
+            ExprKind::Var(name) => name.clone(),
+
+            ExprKind::UnaryOp(op, Fixity::Prefix, operand) => match op {
+                TokenType::Star => format!("(*(intptr_t *)({}))", self.render_expr(operand, scope)),
+                TokenType::Ampersand => format!("((intptr_t)&{})", self.render_lvalue(operand, scope)),
+                TokenType::PlusPlus => format!("(++{})", self.render_lvalue(operand, scope)),
+                TokenType::MinusMinus => format!("(--{})", self.render_lvalue(operand, scope)),
+                _ => format!("({}{})", unary_operator(*op), self.render_expr(operand, scope))
+            },
+
+            ExprKind::UnaryOp(op, Fixity::Postfix, operand) => {
+                format!("({}{})", self.render_lvalue(operand, scope), unary_operator(*op))
+            },
+
+            ExprKind::BinOp(left, TokenType::GreaterGreaterGreater, right) => {
+                format!("((intptr_t)((uintptr_t){} >> {}))", self.render_expr(left, scope), self.render_expr(right, scope))
+            },
+
+            ExprKind::BinOp(left, op, right) => {
+                format!("({} {} {})", self.render_expr(left, scope), binary_operator(*op), self.render_expr(right, scope))
+            },
+
+            ExprKind::Assign(target, value) => {
+                format!("({} = {})", self.render_lvalue(target, scope), self.render_expr(value, scope))
+            },
+
+            ExprKind::Ternary(cond, then_arm, else_arm) => {
+                format!("({} ? {} : {})", self.render_expr(cond, scope), self.render_expr(then_arm, scope), self.render_expr(else_arm, scope))
+            },
+
+            ExprKind::Call(callee, args) => {
+                let args: Vec<String> = args.iter().map(|arg| self.render_expr(arg, scope)).collect();
+
+                match &callee.kind {
+                    ExprKind::Var(name) if !scope.locals.contains(&name.as_str()) && !self.is_data(name) => {
+                        format!("{}({})", name, args.join(", "))
+                    },
+
+                    _ => format!("((intptr_t (*)())({}))({})", self.render_expr(callee, scope), args.join(", "))
+                }
+            },
+
+            ExprKind::Index(..) => self.render_lvalue(expr, scope)
+        }
+    }
+
+    fn render_lvalue(&self, expr: &Expr, scope: &Scope) -> String {
+        match &expr.kind {
+            ExprKind::Var(name) => name.clone(),
+
+            ExprKind::UnaryOp(TokenType::Star, Fixity::Prefix, operand) => {
+                format!("(*(intptr_t *)({}))", self.render_expr(operand, scope))
+            },
+
+            // Like in the assembly output, `i[v]` indexes the vector `v`.
+            ExprKind::Index(base, index) => {
+                let (base, index) = index_operands(base, index, |name| scope.vectors.contains(&name));
+
+                let element = match &base.kind {
+                    ExprKind::Var(name) if scope.byte_arrays.contains(&name.as_str()) => "unsigned char",
+                    _ => "intptr_t"
+                };
+
+                format!("(({} *)({}))[{}]", element, self.render_expr(base, scope), self.render_expr(index, scope))
+            },
+
+            _ => unreachable!("validator rejects non-lvalue operands")
+        }
+    }
+
+    fn is_data(&self, name: &str) -> bool {
+        self.decls.iter().any(|decl| matches!(&decl.kind, DeclKind::External(var) if var.name == name))
+    }
+}
+
+// Collects the names that are used but defined neither globally nor locally.
+struct NameFinder<'a> {
+    defined: Vec<&'a str>,
+    called: bool,
+    found: Vec<String>,
+    locals: Vec<String>
+}
+
+impl Visitor for NameFinder<'_> {
+    // Only the bodies of functions are searched.
+    fn visit_decl(&mut self, decl: &Decl) {
+        if let DeclKind::Function { params, .. } = &decl.kind {
+            self.locals = params.clone();
+            walk_decl(self, decl);
+        }
+    }
+
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        if let StmtKind::Auto(vars) = &stmt.kind {
+            self.locals.extend(vars.iter().map(|var| var.name.clone()));
+        }
+
+        walk_stmt(self, stmt);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Var(name) if !self.called => self.found_name(name),
+
+            // A function called by name is not a use of the name as a value.
+            ExprKind::Call(callee, args) if matches!(callee.kind, ExprKind::Var(_)) => {
+                if let ExprKind::Var(name) = &callee.kind {
+                    if self.called {
+                        self.found_name(name);
+                    }
+                }
+
+                for arg in args {
+                    self.visit_expr(arg);
+                }
+            },
+
+            _ => walk_expr(self, expr)
+        }
+    }
+}
+
+impl NameFinder<'_> {
+    fn found_name(&mut self, name: &str) {
+        let known = self.defined.contains(&name) || self.locals.iter().any(|local| local == name);
+
+        if !known && !self.found.iter().any(|found| found == name) {
+            self.found.push(String::from(name));
+        }
+    }
+}
+
+// The `auto`s of a function body in source order.
+#[derive(Default)]
+struct AutoCollector {
+    autos: Vec<Variable>
+}
+
+impl Visitor for AutoCollector {
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        if let StmtKind::Auto(vars) = &stmt.kind {
+            self.autos.extend(vars.iter().cloned());
+        }
+
+        walk_stmt(self, stmt);
+    }
+
+    fn visit_expr(&mut self, _: &Expr) {}
+}
+
+fn render_break_label(out: &mut String, body: &Stmt, label: usize, indent: &str) {
+    if exits_loop(body, true, 1) {
+        writeln!(out, "{}break_{}: ;", indent, label).unwrap();
+    }
+}
+
+// Whether a `break N` (or `continue N`) in a nested loop of `body` exits the
+// loop of `body`, which is `levels` out from there.
+fn exits_loop(body: &Stmt, is_break: bool, levels: usize) -> bool {
+    match &body.kind {
+        StmtKind::Break(n) => is_break && levels > 1 && *n == levels,
+        StmtKind::Continue(n) => !is_break && levels > 1 && *n == levels,
+        StmtKind::Block(stmts) => stmts.iter().any(|stmt| exits_loop(stmt, is_break, levels)),
+
+        StmtKind::If(_, then_arm, else_arm) =>
+            exits_loop(then_arm, is_break, levels) || else_arm.as_ref().is_some_and(|arm| exits_loop(arm, is_break, levels)),
+
+        StmtKind::While(_, Some(body)) | StmtKind::DoWhile(_, body) => exits_loop(body, is_break, levels + 1),
+        _ => false
+    }
+}
+
+// C requires `main` to return `int`.
+fn function_header(name: &str, params: &[String]) -> String {
+    let result = if name == "main" { "int" } else { "intptr_t" };
+
+    if params.is_empty() {
+        return format!("{} {}(void)", result, name);
+    }
+
+    let params: Vec<String> = params.iter().map(|param| format!("intptr_t {}", param)).collect();
+    format!("{} {}({})", result, name, params.join(", "))
+}
+
+pub fn unary_operator(op: TokenType) -> &'static str {
+    match op {
+        TokenType::Minus => "-",
+        TokenType::Plus => "+",
+        TokenType::Bang => "!",
+        TokenType::Tilda => "~",
+        TokenType::PlusPlus => "++",
+        TokenType::MinusMinus => "--",
+        _ => unreachable!("parser produces only these unary operators")
+    }
+}
+
+pub fn binary_operator(op: TokenType) -> &'static str {
+    match op {
+        TokenType::Comma => ",",
+        TokenType::Plus => "+",
+        TokenType::Minus => "-",
+        TokenType::Star => "*",
+        TokenType::Slash => "/",
+        TokenType::Percent => "%",
+        TokenType::EqualEqual => "==",
+        TokenType::BangEqual => "!=",
+        TokenType::Greater => ">",
+        TokenType::Less => "<",
+        TokenType::GreaterEqual => ">=",
+        TokenType::LessEqual => "<=",
+        TokenType::Bar => "|",
+        TokenType::BarBar => "||",
+        TokenType::Ampersand => "&",
+        TokenType::AmpersandAmpersand => "&&",
+        TokenType::UpArrow => "^",
+        TokenType::GreaterGreater => ">>",
+        TokenType::LessLess => "<<",
+        _ => unreachable!("parser produces only these binary operators")
+    }
+}
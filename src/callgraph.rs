@@ -0,0 +1,31 @@
+// Renders the calls between functions as a Graphviz graph. Only calls by the
+// name of a defined function are edges, calls through addresses are unknown.
+
+use crate::validator::{Global, GlobalKind};
+use std::fmt::Write;
+
+pub fn render(globals: &[Global], calls: &[(String, String)]) -> String {
+    let is_function = |name: &str| globals.iter()
+        .any(|global| global.name == name && matches!(global.kind, GlobalKind::Function { .. }));
+
+    let mut out = String::from("digraph callgraph {\n");
+
+    for global in globals.iter().filter(|global| is_function(&global.name)) {
+        writeln!(out, "    \"{}\";", global.name).unwrap();
+    }
+
+    let mut edges: Vec<&(String, String)> = Vec::new();
+
+    for call in calls.iter().filter(|(_, callee)| is_function(callee)) {
+        if !edges.contains(&call) {
+            edges.push(call);
+        }
+    }
+
+    for (caller, callee) in edges {
+        writeln!(out, "    \"{}\" -> \"{}\";", caller, callee).unwrap();
+    }
+
+    out.push_str("}\n");
+    out
+}
@@ -0,0 +1,58 @@
+// Make dependency rules for `--emit-deps`, in the format of `cc -MD -MP`.
+
+use std::fmt::Write;
+use std::path::Path;
+
+// The target depends on the main file and the included ones. Inputs that are
+// not regular files, like `/dev/stdin`, can't be rebuilt from and are left out.
+// The included files also get empty rules, so that deleting one doesn't break
+// the build.
+pub fn render(target: &str, main: &str, includes: &[String]) -> String {
+    let is_file = |path: &&str| Path::new(path).is_file();
+    let includes: Vec<&str> = includes.iter().map(|path| path.as_str()).filter(is_file).collect();
+    let mut out = escape(target);
+    out.push(':');
+
+    for input in Some(main).filter(is_file).iter().chain(&includes) {
+        write!(out, " \\\n  {}", escape(input)).unwrap();
+    }
+
+    out.push('\n');
+
+    for input in &includes {
+        writeln!(out, "\n{}:", escape(input)).unwrap();
+    }
+
+    out
+}
+
+fn escape(path: &str) -> String {
+    let mut out = String::new();
+
+    for ch in path.chars() {
+        match ch {
+            ' ' | '#' => out.push('\\'),
+            '$' => out.push('$'),
+            _ => {}
+        }
+
+        out.push(ch);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn special_characters_are_escaped() {
+        assert_eq!(escape("my file#1.$b"), "my\\ file\\#1.$$b");
+    }
+
+    #[test]
+    fn missing_inputs_are_left_out() {
+        assert_eq!(render("out.s", "/dev/stdin", &["/nonexistent.b".to_string()]), "out.s:\n");
+    }
+}
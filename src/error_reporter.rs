@@ -1,5 +1,256 @@
 use crate::file::FilePosition;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+#[derive(Clone, Copy)]
+pub enum ColorMode {
+    // Only when stderr is a terminal.
+    Auto,
+    Always,
+    Never
+}
+
+static USE_COLOR: AtomicBool = AtomicBool::new(false);
+static ERROR_COUNT: AtomicUsize = AtomicUsize::new(0);
+// Set by `-w`.
+static NO_WARNINGS: AtomicBool = AtomicBool::new(false);
+// Bit per `WarningKind`, set by `-Wno-<name>`.
+static DISABLED_WARNINGS: AtomicU32 = AtomicU32::new(0);
+static SHOW_CATEGORIES: AtomicBool = AtomicBool::new(false);
+
+pub fn set_color_mode(mode: ColorMode) {
+    let color = match mode {
+        ColorMode::Auto => std::io::stderr().is_terminal(),
+        ColorMode::Always => true,
+        ColorMode::Never => false
+    };
+
+    USE_COLOR.store(color, Ordering::Relaxed);
+}
+
+// Appends the category to the message of diagnostics that have one.
+pub fn set_show_categories(show: bool) {
+    SHOW_CATEGORIES.store(show, Ordering::Relaxed);
+}
+
+pub fn set_warning_filter(all: bool, disabled: &[WarningKind]) {
+    NO_WARNINGS.store(all, Ordering::Relaxed);
+    DISABLED_WARNINGS.store(disabled.iter().fold(0, |bits, kind| bits | kind.bit()), Ordering::Relaxed);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error(ErrorKind),
+    Warning(WarningKind)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ErrorKind {
+    UndefinedReference,
+    Redefinition,
+    NotLvalue,
+    LoopControl,
+    ArraySize,
+    TooManyInitializers,
+    NonConstantInitializer,
+    InitializerCycle,
+    BuiltinArgument
+}
+
+impl ErrorKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorKind::UndefinedReference => "undefined-reference",
+            ErrorKind::Redefinition => "redefinition",
+            ErrorKind::NotLvalue => "not-lvalue",
+            ErrorKind::LoopControl => "loop-control",
+            ErrorKind::ArraySize => "array-size",
+            ErrorKind::TooManyInitializers => "too-many-initializers",
+            ErrorKind::NonConstantInitializer => "non-constant-initializer",
+            ErrorKind::InitializerCycle => "initializer-cycle",
+            ErrorKind::BuiltinArgument => "builtin-argument"
+        }
+    }
+}
+
+// Categories of warnings, each can be turned off with `-Wno-<name>`. The names
+// are stable, scripts and documentation refer to them.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WarningKind {
+    Unused,
+    ConstantCondition,
+    DanglingElse,
+    IdenticalBranches,
+    MixedReturns,
+    TooManyParams,
+    Shadow,
+    ShiftCount,
+    IndexOverflow,
+    ArrayBounds,
+    WriteStrings,
+    UndefinedExtern
+}
+
+const WARNING_KINDS: [WarningKind; 12] = [
+    WarningKind::Unused,
+    WarningKind::ConstantCondition,
+    WarningKind::DanglingElse,
+    WarningKind::IdenticalBranches,
+    WarningKind::MixedReturns,
+    WarningKind::TooManyParams,
+    WarningKind::Shadow,
+    WarningKind::ShiftCount,
+    WarningKind::IndexOverflow,
+    WarningKind::ArrayBounds,
+    WarningKind::WriteStrings,
+    WarningKind::UndefinedExtern
+];
+
+impl WarningKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            WarningKind::Unused => "unused",
+            WarningKind::ConstantCondition => "constant-condition",
+            WarningKind::DanglingElse => "dangling-else",
+            WarningKind::IdenticalBranches => "identical-branches",
+            WarningKind::MixedReturns => "mixed-returns",
+            WarningKind::TooManyParams => "too-many-params",
+            WarningKind::Shadow => "shadow",
+            WarningKind::ShiftCount => "shift-count",
+            WarningKind::IndexOverflow => "index-overflow",
+            WarningKind::ArrayBounds => "array-bounds",
+            WarningKind::WriteStrings => "write-strings",
+            WarningKind::UndefinedExtern => "undefined-extern"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<WarningKind> {
+        WARNING_KINDS.into_iter().find(|kind| kind.name() == name)
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+
+    fn is_enabled(self) -> bool {
+        !NO_WARNINGS.load(Ordering::Relaxed) && DISABLED_WARNINGS.load(Ordering::Relaxed) & self.bit() == 0
+    }
+}
+
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub pos: FilePosition,
+    pub msg: String
+}
+
+impl Diagnostic {
+    pub fn category(&self) -> &'static str {
+        match self.severity {
+            Severity::Error(kind) => kind.name(),
+            Severity::Warning(kind) => kind.name()
+        }
+    }
+}
+
+pub fn report(diagnostic: &Diagnostic) {
+    let msg = if SHOW_CATEGORIES.load(Ordering::Relaxed) {
+        format!("{} [{}]", diagnostic.msg, diagnostic.category())
+    } else {
+        diagnostic.msg.clone()
+    };
+
+    match diagnostic.severity {
+        Severity::Error(_) => report_error(&diagnostic.pos, &msg),
+        Severity::Warning(kind) => report_warning(kind, &diagnostic.pos, &msg)
+    }
+}
+
+// Errors reported so far, warnings and notes are not counted.
+pub fn error_count() -> usize {
+    ERROR_COUNT.load(Ordering::Relaxed)
+}
 
 pub fn report_error(pos: &FilePosition, msg: &str) {
-    eprintln!("{}:{}: error {}.", pos.file.path, pos.line, msg);
+    ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
+    eprintln!("{}:{}: {} {}.", pos.file.path, pos.line, severity("error", "1;31"), msg);
+    eprint!("{}", format_snippet(pos));
+}
+
+pub fn report_warning(kind: WarningKind, pos: &FilePosition, msg: &str) {
+    if !kind.is_enabled() {
+        return;
+    }
+
+    eprintln!("{}:{}: {} {}.", pos.file.path, pos.line, severity("warning", "1;33"), msg);
+    eprint!("{}", format_snippet(pos));
+}
+
+// For errors about the whole program rather than a place in the source.
+pub fn report_program_error(msg: &str) {
+    ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
+    eprintln!("{}: {}.", severity("error", "1;31"), msg);
+}
+
+// Points at a position related to the previous diagnostic.
+pub fn report_note(pos: &FilePosition, msg: &str) {
+    eprintln!("{}:{}: {} {}.", pos.file.path, pos.line, severity("note", "1;36"), msg);
+    eprint!("{}", format_snippet(pos));
+}
+
+fn severity(name: &str, color: &str) -> String {
+    if USE_COLOR.load(Ordering::Relaxed) {
+        format!("\x1b[{}m{}\x1b[0m", color, name)
+    } else {
+        String::from(name)
+    }
+}
+
+// Renders the source line of the position with a caret under it:
+//
+//   12 | x = y +;
+//      |        ^
+//
+// The caret is indented relative to the start of the source text, so the width
+// of the line number gutter doesn't affect it.
+fn format_snippet(pos: &FilePosition) -> String {
+    let data = &pos.file.data;
+
+    if pos.offset > data.len() || pos.line == 0 {
+        return String::new();
+    }
+
+    let line_start = data[..pos.offset].iter()
+        .rposition(|ch| *ch == b'\n')
+        .map_or(pos.file.content_start().min(pos.offset), |i| i + 1);
+
+    let line_end = data[pos.offset..].iter()
+        .position(|ch| *ch == b'\n')
+        .map_or(data.len(), |i| pos.offset + i);
+
+    let line = String::from_utf8_lossy(&data[line_start..line_end]);
+    let before = String::from_utf8_lossy(&data[line_start..pos.offset]);
+
+    // Keep tabs so the caret lines up the same way the source does.
+    let indent: String = before.chars()
+        .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    let gutter = pos.line.to_string();
+    let blank_gutter = " ".repeat(gutter.len());
+
+    format!(" {} | {}\n {} | {}^\n", gutter, line.trim_end_matches('\r'), blank_gutter, indent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warning_names_round_trip() {
+        for kind in WARNING_KINDS {
+            assert_eq!(WarningKind::from_name(kind.name()), Some(kind));
+        }
+
+        assert_eq!(WarningKind::from_name("everything"), None);
+    }
 }
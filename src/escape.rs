@@ -0,0 +1,97 @@
+// Decodes the escape sequences in the body of a character or string literal.
+// On failure returns the byte index of the first unknown escape in `text` and
+// the message for it.
+pub fn decode_escapes(text: &str) -> Result<String, (usize, String)> {
+    let mut res = String::with_capacity(text.len());
+    let mut chars = text.char_indices();
+
+    while let Some((index, ch)) = chars.next() {
+        if ch != '\\' {
+            res.push(ch);
+            continue;
+        }
+
+        match chars.next() {
+            Some((_, escaped)) => match decode_escape(escaped) {
+                Some(decoded) => res.push(decoded),
+                None => return Err((index, format!("unknown escape sequence '\\{}'", escaped)))
+            },
+
+            None => return Err((index, String::from("expected character after '\\'")))
+        }
+    }
+
+    Ok(res)
+}
+
+fn decode_escape(ch: char) -> Option<char> {
+    let byte: u8 = match ch {
+        'n' => b'\n',
+        't' => b'\t',
+        'r' => b'\r',
+        '0' => 0,
+        'a' => 7,
+        'b' => 8,
+        'v' => 11,
+        'f' => 12,
+        'e' => 27, // Extension, not in C.
+        '\\' => b'\\',
+        '\'' => b'\'',
+        '\"' => b'\"',
+        _ => return None
+    };
+
+    Some(char::from(byte))
+}
+
+// Quotes bytes for C and the GNU assembler, which share the escapes. Octal
+// escapes have a fixed length, unlike hex ones they can't swallow the next
+// character.
+pub fn quote_bytes(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(byte as char);
+            },
+
+            b' '..=b'~' => out.push(byte as char),
+            _ => out.push_str(&format!("\\{:03o}", byte))
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_escapes, quote_bytes};
+
+    #[test]
+    fn decodes_every_escape() {
+        let cases = [
+            ("\\a", 7), ("\\b", 8), ("\\t", 9), ("\\n", 10), ("\\v", 11), ("\\f", 12), ("\\r", 13),
+            ("\\e", 27), ("\\0", 0), ("\\\\", b'\\'), ("\\'", b'\''), ("\\\"", b'"')
+        ];
+
+        for (text, byte) in cases {
+            assert_eq!(decode_escapes(text), Ok(String::from(char::from(byte))), "{}", text);
+        }
+    }
+
+    #[test]
+    fn unknown_escape_is_reported_at_its_index() {
+        assert_eq!(decode_escapes("ab\\qc"), Err((2, String::from("unknown escape sequence '\\q'"))));
+        assert_eq!(decode_escapes("ab\\"), Err((2, String::from("expected character after '\\'"))));
+    }
+
+    #[test]
+    fn quoted_bytes_escape_everything_outside_printable_ascii() {
+        assert_eq!(quote_bytes(b"a\"b\\c"), "\"a\\\"b\\\\c\"");
+        // The escape is always three digits, so a following digit is not part of it.
+        assert_eq!(quote_bytes(b"\n1\xc3\xa9"), "\"\\0121\\303\\251\"");
+    }
+}
@@ -12,8 +12,20 @@ pub fn read_file(path: &String) -> Result<Rc<File>, std::io::Error> {
     Ok(Rc::new(File { path: path.clone(), data: file_data.into_bytes() }))
 }
 
+// Skipped at the start of a file. The file data keeps it, so that offsets are
+// the offsets on disk.
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+impl File {
+    // Offset of the first byte after the BOM, if any.
+    pub fn content_start(&self) -> usize {
+        if self.data.starts_with(UTF8_BOM) { UTF8_BOM.len() } else { 0 }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct FilePosition {
     pub file: Rc<File>,
-    pub line: usize
+    pub line: usize,
+    pub offset: usize
 }
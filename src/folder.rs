@@ -0,0 +1,429 @@
+// Constant folding. Runs before validation, so it must leave anything it can't
+// fold untouched for the validator to report.
+
+use crate::{ast::*, token::TokenType};
+
+pub const LEN_BUILTIN: &str = "__len";
+pub const WORDSIZE_BUILTIN: &str = "__wordsize";
+pub const LINE_BUILTIN: &str = "__line";
+pub const FILE_BUILTIN: &str = "__file";
+
+// Builtins that always fold to a literal, they are not lvalues.
+pub fn is_literal_builtin(name: &str) -> bool {
+    name == WORDSIZE_BUILTIN || name == LINE_BUILTIN || name == FILE_BUILTIN
+}
+
+pub fn fold_decl(decl: &mut Decl, word_size: usize) {
+    match &mut decl.kind {
+        DeclKind::External(var) => fold_var(var, word_size),
+        DeclKind::Function { body, .. } => fold_stmt(body, word_size)
+    }
+}
+
+fn fold_var(var: &mut Variable, word_size: usize) {
+    if let VariableSize::Vector(Some(size)) = &mut var.size {
+        fold_expr(size, word_size);
+    }
+
+    for expr in &mut var.initial {
+        fold_expr(expr, word_size);
+    }
+}
+
+fn fold_stmt(stmt: &mut Stmt, word_size: usize) {
+    match &mut stmt.kind {
+        StmtKind::Block(stmts) => {
+            for stmt in stmts {
+                fold_stmt(stmt, word_size);
+            }
+        },
+
+        StmtKind::Expr(expr) => fold_expr(expr, word_size),
+        StmtKind::Auto(vars) => {
+            for var in vars {
+                fold_var(var, word_size);
+            }
+        },
+        StmtKind::Extern(_) => {},
+
+        StmtKind::If(cond, then_arm, else_arm) => {
+            fold_expr(cond, word_size);
+            fold_stmt(then_arm, word_size);
+
+            if let Some(else_arm) = else_arm {
+                fold_stmt(else_arm, word_size);
+            }
+        },
+
+        StmtKind::While(cond, body) => {
+            fold_expr(cond, word_size);
+
+            if let Some(body) = body {
+                fold_stmt(body, word_size);
+            }
+        },
+
+        StmtKind::DoWhile(cond, body) => {
+            fold_expr(cond, word_size);
+            fold_stmt(body, word_size);
+        },
+
+        StmtKind::Return(expr) => {
+            if let Some(expr) = expr {
+                fold_expr(expr, word_size);
+            }
+        },
+
+        StmtKind::Asm(_) | StmtKind::Break(_) | StmtKind::Continue(_) | StmtKind::Empty => {}
+    }
+}
+
+fn fold_expr(expr: &mut Expr, word_size: usize) {
+    match &mut expr.kind {
+        ExprKind::Var(name) if name == WORDSIZE_BUILTIN => {
+            expr.kind = ExprKind::IntLit(word_size as i32, None);
+            return;
+        },
+
+        // The position of a macro expansion is the position of its use.
+        ExprKind::Var(name) if name == LINE_BUILTIN => {
+            expr.kind = ExprKind::IntLit(expr.pos.line as i32, None);
+            return;
+        },
+
+        ExprKind::Var(name) if name == FILE_BUILTIN => {
+            expr.kind = ExprKind::StringLit(expr.pos.file.path.clone(), StringKind::NulTerminated);
+            return;
+        },
+
+        ExprKind::IntLit(..) | ExprKind::StringLit(..) | ExprKind::Var(_) => {},
+
+        ExprKind::UnaryOp(op, _, inner) => {
+            if matches!(op, TokenType::Ampersand | TokenType::PlusPlus | TokenType::MinusMinus) {
+                fold_lvalue(inner, word_size);
+            } else {
+                fold_expr(inner, word_size);
+            }
+
+            if let Some(simplified) = simplify_address(expr) {
+                *expr = simplified;
+                return;
+            }
+        },
+
+        ExprKind::Assign(target, value) => {
+            fold_lvalue(target, word_size);
+            fold_expr(value, word_size);
+        },
+
+        ExprKind::BinOp(left, _, right) | ExprKind::Index(left, right) => {
+            fold_expr(left, word_size);
+            fold_expr(right, word_size);
+        },
+
+        ExprKind::Ternary(cond, then_arm, else_arm) => {
+            fold_expr(cond, word_size);
+            fold_expr(then_arm, word_size);
+            fold_expr(else_arm, word_size);
+
+            if let ExprKind::IntLit(value, _) = cond.kind {
+                let arm = if value != 0 { then_arm } else { else_arm };
+                let arm = std::mem::replace(arm.as_mut(), Expr { pos: expr.pos.clone(), kind: ExprKind::IntLit(0, None) });
+                *expr = arm;
+                return;
+            }
+        },
+
+        ExprKind::Call(callee, args) => {
+            fold_expr(callee, word_size);
+
+            for arg in args {
+                fold_expr(arg, word_size);
+            }
+        }
+    }
+
+    if let Some(value) = evaluate(expr, word_size) {
+        expr.kind = ExprKind::IntLit(value, None);
+    }
+}
+
+// `&*p` isn't an lvalue, so it's left as is where one is expected. A variable
+// is left as is too, the validator reports `__wordsize` there.
+fn fold_lvalue(expr: &mut Expr, word_size: usize) {
+    match &mut expr.kind {
+        ExprKind::Var(_) => {},
+        ExprKind::UnaryOp(TokenType::Ampersand, Fixity::Prefix, inner) => fold_expr(inner, word_size),
+        _ => fold_expr(expr, word_size)
+    }
+}
+
+// `*&x` is `x` and `&*p` is `p`. The first one only for lvalues, for which
+// taking the address is valid, so it stays an lvalue.
+fn simplify_address(expr: &mut Expr) -> Option<Expr> {
+    let ExprKind::UnaryOp(outer, Fixity::Prefix, inner) = &mut expr.kind else {
+        return None;
+    };
+
+    let ExprKind::UnaryOp(op, Fixity::Prefix, operand) = &mut inner.kind else {
+        return None;
+    };
+
+    let simplify = match (outer, op) {
+        (TokenType::Star, TokenType::Ampersand) => matches!(
+            operand.kind,
+            ExprKind::Var(_) | ExprKind::Index(..) | ExprKind::UnaryOp(TokenType::Star, Fixity::Prefix, _)
+        ),
+
+        (TokenType::Ampersand, TokenType::Star) => true,
+        _ => false
+    };
+
+    if !simplify {
+        return None;
+    }
+
+    let placeholder = Expr { pos: operand.pos.clone(), kind: ExprKind::IntLit(0, None) };
+    Some(std::mem::replace(operand.as_mut(), placeholder))
+}
+
+// Folds the way the target computes: a 32-bit word wraps around, a 64-bit
+// result is kept only if it fits a literal. Shifts by more than a word and
+// divisions that trap are left to run.
+fn evaluate(expr: &Expr, word_size: usize) -> Option<i32> {
+    let bits = word_size as u32 * 8;
+
+    let value = match &expr.kind {
+        ExprKind::UnaryOp(op, Fixity::Prefix, inner) => {
+            let value = int_value(inner)?;
+
+            match op {
+                TokenType::Minus => Some(value.wrapping_neg()),
+                TokenType::Plus => Some(value),
+                TokenType::Tilda => Some(!value),
+                TokenType::Bang => Some((value == 0) as i64),
+                _ => None
+            }
+        },
+
+        // The right operand is not evaluated when the left one decides, so it
+        // doesn't matter what it is.
+        ExprKind::BinOp(left, TokenType::AmpersandAmpersand, _) if int_value(left) == Some(0) => Some(0),
+        ExprKind::BinOp(left, TokenType::BarBar, _) if int_value(left).is_some_and(|value| value != 0) => Some(1),
+
+        ExprKind::BinOp(left, op, right) => {
+            let (left, right) = (int_value(left)?, int_value(right)?);
+
+            let shift = || u32::try_from(right).ok().filter(|&shift| shift < bits);
+            // `idiv` traps on `MIN / -1` as well as on a zero divisor.
+            let traps = right == 0 || (bits == 32 && left == i32::MIN.into() && right == -1);
+
+            match op {
+                TokenType::Plus => Some(left.wrapping_add(right)),
+                TokenType::Minus => Some(left.wrapping_sub(right)),
+                TokenType::Star => Some(left.wrapping_mul(right)),
+                TokenType::Slash if !traps => Some(left / right),
+                TokenType::Percent if !traps => Some(left % right),
+                TokenType::Ampersand => Some(left & right),
+                TokenType::Bar => Some(left | right),
+                TokenType::UpArrow => Some(left ^ right),
+                TokenType::LessLess => Some(left << shift()?),
+                TokenType::GreaterGreater => Some(left >> shift()?),
+
+                TokenType::GreaterGreaterGreater => {
+                    let shift = shift()?;
+                    Some(if bits == 32 { (left as u32 >> shift).into() } else { (left as u64 >> shift) as i64 })
+                },
+
+                TokenType::EqualEqual => Some((left == right) as i64),
+                TokenType::BangEqual => Some((left != right) as i64),
+                TokenType::Less => Some((left < right) as i64),
+                TokenType::Greater => Some((left > right) as i64),
+                TokenType::LessEqual => Some((left <= right) as i64),
+                TokenType::GreaterEqual => Some((left >= right) as i64),
+                TokenType::AmpersandAmpersand => Some((left != 0 && right != 0) as i64),
+                TokenType::BarBar => Some((left != 0 || right != 0) as i64),
+                _ => None
+            }
+        },
+
+        ExprKind::Call(callee, args) => match (&callee.kind, args.as_slice()) {
+            (ExprKind::Var(name), [Expr { kind: ExprKind::StringLit(value, _), .. }]) if name == LEN_BUILTIN => {
+                value.len().try_into().ok()
+            },
+
+            _ => None
+        },
+
+        _ => None
+    };
+
+    if bits == 32 {
+        Some(value? as i32)
+    } else {
+        value?.try_into().ok()
+    }
+}
+
+fn int_value(expr: &Expr) -> Option<i64> {
+    match expr.kind {
+        ExprKind::IntLit(value, _) => Some(value.into()),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{file::File, parser::{Dialect, Parser}, preprocessor::Preprocessor, scanner::Scanner};
+    use std::rc::Rc;
+
+    // Folds `source` as the value of a return statement, for 32-bit words.
+    fn fold(source: &str) -> Expr {
+        fold_for(source, 4)
+    }
+
+    fn fold_for(source: &str, word_size: usize) -> Expr {
+        let file = Rc::new(File {
+            path: String::from("test.b"),
+            data: format!("f() {{ return {}; }}", source).into_bytes()
+        });
+
+        let mut preprocessor = Preprocessor::new(Scanner::new(file), Vec::new());
+        let mut decl = Parser::new(&mut preprocessor, Dialect::default()).parse_one_decl().expect("test programs parse");
+        fold_decl(&mut decl, word_size);
+
+        let DeclKind::Function { body, .. } = decl.kind else {
+            panic!("expected a function");
+        };
+
+        match body.kind {
+            StmtKind::Block(mut stmts) => match stmts.pop() {
+                Some(Stmt { kind: StmtKind::Return(Some(expr)), .. }) => expr,
+                _ => panic!("expected a return statement")
+            },
+
+            _ => panic!("expected a block")
+        }
+    }
+
+    fn is_var(expr: &Expr, expected: &str) -> bool {
+        matches!(&expr.kind, ExprKind::Var(name) if name == expected)
+    }
+
+    #[test]
+    fn address_and_deref_cancel() {
+        assert!(is_var(&fold("*&x"), "x"));
+        assert!(is_var(&fold("&*p"), "p"));
+        assert!(is_var(&fold("*&*&x"), "x"));
+        assert!(matches!(fold("*&v[1]").kind, ExprKind::Index(..)));
+    }
+
+    #[test]
+    fn address_of_non_lvalue_is_kept() {
+        assert!(matches!(fold("*&1").kind, ExprKind::UnaryOp(TokenType::Star, ..)));
+
+        // `&*p` must stay where an lvalue is expected, for the validator.
+        let ExprKind::Assign(target, _) = fold("&*p = 1").kind else {
+            panic!("expected an assignment");
+        };
+
+        assert!(matches!(target.kind, ExprKind::UnaryOp(TokenType::Ampersand, ..)));
+    }
+
+    fn int_lit(expr: &Expr) -> Option<i32> {
+        match expr.kind {
+            ExprKind::IntLit(value, _) => Some(value),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn logical_shift_depends_on_word_size() {
+        assert_eq!(int_lit(&fold("64 >>> 3")), Some(8));
+        assert_eq!(int_lit(&fold("-16 >>> 2")), Some(0x3fff_fffc));
+        assert_eq!(int_lit(&fold("-16 >> 2")), Some(-4));
+
+        // Zero-filled from bit 63, the result doesn't fit a literal.
+        assert_eq!(int_lit(&fold_for("-16 >>> 2", 8)), None);
+        assert_eq!(int_lit(&fold_for("-16 >>> 60", 8)), Some(15));
+    }
+
+    #[test]
+    fn overflow_wraps_only_for_32_bit_words() {
+        assert_eq!(int_lit(&fold("2147483647 + 1")), Some(i32::MIN));
+        assert_eq!(int_lit(&fold("65536 * 65536")), Some(0));
+        assert_eq!(int_lit(&fold("1 << 31")), Some(i32::MIN));
+
+        assert_eq!(int_lit(&fold_for("2147483647 + 1", 8)), None);
+        assert_eq!(int_lit(&fold_for("65536 * 65536", 8)), None);
+        assert_eq!(int_lit(&fold_for("1 << 31", 8)), None);
+        assert_eq!(int_lit(&fold_for("-1 << 31", 8)), Some(i32::MIN));
+    }
+
+    #[test]
+    fn shift_by_a_word_or_more_is_not_folded() {
+        assert_eq!(int_lit(&fold("1 << 32")), None);
+        assert_eq!(int_lit(&fold("1 << -1")), None);
+        assert_eq!(int_lit(&fold_for("1 << 32", 8)), None);
+        assert_eq!(int_lit(&fold_for("1 << 64", 8)), None);
+    }
+
+    #[test]
+    fn trapping_division_is_not_folded() {
+        assert_eq!(int_lit(&fold("1 / 0")), None);
+        assert_eq!(int_lit(&fold("1 % 0")), None);
+        assert_eq!(int_lit(&fold("-2147483647 - 1")), Some(i32::MIN));
+        assert_eq!(int_lit(&fold("(-2147483647 - 1) / -1")), None);
+        assert_eq!(int_lit(&fold("(-2147483647 - 1) % -1")), None);
+
+        // No trap with 64-bit words, but the quotient doesn't fit a literal.
+        assert_eq!(int_lit(&fold_for("(-2147483647 - 1) / -1", 8)), None);
+        assert_eq!(int_lit(&fold_for("(-2147483647 - 1) % -1", 8)), Some(0));
+    }
+
+    #[test]
+    fn wordsize_builtin_folds_to_the_target_word() {
+        assert_eq!(int_lit(&fold("__wordsize")), Some(4));
+        assert_eq!(int_lit(&fold_for("__wordsize * 8", 8)), Some(64));
+    }
+
+    #[test]
+    fn folded_literal_has_no_spelling() {
+        assert!(matches!(fold("007").kind, ExprKind::IntLit(7, Some(ref text)) if &**text == "007"));
+        assert!(matches!(fold("0 + 007").kind, ExprKind::IntLit(7, None)));
+    }
+
+    #[test]
+    fn position_builtins_fold_to_where_they_are_used() {
+        // `fold` wraps the expression in a one-line function.
+        assert_eq!(int_lit(&fold("__line * 10")), Some(10));
+        assert!(matches!(fold("__file").kind, ExprKind::StringLit(ref path, StringKind::NulTerminated) if path == "test.b"));
+    }
+
+    #[test]
+    fn comparisons_fold_to_zero_or_one() {
+        assert_eq!(int_lit(&fold("2 < 3")), Some(1));
+        assert_eq!(int_lit(&fold("-1 >= 0")), Some(0));
+        assert_eq!(int_lit(&fold("4 == 2 + 2")), Some(1));
+        assert_eq!(int_lit(&fold("!5")), Some(0));
+        assert_eq!(int_lit(&fold("!(1 != 1)")), Some(1));
+    }
+
+    #[test]
+    fn logical_operators_fold_when_the_left_operand_decides() {
+        assert_eq!(int_lit(&fold("2 && 3")), Some(1));
+        assert_eq!(int_lit(&fold("0 || 7")), Some(1));
+        assert_eq!(int_lit(&fold("0 || 0")), Some(0));
+
+        // The right operand never runs, even if it isn't constant.
+        assert_eq!(int_lit(&fold("0 && f()")), Some(0));
+        assert_eq!(int_lit(&fold("3 - 3 && f()")), Some(0));
+        assert_eq!(int_lit(&fold("1 || x++")), Some(1));
+
+        // Otherwise it decides and must be evaluated.
+        assert_eq!(int_lit(&fold("1 && f()")), None);
+        assert_eq!(int_lit(&fold("0 || x")), None);
+        assert_eq!(int_lit(&fold("f() && 0")), None);
+    }
+}
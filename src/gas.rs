@@ -0,0 +1,250 @@
+// Renders a program as GNU assembler source for x86, 32-bit or 64-bit depending
+// on the word size.
+//
+// The stack machine maps onto the hardware stack, the accumulator and the
+// counter register are scratch.
+
+use crate::{escape::quote_bytes, file::FilePosition, program::*};
+use std::fmt::Write;
+
+struct Target {
+    word_size: usize,
+    // Instruction suffix for word-sized operands.
+    s: char,
+    ax: &'static str,
+    cx: &'static str,
+    dx: &'static str,
+    bp: &'static str,
+    sp: &'static str,
+    word_directive: &'static str,
+    // Sign-extends the accumulator into the data register before a division.
+    extend: &'static str
+}
+
+const X86: Target = Target {
+    word_size: 4,
+    s: 'l',
+    ax: "%eax",
+    cx: "%ecx",
+    dx: "%edx",
+    bp: "%ebp",
+    sp: "%esp",
+    word_directive: ".long",
+    extend: "cltd"
+};
+
+const X86_64: Target = Target {
+    word_size: 8,
+    s: 'q',
+    ax: "%rax",
+    cx: "%rcx",
+    dx: "%rdx",
+    bp: "%rbp",
+    sp: "%rsp",
+    word_directive: ".quad",
+    extend: "cqto"
+};
+
+// Where the x86-64 C convention passes the first arguments.
+const ARGUMENT_REGISTERS: [&str; 6] = ["%rdi", "%rsi", "%rdx", "%rcx", "%r8", "%r9"];
+
+pub fn render(program: &Program) -> String {
+    render_with_source_map(program).0
+}
+
+// Also returns the output line where the code of each source line starts,
+// lines are numbered from 1.
+pub fn render_with_source_map(program: &Program) -> (String, Vec<(usize, &FilePosition)>) {
+    let target = target(program);
+    let mut out = String::new();
+    let mut offsets = Vec::new();
+
+    if !program.functions.is_empty() {
+        out.push_str("\t.text\n");
+    }
+
+    for function in &program.functions {
+        render_function(&mut out, target, function, &mut offsets);
+    }
+
+    if !program.data.is_empty() {
+        out.push_str("\t.data\n");
+    }
+
+    for data in &program.data {
+        if data.global {
+            writeln!(out, "\t.globl {}", data.label).unwrap();
+        }
+
+        writeln!(out, "{}:", data.label).unwrap();
+
+        for value in &data.values {
+            match value {
+                DataValue::Word(value) => writeln!(out, "\t{} {}", target.word_directive, value),
+                DataValue::Address(label) => writeln!(out, "\t{} {}", target.word_directive, label),
+                DataValue::Bytes(bytes) if bytes.is_empty() => Ok(()),
+                DataValue::Bytes(bytes) if program.string_encoding == StringEncoding::Escaped => {
+                    writeln!(out, "\t.ascii {}", quote_bytes(bytes))
+                },
+                DataValue::Bytes(bytes) => {
+                    let bytes: Vec<String> = bytes.iter().map(|b| b.to_string()).collect();
+                    writeln!(out, "\t.byte {}", bytes.join(", "))
+                }
+            }.unwrap();
+        }
+    }
+
+    let mut map = Vec::new();
+    let mut line = 1;
+    let mut counted = 0;
+
+    for (offset, pos) in offsets {
+        line += out[counted..offset].matches('\n').count();
+        counted = offset;
+        map.push((line, pos));
+    }
+
+    (out, map)
+}
+
+// Renders the code of a function split by the source lines it was generated
+// for. The prologue goes with the first line.
+pub fn render_function_lines<'a>(program: &Program, function: &'a Function) -> Vec<(&'a FilePosition, String)> {
+    let t = target(program);
+    let mut lines = Vec::new();
+    let mut pos = None;
+    let mut text = String::new();
+
+    render_prologue(&mut text, t, function);
+
+    for instruction in &function.code {
+        if let Instruction::Line(line) = instruction {
+            if let Some(pos) = pos {
+                lines.push((pos, std::mem::take(&mut text)));
+            }
+
+            pos = Some(line);
+        }
+
+        render_instruction(&mut text, t, instruction);
+    }
+
+    if let Some(pos) = pos {
+        lines.push((pos, text));
+    }
+
+    lines
+}
+
+fn target(program: &Program) -> &'static Target {
+    if program.word_size == 8 { &X86_64 } else { &X86 }
+}
+
+// Records the output offset of every line marker, the first one also covers
+// the prologue.
+fn render_function<'a>(out: &mut String, t: &Target, function: &'a Function, offsets: &mut Vec<(usize, &'a FilePosition)>) {
+    let mut start = Some(out.len());
+
+    render_prologue(out, t, function);
+
+    for instruction in &function.code {
+        if let Instruction::Line(pos) = instruction {
+            offsets.push((start.take().unwrap_or(out.len()), pos));
+        }
+
+        render_instruction(out, t, instruction);
+    }
+}
+
+fn render_prologue(out: &mut String, t: &Target, function: &Function) {
+    writeln!(out, "\t.globl {}", function.name).unwrap();
+    writeln!(out, "{}:", function.name).unwrap();
+    writeln!(out, "\tpush{} {}\n\tmov{} {}, {}", t.s, t.bp, t.s, t.sp, t.bp).unwrap();
+
+    if function.frame_size > 0 {
+        writeln!(out, "\tsub{} ${}, {}", t.s, function.frame_size, t.sp).unwrap();
+    }
+
+    for (i, register) in ARGUMENT_REGISTERS.iter().take(function.register_params).enumerate() {
+        writeln!(out, "\tmov{} {}, -{}({})", t.s, register, (i + 1) * t.word_size, t.bp).unwrap();
+    }
+}
+
+fn render_instruction(out: &mut String, t: &Target, instruction: &Instruction) {
+    let (s, ax, cx) = (t.s, t.ax, t.cx);
+
+    match instruction {
+        Instruction::Line(_) => Ok(()),
+
+        Instruction::Push(value) => writeln!(out, "\tpush{} ${}", s, value),
+        Instruction::PushAddress(label) => writeln!(out, "\tpush{} ${}", s, label),
+        Instruction::PushLocalAddress(offset) => writeln!(out, "\tlea{} {}({}), {}\n\tpush{} {}", s, offset, t.bp, ax, s, ax),
+        Instruction::Dup => writeln!(out, "\tpush{} ({})", s, t.sp),
+        Instruction::Pop => writeln!(out, "\tadd{} ${}, {}", s, t.word_size, t.sp),
+
+        Instruction::Load => writeln!(out, "\tpop{} {}\n\tpush{} ({})", s, ax, s, ax),
+        Instruction::Store => writeln!(out, "\tpop{s} {cx}\n\tpop{s} {ax}\n\tmov{s} {cx}, ({ax})\n\tpush{s} {cx}"),
+        Instruction::LoadByte => writeln!(out, "\tpop{s} {ax}\n\tmovzb{s} ({ax}), {ax}\n\tpush{s} {ax}"),
+        Instruction::StoreByte => writeln!(out, "\tpop{s} {cx}\n\tpop{s} {ax}\n\tmovb %cl, ({ax})\n\tmovzb{s} %cl, {cx}\n\tpush{s} {cx}"),
+
+        Instruction::Unary(op) => {
+            let body = match op {
+                UnaryOp::Negate => format!("\tneg{s} {ax}\n"),
+                UnaryOp::Complement => format!("\tnot{s} {ax}\n"),
+                UnaryOp::Not => format!("\ttest{s} {ax}, {ax}\n{}", set_flag("sete", t))
+            };
+
+            write!(out, "\tpop{s} {ax}\n{body}\tpush{s} {ax}\n")
+        },
+
+        Instruction::Binary(op) => {
+            let body = match op {
+                BinaryOp::Add => format!("\tadd{s} {cx}, {ax}\n"),
+                BinaryOp::Sub => format!("\tsub{s} {cx}, {ax}\n"),
+                BinaryOp::Mul => format!("\timul{s} {cx}, {ax}\n"),
+                BinaryOp::Div => format!("\t{}\n\tidiv{s} {cx}\n", t.extend),
+                BinaryOp::Mod => format!("\t{}\n\tidiv{s} {cx}\n\tmov{s} {}, {ax}\n", t.extend, t.dx),
+                BinaryOp::And => format!("\tand{s} {cx}, {ax}\n"),
+                BinaryOp::Or => format!("\tor{s} {cx}, {ax}\n"),
+                BinaryOp::Xor => format!("\txor{s} {cx}, {ax}\n"),
+                BinaryOp::ShiftLeft => format!("\tshl{s} %cl, {ax}\n"),
+                BinaryOp::ShiftRight => format!("\tsar{s} %cl, {ax}\n"),
+                BinaryOp::ShiftRightLogical => format!("\tshr{s} %cl, {ax}\n"),
+                BinaryOp::Equal => compare("sete", t),
+                BinaryOp::NotEqual => compare("setne", t),
+                BinaryOp::Less => compare("setl", t),
+                BinaryOp::LessEqual => compare("setle", t),
+                BinaryOp::Greater => compare("setg", t),
+                BinaryOp::GreaterEqual => compare("setge", t)
+            };
+
+            write!(out, "\tpop{s} {cx}\n\tpop{s} {ax}\n{body}\tpush{s} {ax}\n")
+        },
+
+        Instruction::Label(label) => writeln!(out, ".L{}:", label),
+        Instruction::Jump(label) => writeln!(out, "\tjmp .L{}", label),
+        Instruction::JumpIfZero(label) => writeln!(out, "\tpop{s} {ax}\n\ttest{s} {ax}, {ax}\n\tjz .L{label}"),
+        Instruction::JumpIfNotZero(label) => writeln!(out, "\tpop{s} {ax}\n\ttest{s} {ax}, {ax}\n\tjnz .L{label}"),
+
+        Instruction::Call(args) => {
+            writeln!(out, "\tpop{s} {ax}\n\tcall *{ax}\n\tadd{s} ${}, {}\n\tpush{s} {ax}", args * t.word_size, t.sp)
+        },
+
+        Instruction::CallDirect(name, args) => {
+            writeln!(out, "\tcall {name}\n\tadd{s} ${}, {}\n\tpush{s} {ax}", args * t.word_size, t.sp)
+        },
+
+        Instruction::Return => writeln!(out, "\tpop{s} {ax}\n\tleave\n\tret"),
+
+        Instruction::Asm(text) => writeln!(out, "\t{}", text)
+    }.unwrap();
+}
+
+fn compare(set: &str, t: &Target) -> String {
+    format!("\tcmp{} {}, {}\n{}", t.s, t.cx, t.ax, set_flag(set, t))
+}
+
+// Materializes a condition flag as 0 or 1 in the accumulator.
+fn set_flag(set: &str, t: &Target) -> String {
+    format!("\t{} %al\n\tmovzb{} %al, {}\n", set, t.s, t.ax)
+}
@@ -0,0 +1,213 @@
+// Reparsing after an edit, for editors. Top-level declarations don't depend on
+// each other syntactically, so only the ones the edit touches are parsed again
+// and the rest keep their trees with moved positions.
+//
+// Macros are not tracked, a reparsed declaration doesn't see the `#define`s
+// before it.
+
+use crate::{ast::*, file::*, parser::{Dialect, Parser}, preprocessor::Preprocessor, scanner::Scanner};
+use std::ops::Range;
+use std::rc::Rc;
+
+// Replaces the bytes in `range` of the old file with `text`.
+pub struct Edit {
+    pub range: Range<usize>,
+    pub text: String
+}
+
+pub struct Reparse {
+    pub file: Rc<File>,
+    pub decls: Vec<Decl>,
+    // Indices of the reparsed declarations in `decls`.
+    pub reparsed: Range<usize>
+}
+
+// The starting point for `reparse`. Declarations with errors are left out.
+pub fn parse(file: &Rc<File>) -> Vec<Decl> {
+    let mut preprocessor = Preprocessor::new(Scanner::new(file.clone()), Vec::new());
+    let mut parser = Parser::new(&mut preprocessor, Dialect::default());
+    let mut decls = Vec::new();
+
+    while !parser.is_at_end() {
+        if let Some(decl) = parser.parse_one_decl() {
+            decls.push(decl);
+        }
+    }
+
+    decls
+}
+
+// `decls` must be the declarations parsed from `file`, in order.
+pub fn reparse(file: &Rc<File>, decls: Vec<Decl>, edit: &Edit) -> Reparse {
+    let mut data = file.data[..edit.range.start].to_vec();
+    data.extend_from_slice(edit.text.as_bytes());
+    data.extend_from_slice(&file.data[edit.range.end..]);
+
+    let new_file = Rc::new(File {
+        path: file.path.clone(),
+        data
+    });
+
+    let offset_delta = edit.text.len() as isize - edit.range.len() as isize;
+    let line_delta = count_lines(edit.text.as_bytes()) as isize - count_lines(&file.data[edit.range.clone()]) as isize;
+
+    // The declaration before the edit may end right where it starts.
+    let first = decls.iter()
+        .rposition(|decl| decl.pos.offset < edit.range.start)
+        .unwrap_or(0);
+
+    let mut before = Vec::new();
+    let mut after = Vec::new();
+    let mut region_start = None;
+
+    for (i, mut decl) in decls.into_iter().enumerate() {
+        if i < first {
+            move_decl(&mut decl, &new_file, 0, 0);
+            before.push(decl);
+        } else if decl.pos.offset <= edit.range.end {
+            region_start.get_or_insert((decl.pos.offset, decl.pos.line));
+        } else {
+            move_decl(&mut decl, &new_file, offset_delta, line_delta);
+            after.push(decl);
+        }
+    }
+
+    let (offset, line) = region_start.unwrap_or((edit.range.start, 1 + count_lines(&file.data[..edit.range.start])));
+    let scanner = Scanner::starting_at(new_file.clone(), offset, line);
+    let mut preprocessor = Preprocessor::new(scanner, Vec::new());
+    let mut parser = Parser::new(&mut preprocessor, Dialect::default());
+
+    // Parses until the next token starts an untouched declaration, the edit
+    // may have swallowed some of them.
+    let mut reparsed = Vec::new();
+    let region_end = (edit.range.start + edit.text.len()) as isize;
+
+    loop {
+        let offset = parser.current_offset();
+        after.retain(|decl| decl.pos.offset >= offset);
+
+        let at_boundary = offset as isize > region_end &&
+            after.first().is_some_and(|decl| decl.pos.offset == offset);
+
+        if parser.is_at_end() || at_boundary {
+            break;
+        }
+
+        if let Some(decl) = parser.parse_one_decl() {
+            reparsed.push(decl);
+        }
+    }
+
+    let reparsed_range = before.len()..before.len() + reparsed.len();
+
+    before.extend(reparsed);
+    before.extend(after);
+
+    Reparse {
+        file: new_file,
+        decls: before,
+        reparsed: reparsed_range
+    }
+}
+
+fn count_lines(data: &[u8]) -> usize {
+    data.iter().filter(|ch| **ch == b'\n').count()
+}
+
+fn move_pos(pos: &mut FilePosition, file: &Rc<File>, offset_delta: isize, line_delta: isize) {
+    pos.file = file.clone();
+    pos.offset = (pos.offset as isize + offset_delta) as usize;
+    pos.line = (pos.line as isize + line_delta) as usize;
+}
+
+fn move_decl(decl: &mut Decl, file: &Rc<File>, offset_delta: isize, line_delta: isize) {
+    move_pos(&mut decl.pos, file, offset_delta, line_delta);
+
+    match &mut decl.kind {
+        DeclKind::Function { body, .. } => move_stmt(body, file, offset_delta, line_delta),
+        DeclKind::External(var) => move_var(var, file, offset_delta, line_delta)
+    }
+}
+
+fn move_var(var: &mut Variable, file: &Rc<File>, offset_delta: isize, line_delta: isize) {
+    if let VariableSize::Vector(Some(size)) = &mut var.size {
+        move_expr(size, file, offset_delta, line_delta);
+    }
+
+    for expr in &mut var.initial {
+        move_expr(expr, file, offset_delta, line_delta);
+    }
+}
+
+fn move_stmt(stmt: &mut Stmt, file: &Rc<File>, offset_delta: isize, line_delta: isize) {
+    move_pos(&mut stmt.pos, file, offset_delta, line_delta);
+
+    match &mut stmt.kind {
+        StmtKind::Block(stmts) => {
+            for inner in stmts {
+                move_stmt(inner, file, offset_delta, line_delta);
+            }
+        },
+
+        StmtKind::Expr(expr) | StmtKind::Return(Some(expr)) => move_expr(expr, file, offset_delta, line_delta),
+        StmtKind::Auto(vars) => {
+            for var in vars {
+                move_var(var, file, offset_delta, line_delta);
+            }
+        },
+
+        StmtKind::If(cond, then_arm, else_arm) => {
+            move_expr(cond, file, offset_delta, line_delta);
+            move_stmt(then_arm, file, offset_delta, line_delta);
+
+            if let Some(else_arm) = else_arm {
+                move_stmt(else_arm, file, offset_delta, line_delta);
+            }
+        },
+
+        StmtKind::While(cond, body) => {
+            move_expr(cond, file, offset_delta, line_delta);
+
+            if let Some(body) = body {
+                move_stmt(body, file, offset_delta, line_delta);
+            }
+        },
+
+        StmtKind::DoWhile(cond, body) => {
+            move_stmt(body, file, offset_delta, line_delta);
+            move_expr(cond, file, offset_delta, line_delta);
+        },
+
+        StmtKind::Return(None) | StmtKind::Extern(_) | StmtKind::Asm(_) |
+        StmtKind::Break(_) | StmtKind::Continue(_) | StmtKind::Empty => {}
+    }
+}
+
+fn move_expr(expr: &mut Expr, file: &Rc<File>, offset_delta: isize, line_delta: isize) {
+    move_pos(&mut expr.pos, file, offset_delta, line_delta);
+
+    match &mut expr.kind {
+        ExprKind::IntLit(..) | ExprKind::StringLit(..) | ExprKind::Var(_) => {},
+
+        ExprKind::UnaryOp(_, _, operand) => move_expr(operand, file, offset_delta, line_delta),
+
+        ExprKind::BinOp(left, _, right) | ExprKind::Assign(left, right) | ExprKind::Index(left, right) => {
+            move_expr(left, file, offset_delta, line_delta);
+            move_expr(right, file, offset_delta, line_delta);
+        },
+
+        ExprKind::Ternary(cond, then_arm, else_arm) => {
+            move_expr(cond, file, offset_delta, line_delta);
+            move_expr(then_arm, file, offset_delta, line_delta);
+            move_expr(else_arm, file, offset_delta, line_delta);
+        },
+
+        ExprKind::Call(callee, args) => {
+            move_expr(callee, file, offset_delta, line_delta);
+
+            for arg in args {
+                move_expr(arg, file, offset_delta, line_delta);
+            }
+        }
+    }
+}
@@ -1,77 +1,775 @@
 use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::Instant;
 
 use file::{read_file, File};
-use parser::Parser;
+use folder::fold_decl;
+use parser::{Dialect, Parser, DEFAULT_MAX_NESTING};
+use preprocessor::{macro_name, Preprocessor, DEFAULT_MAX_INCLUDE_DEPTH};
 use scanner::Scanner;
+use token::TokenType;
+use error_reporter::{error_count, report_program_error, set_color_mode, set_show_categories, set_warning_filter, ColorMode};
 use simple_compiler::Compiler;
-use validator::Validator;
+use c_backend::CBackend;
+use program::StringEncoding;
+use stats::Stats;
+use profile::{time, Profile};
+use ast::{Decl, DeclKind, Visitor};
+use token_dump::{dump_tokens, DumpFormat};
+use ast_dump::{dump_decl, DumpStage};
 use std::rc::Rc;
 
-mod ast;
-mod file;
-mod token;
+pub mod ast;
+pub mod file;
+pub mod token;
+pub mod incremental;
 mod scanner;
+mod preprocessor;
 mod parser;
 mod error_reporter;
+mod escape;
+mod token_dump;
+mod ast_dump;
+mod ast_graph;
+mod folder;
 mod validator;
+mod program;
+mod gas;
+mod listing;
 mod simple_compiler;
+mod c_backend;
+mod callgraph;
+mod deps;
+mod toy;
+mod stats;
+mod profile;
+
+// The validator for library users, it returns the diagnostics instead of
+// printing them.
+pub use validator::{Global, GlobalKind, Validator, Warnings};
+pub use error_reporter::{Diagnostic, ErrorKind, Severity, WarningKind};
 
 pub struct Config {
     input_path: String,
     output_path: String,
-    use_simple_compiler: bool
+    use_simple_compiler: bool,
+    include_dirs: Vec<String>,
+    max_include_depth: usize,
+    max_nesting: usize,
+    defines: Vec<(String, String)>,
+    warnings: Warnings,
+    // Parses and validates without generating code or writing output.
+    only_validate: bool,
+    // Writes the output for the declarations without errors even if others have them.
+    keep_going: bool,
+    list_globals: bool,
+    stats: bool,
+    // Prints the time of every phase to stderr.
+    profile: bool,
+    dump_tokens: Option<DumpFormat>,
+    dump_ast: Option<DumpStage>,
+    // Name of the function to render as a Graphviz tree.
+    ast_graph: Option<String>,
+    listing_path: Option<String>,
+    source_map_path: Option<String>,
+    deps_path: Option<String>,
+    dialect: Dialect,
+    color: ColorMode,
+    // Checked to be a defined function only when given explicitly.
+    entry: Option<String>,
+    word_size: usize,
+    emit: Emit,
+    packed_chars: bool,
+    // Runs the assembler on the output to write an object file instead.
+    assembler: Option<String>,
+    string_encoding: StringEncoding,
+    trace_parse: bool,
+    // Appends `[name]` to the diagnostics of the validator.
+    show_categories: bool
 }
 
 impl Config {
+    pub fn entry(&self) -> &str {
+        self.entry.as_deref().unwrap_or("main")
+    }
+
     pub fn build(args: &[String]) -> Result<Config, &'static str> {
-        if args.len() < 3 {
+        let mut positional = Vec::new();
+        let mut use_simple_compiler = true;
+        let mut include_dirs = Vec::new();
+        let mut max_include_depth = DEFAULT_MAX_INCLUDE_DEPTH;
+        let mut max_nesting = DEFAULT_MAX_NESTING;
+        let mut defines = Vec::new();
+        let mut warnings = Warnings::default();
+        let mut only_validate = false;
+        let mut keep_going = false;
+        let mut list_globals = false;
+        let mut stats = false;
+        let mut profile = false;
+        let mut dump_tokens = None;
+        let mut dump_ast = None;
+        let mut ast_graph = None;
+        let mut listing_path = None;
+        let mut source_map_path = None;
+        let mut deps_path = None;
+        let mut dialect = Dialect::default();
+        let mut color = ColorMode::Auto;
+        let mut entry = None;
+        let mut word_size = None;
+        let mut emit = Emit::Assembly;
+        let mut packed_chars = false;
+        let mut assemble = false;
+        let mut assembler = None;
+        let mut string_encoding = StringEncoding::default();
+        let mut trace_parse = false;
+        let mut show_categories = false;
+
+        let mut iter = args.iter().skip(1);
+
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                // Everything after it is a file, even if it starts with '-'.
+                "--" => {
+                    positional.extend(iter.by_ref().cloned());
+                    break;
+                },
+
+                "--tac" => use_simple_compiler = false,
+
+                "--only-validate" => only_validate = true,
+
+                "--keep-going" => keep_going = true,
+
+                "--pedantic" => dialect.pedantic = true,
+
+                "--braceless-functions" => dialect.braceless_functions = true,
+
+                "--trace-parse" => trace_parse = true,
+
+                "--no-color" | "--color=never" => color = ColorMode::Never,
+
+                "--color=always" => color = ColorMode::Always,
+
+                "--color=auto" => color = ColorMode::Auto,
+
+                "--list-globals" => list_globals = true,
+
+                "--stats" => stats = true,
+
+                "--profile" => profile = true,
+
+                "--emit=asm" => emit = Emit::Assembly,
+
+                "--emit-c" | "--emit=c" => emit = Emit::C,
+
+                "--emit=callgraph" => emit = Emit::CallGraph,
+
+                "--emit=bin" => emit = Emit::Binary,
+
+                "--emit=tokens-count" => emit = Emit::TokensCount,
+
+                "--packed-chars" => packed_chars = true,
+
+                "--assemble" => assemble = true,
+
+                "--assembler" => match iter.next() {
+                    Some(command) => assembler = Some(command.clone()),
+                    None => return Err("expected command after '--assembler'")
+                },
+
+                "--strings=bytes" => string_encoding = StringEncoding::Bytes,
+
+                "--strings=escaped" => string_encoding = StringEncoding::Escaped,
+
+                "--dump-tokens" => dump_tokens = Some(DumpFormat::Plain),
+
+                "--dump-tokens=json" => dump_tokens = Some(DumpFormat::Json),
+
+                "--dump-ast" => dump_ast = Some(DumpStage::Parsed),
+
+                "--dump-ast=optimized" => dump_ast = Some(DumpStage::Optimized),
+
+                "--ast-graphviz" => match iter.next() {
+                    Some(name) => ast_graph = Some(name.clone()),
+                    None => return Err("expected function name after '--ast-graphviz'")
+                },
+
+                "-Wdangling-else" => warnings.dangling_else = true,
+
+                "-Widentical-branches" => warnings.identical_branches = true,
+
+                "-Wno-undefined-extern" => warnings.undefined_extern = false,
+
+                "-Wno-unused-ternary" => warnings.unused_ternary = false,
+
+                "-Wno-write-strings" => warnings.write_strings = false,
+
+                "-w" => warnings.quiet = true,
+
+                "--show-categories" => show_categories = true,
+
+                "--word-size" => match iter.next().map(|size| size.as_str()) {
+                    Some("4") => word_size = Some(4),
+                    Some("8") => word_size = Some(8),
+                    _ => return Err("expected word size 4 or 8 after '--word-size'")
+                },
+
+                "--listing" => match iter.next() {
+                    Some(path) => listing_path = Some(path.clone()),
+                    None => return Err("expected file after '--listing'")
+                },
+
+                "--sourcemap" => match iter.next() {
+                    Some(path) => source_map_path = Some(path.clone()),
+                    None => return Err("expected file after '--sourcemap'")
+                },
+
+                "--emit-deps" => match iter.next() {
+                    Some(path) => deps_path = Some(path.clone()),
+                    None => return Err("expected file after '--emit-deps'")
+                },
+
+                "--entry" => match iter.next() {
+                    Some(name) => entry = Some(name.clone()),
+                    None => return Err("expected function name after '--entry'")
+                },
+
+                "--max-include-depth" => match iter.next().and_then(|depth| depth.parse().ok()) {
+                    Some(depth) => max_include_depth = depth,
+                    None => return Err("expected number after '--max-include-depth'")
+                },
+
+                "--max-nesting" => match iter.next().and_then(|depth| depth.parse().ok()) {
+                    Some(depth) => max_nesting = depth,
+                    None => return Err("expected number after '--max-nesting'")
+                },
+
+                "-I" => match iter.next() {
+                    Some(dir) => include_dirs.push(dir.clone()),
+                    None => return Err("expected directory after '-I'")
+                },
+
+                "-D" => match iter.next() {
+                    Some(define) => defines.push(parse_define(define)?),
+                    None => return Err("expected macro name after '-D'")
+                },
+
+                _ => if let Some(dir) = arg.strip_prefix("-I") {
+                    include_dirs.push(String::from(dir));
+                } else if let Some(define) = arg.strip_prefix("-D") {
+                    defines.push(parse_define(define)?);
+                } else if let Some(max) = arg.strip_prefix("-Wtoo-many-params=") {
+                    match max.parse() {
+                        Ok(max) => warnings.max_params = Some(max),
+                        Err(_) => return Err("expected number after '-Wtoo-many-params='")
+                    }
+                } else if let Some(name) = arg.strip_prefix("-Wno-") {
+                    match WarningKind::from_name(name) {
+                        Some(kind) => warnings.disabled.push(kind),
+                        None => return Err("unknown warning option")
+                    }
+                } else if arg.starts_with("-W") {
+                    return Err("unknown warning option")
+                } else if arg.starts_with("--emit=") {
+                    return Err("expected 'asm', 'c', 'callgraph', 'bin' or 'tokens-count' after '--emit='")
+                } else if arg.starts_with("--strings=") {
+                    return Err("expected 'bytes' or 'escaped' after '--strings='")
+                } else {
+                    positional.push(arg.clone());
+                }
+            }
+        }
+
+        // There is no output to name when only validating.
+        if only_validate && positional.len() == 1 {
+            positional.push(String::new());
+        }
+
+        if assemble && emit != Emit::Assembly {
+            return Err("'--assemble' needs assembly output")
+        }
+
+        let assembler = if assemble {
+            Some(assembler.unwrap_or_else(|| String::from("as")))
+        } else {
+            None
+        };
+
+        if emit == Emit::Binary && word_size.is_some_and(|size| size != 4) {
+            return Err("'--emit=bin' supports only word size 4")
+        }
+
+        if positional.len() != 2 {
             return Err("wrong arguments count")
         }
 
-        let input_path = args[1].clone();
-        let output_path = args[2].clone();
-        let use_simple_compiler = !args.contains(&String::from("--tac"));
+        let output_path = positional.pop().unwrap();
+        let input_path = positional.pop().unwrap();
+
+        // C words are `intptr_t`, so the C output defaults to the host's word.
+        let word_size = word_size.unwrap_or(if emit == Emit::C { std::mem::size_of::<usize>() } else { 4 });
 
         Ok(Config {
             input_path,
             output_path,
-            use_simple_compiler
+            use_simple_compiler,
+            include_dirs,
+            max_include_depth,
+            max_nesting,
+            defines,
+            warnings,
+            only_validate,
+            keep_going,
+            list_globals,
+            stats,
+            profile,
+            dump_tokens,
+            dump_ast,
+            ast_graph,
+            listing_path,
+            source_map_path,
+            deps_path,
+            dialect,
+            color,
+            entry,
+            word_size,
+            emit,
+            packed_chars,
+            assembler,
+            string_encoding,
+            trace_parse,
+            show_categories
         })
     }
 }
 
-// If there was a parsing error or semantic error, then this function will return Ok(()).
-pub fn run(conf: &Config) -> Result<(), Box<dyn Error>> {
+#[derive(Clone, Copy, PartialEq)]
+enum Emit {
+    Assembly,
+    C,
+    // Graphviz graph of the calls between functions.
+    CallGraph,
+    // Flat binary for the toy machine, 32-bit only.
+    Binary,
+    // Number of tokens in the main file and the scanning speed, for tracking
+    // the performance of the scanner. Not preprocessed.
+    TokensCount
+}
+
+// `NAME` alone is defined as 1, like in C.
+fn parse_define(arg: &str) -> Result<(String, String), &'static str> {
+    let (name, value) = arg.split_once('=').unwrap_or((arg, "1"));
+
+    // The same names as `#define` takes.
+    if macro_name(name).is_none() {
+        return Err("expected macro name after '-D'")
+    }
+
+    Ok((String::from(name), String::from(value)))
+}
+
+// Failures of `run`, `main` maps them to exit codes.
+#[derive(Debug)]
+pub enum RunError {
+    Io(std::io::Error),
+    // Bad command line arguments.
+    Config(&'static str),
+    // The program had parsing or semantic errors, this many of them. The
+    // diagnostics themselves are already reported.
+    Diagnostics(usize),
+    // The external assembler failed, it has reported why.
+    Assembler(String),
+    TacUnimplemented
+}
+
+impl RunError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RunError::Diagnostics(_) => 2,
+            RunError::Io(_) | RunError::Config(_) | RunError::Assembler(_) | RunError::TacUnimplemented => 1
+        }
+    }
+
+    // Counts what the error reporter has seen, some errors are found before the
+    // failing stage.
+    fn diagnostics() -> RunError {
+        RunError::Diagnostics(error_count())
+    }
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::Io(e) => write!(f, "{}", e),
+            RunError::Config(msg) => write!(f, "problem parsing arguments: {}", msg),
+            RunError::Diagnostics(1) => write!(f, "compilation failed with 1 error"),
+            RunError::Diagnostics(count) => write!(f, "compilation failed with {} errors", count),
+            RunError::Assembler(msg) => write!(f, "{}", msg),
+            RunError::TacUnimplemented => write!(f, "TAC compiler is under development")
+        }
+    }
+}
+
+impl Error for RunError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RunError::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<std::io::Error> for RunError {
+    fn from(e: std::io::Error) -> Self {
+        RunError::Io(e)
+    }
+}
+
+pub fn run(conf: &Config) -> Result<(), RunError> {
+    set_color_mode(conf.color);
+    set_warning_filter(conf.warnings.quiet, &conf.warnings.disabled);
+    set_show_categories(conf.show_categories);
+
     let file = read_file(&conf.input_path)?;
 
-    if conf.use_simple_compiler {
+    if conf.emit == Emit::TokensCount {
+        run_token_count(file);
+        Ok(())
+    } else if let Some(format) = conf.dump_tokens {
+        let mut preprocessor = make_preprocessor(conf, file);
+        dump_tokens(&mut preprocessor, format);
+        Ok(())
+    } else if let Some(name) = &conf.ast_graph {
+        run_ast_graph(conf, file, name)
+    } else if let Some(stage) = conf.dump_ast {
+        run_ast_dump(conf, file, stage)
+    } else if conf.use_simple_compiler {
         run_simple_compiler(conf, file)
     } else {
         run_tac_compiler(conf, file)
     }
 }
 
-fn run_simple_compiler(conf: &Config, file: Rc<File>) -> Result<(), Box<dyn Error>> {
+// Counts the tokens of arbitrary bytes, for benchmarks.
+pub fn count_tokens(data: &[u8]) -> usize {
+    let file = Rc::new(File {
+        path: String::from("<bench>"),
+        data: data.to_vec()
+    });
+
+    Scanner::new(file).count_tokens()
+}
+
+// Builds every token of arbitrary bytes and returns how many there were, for
+// benchmarks.
+pub fn scan_tokens(data: &[u8]) -> usize {
+    let file = Rc::new(File {
+        path: String::from("<bench>"),
+        data: data.to_vec()
+    });
+
     let mut scanner = Scanner::new(file);
-    let mut parser = Parser::new(&mut scanner);
+    let mut count = 0;
+
+    while scanner.next_token().kind != TokenType::EndOfFile {
+        count += 1;
+    }
+
+    count
+}
+
+// The parser recurses as deep as its nesting limit allows, which takes more
+// than the stack a spawned thread gets by default in debug builds.
+const PARSER_STACK_SIZE: usize = 64 * 1024 * 1024;
+
+// Scans and parses arbitrary bytes, for fuzzers. Must not panic on any input,
+// returns false if there were errors. Runs on its own thread, so it doesn't
+// depend on the stack size of the caller's.
+pub fn fuzz_parse(data: &[u8]) -> bool {
+    let data = data.to_vec();
+
+    let worker = std::thread::Builder::new()
+        .stack_size(PARSER_STACK_SIZE)
+        .spawn(move || parse_bytes(data))
+        .expect("failed to spawn the parser thread");
+
+    match worker.join() {
+        Ok(valid) => valid,
+        Err(panic) => std::panic::resume_unwind(panic)
+    }
+}
 
-    let mut validator = Validator::new();
-    let mut compiler = Compiler::new();
+fn parse_bytes(data: Vec<u8>) -> bool {
+    let file = Rc::new(File {
+        path: String::from("<fuzz>"),
+        data
+    });
+
+    let mut preprocessor = Preprocessor::new(Scanner::new(file), Vec::new());
+    let mut parser = Parser::new(&mut preprocessor, Dialect::default());
+    let mut failed = false;
 
     while !parser.is_at_end() {
-        if let Some(decl) = parser.parse_one_decl() {
-            if validator.validate_one_decl(&decl) {
-                compiler.compile_one_decl(&decl)
+        if parser.parse_one_decl().is_none() {
+            failed = true;
+        }
+    }
+
+    !failed && !parser.had_error()
+}
+
+fn run_simple_compiler(conf: &Config, file: Rc<File>) -> Result<(), RunError> {
+    let mut preprocessor = make_preprocessor(conf, file.clone());
+
+    if conf.profile {
+        preprocessor.enable_scan_timing();
+    }
+
+    let mut parser = make_parser(conf, &mut preprocessor);
+
+    if conf.trace_parse {
+        parser.enable_trace();
+    }
+
+    let mut validator = Validator::new(conf.warnings.clone(), conf.word_size);
+    let mut compiler = Compiler::new(conf.word_size, conf.packed_chars);
+    compiler.set_string_encoding(conf.string_encoding);
+    compiler.set_entry(conf.entry());
+    let mut c_backend = CBackend::new(conf.word_size, conf.packed_chars);
+    let mut stats = Stats::default();
+    let mut profile = Profile::default();
+    let mut failed = false;
+
+    // Declarations are dropped right after validation when no code is needed,
+    // the validator keeps only what it needs to know about the globals.
+    let generate = !conf.only_validate && !conf.list_globals && !conf.stats;
+
+    // An empty input is a valid empty program and produces an empty output.
+    while !parser.is_at_end() {
+        let errors = error_count();
+
+        if let Some(mut decl) = time(&mut profile.parsing, || parser.parse_one_decl()) {
+            time(&mut profile.optimization, || fold_decl(&mut decl, conf.word_size));
+            stats.visit_decl(&decl);
+
+            // A declaration the parser recovered inside of is incomplete.
+            let parsed = error_count() == errors;
+
+            if !time(&mut profile.validation, || validator.validate_one_decl(&decl)) || !parsed {
+                failed = true;
+            } else if generate && conf.emit == Emit::C {
+                time(&mut profile.codegen, || c_backend.add_decl(decl));
+            } else if generate {
+                time(&mut profile.codegen, || compiler.compile_one_decl(&decl))
             }
+        } else {
+            failed = true;
+        }
+    }
+
+    // Errors the parser recovered from inside a declaration still fail the build.
+    if parser.had_error() {
+        failed = true;
+    }
+
+    profile.scanning = preprocessor.scan_time();
+    profile.parsing = profile.parsing.saturating_sub(profile.scanning);
+
+    if !time(&mut profile.validation, || validator.finish()) {
+        failed = true;
+    }
+
+    if conf.entry.is_some() && !has_function(validator.globals(), conf.entry()) {
+        report_program_error(&format!("entry function '{}' is not defined", conf.entry()));
+        failed = true;
+    }
+
+    if conf.list_globals {
+        list_globals(validator.globals());
+    }
+
+    if conf.stats {
+        print!("{}", stats);
+    }
+
+    // With --keep-going the declarations that compiled are still written out.
+    if failed && !conf.keep_going {
+        return Err(RunError::diagnostics());
+    }
+
+    if generate {
+        time(&mut profile.codegen, || compiler.resolve_initializers());
+
+        if let Some(path) = &conf.listing_path {
+            std::fs::write(path, compiler.get_listing(&file))?;
         }
+
+        if let Some(path) = &conf.source_map_path {
+            std::fs::write(path, compiler.get_source_map())?;
+        }
+
+        if let Some(path) = &conf.deps_path {
+            std::fs::write(path, deps::render(&conf.output_path, &conf.input_path, preprocessor.included_files()))?;
+        }
+
+        let start = Instant::now();
+
+        let code = match conf.emit {
+            Emit::Assembly => compiler.get_code().into_bytes(),
+            Emit::C => c_backend.get_code().into_bytes(),
+            Emit::CallGraph => callgraph::render(validator.globals(), validator.calls()).into_bytes(),
+
+            Emit::Binary => match compiler.get_binary(conf.entry()) {
+                Ok(code) => code,
+                Err(msg) => {
+                    report_program_error(&msg);
+                    return Err(RunError::diagnostics());
+                }
+            },
+
+            Emit::TokensCount => unreachable!("run counts tokens before compiling")
+        };
+
+        profile.codegen += start.elapsed();
+
+        match &conf.assembler {
+            Some(assembler) => run_assembler(assembler, conf, &code)?,
+            None => std::fs::write(&conf.output_path, code)?
+        }
+    }
+
+    if conf.profile {
+        eprint!("{}", profile);
     }
 
-    match std::fs::write(&conf.output_path, compiler.get_code()) {
-        Ok(()) => Ok(()),
-        Err(e) => Err(Box::new(e))
+    if failed {
+        return Err(RunError::diagnostics());
     }
+
+    Ok(())
+}
+
+fn run_ast_dump(conf: &Config, file: Rc<File>, stage: DumpStage) -> Result<(), RunError> {
+    let mut preprocessor = make_preprocessor(conf, file);
+    let mut parser = make_parser(conf, &mut preprocessor);
+    let mut failed = false;
+
+    while !parser.is_at_end() {
+        if let Some(mut decl) = parser.parse_one_decl() {
+            if stage == DumpStage::Optimized {
+                fold_decl(&mut decl, conf.word_size);
+            }
+
+            print!("{}", dump_decl(&decl));
+        } else {
+            failed = true;
+        }
+    }
+
+    if failed || parser.had_error() {
+        return Err(RunError::diagnostics());
+    }
+
+    Ok(())
+}
+
+fn run_ast_graph(conf: &Config, file: Rc<File>, function: &str) -> Result<(), RunError> {
+    let mut preprocessor = make_preprocessor(conf, file);
+    let mut parser = make_parser(conf, &mut preprocessor);
+    let mut found = false;
+
+    while !parser.is_at_end() {
+        if let Some(Decl { kind: DeclKind::Function { name, params, body }, .. }) = parser.parse_one_decl() {
+            if name == function && !found {
+                print!("{}", ast_graph::render(&name, &params, &body));
+                found = true;
+            }
+        }
+    }
+
+    if parser.had_error() {
+        return Err(RunError::diagnostics());
+    }
+
+    if !found {
+        report_program_error(&format!("function '{}' is not defined", function));
+        return Err(RunError::diagnostics());
+    }
+
+    Ok(())
+}
+
+// The assembly goes through stdin, the assembler's diagnostics go straight to
+// our stderr.
+fn run_assembler(assembler: &str, conf: &Config, code: &[u8]) -> Result<(), RunError> {
+    let mode = if conf.word_size == 8 { "--64" } else { "--32" };
+
+    let mut child = Command::new(assembler)
+        .args([mode, "-o", &conf.output_path, "-"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| RunError::Assembler(format!("cannot run '{}': {}", assembler, e)))?;
+
+    // An assembler that exits without reading it all is reported by its status.
+    match child.stdin.take().expect("stdin is piped").write_all(code) {
+        Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => return Err(RunError::Io(e)),
+        _ => {}
+    }
+
+    let status = child.wait()?;
+
+    if !status.success() {
+        return Err(RunError::Assembler(format!("'{}' failed with {}", assembler, status)));
+    }
+
+    Ok(())
+}
+
+fn make_parser<'a>(conf: &Config, preprocessor: &'a mut Preprocessor) -> Parser<'a> {
+    let mut parser = Parser::new(preprocessor, conf.dialect.clone());
+    parser.set_max_nesting(conf.max_nesting);
+    parser
+}
+
+fn make_preprocessor(conf: &Config, file: Rc<File>) -> Preprocessor {
+    let mut preprocessor = Preprocessor::new(Scanner::new(file), conf.include_dirs.clone());
+    preprocessor.set_max_include_depth(conf.max_include_depth);
+
+    for (name, value) in &conf.defines {
+        preprocessor.define(name, value, "<command line>");
+    }
+
+    preprocessor
+}
+
+fn has_function(globals: &[Global], name: &str) -> bool {
+    globals.iter().any(|global| global.name == name && matches!(global.kind, GlobalKind::Function { .. }))
+}
+
+fn list_globals(globals: &[Global]) {
+    for global in globals {
+        let (kind, size) = match global.kind {
+            GlobalKind::Function { arity } => ("function", arity),
+            GlobalKind::Data { size } => ("data", size),
+            GlobalKind::Vector { size } => ("vector", size)
+        };
+
+        println!("{}\t{}\t{}\t{}:{}", global.name, kind, size, global.pos.file.path, global.pos.line);
+    }
+}
+
+fn run_token_count(file: Rc<File>) {
+    let size = file.data.len();
+    let start = Instant::now();
+    let count = Scanner::new(file).count_tokens();
+    let seconds = start.elapsed().as_secs_f64();
+
+    println!("{} tokens in {} bytes", count, size);
+    println!("{:.3} ms, {:.0} tokens/s", seconds * 1000.0, count as f64 / seconds);
 }
 
-fn run_tac_compiler(_conf: &Config, _file: Rc<File>) -> Result<(), Box<dyn Error>> {
-    Err("TAC compiler is under development".into())
+fn run_tac_compiler(_conf: &Config, _file: Rc<File>) -> Result<(), RunError> {
+    Err(RunError::TacUnimplemented)
 }
@@ -0,0 +1,37 @@
+// Renders the source interleaved with the assembly generated for each line:
+//
+//   3 | main() {
+//     | 	.globl main
+//     | main:
+//
+// Code generated from included files is left out.
+
+use crate::{file::File, gas, program::Program};
+use std::{fmt::Write, rc::Rc};
+
+pub fn render(program: &Program, file: &Rc<File>) -> String {
+    let source = String::from_utf8_lossy(&file.data);
+    let lines: Vec<&str> = source.lines().collect();
+    let mut code = vec![String::new(); lines.len()];
+
+    for function in &program.functions {
+        for (pos, text) in gas::render_function_lines(program, function) {
+            if Rc::ptr_eq(&pos.file, file) && pos.line >= 1 && pos.line <= lines.len() {
+                code[pos.line - 1].push_str(&text);
+            }
+        }
+    }
+
+    let width = lines.len().to_string().len();
+    let mut out = String::new();
+
+    for (i, line) in lines.iter().enumerate() {
+        writeln!(out, " {:>width$} | {}", i + 1, line.trim_end_matches('\r')).unwrap();
+
+        for instruction in code[i].lines() {
+            writeln!(out, " {:width$} | {}", "", instruction).unwrap();
+        }
+    }
+
+    out
+}
@@ -1,15 +1,29 @@
-use blang::Config;
+use blang::{Config, RunError};
 
+// The parser and the passes after it recurse on the tree, nesting as deep as
+// `--max-nesting` allows needs more than the default main thread stack.
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+// Exit codes: 0 on success, 1 on usage or I/O errors, 2 on compilation errors.
 fn main() {
     let args: Vec<String> = std::env::args().collect();
 
-    let config = Config::build(&args).unwrap_or_else(|err| {
-        eprintln!("error: problem parsing arguments: {err}");
-        std::process::exit(1);
-    });
+    let worker = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            Config::build(&args)
+                .map_err(RunError::Config)
+                .and_then(|config| blang::run(&config))
+        })
+        .expect("failed to spawn the compiler thread");
+
+    let result = match worker.join() {
+        Ok(result) => result,
+        Err(panic) => std::panic::resume_unwind(panic)
+    };
 
-    if let Err(e) = blang::run(&config) {
+    if let Err(e) = result {
         eprintln!("error: {e}");
-        std::process::exit(1);
+        std::process::exit(e.exit_code());
     }
 }
@@ -1,65 +1,173 @@
 use crate::ast::*;
 use crate::file::*;
-use crate::scanner::*;
+use crate::preprocessor::Preprocessor;
 use crate::token::*;
-use crate::error_reporter::report_error;
+use crate::error_reporter::{report_error, report_note};
+use crate::escape::decode_escapes;
+use crate::folder::{FILE_BUILTIN, LEN_BUILTIN, LINE_BUILTIN, WORDSIZE_BUILTIN};
 
-use std::rc::Rc;
+use std::{cell::Cell, rc::Rc};
+
+// Deeper nesting of expressions and statements is an error rather than a
+// stack overflow.
+pub const DEFAULT_MAX_NESTING: usize = 1000;
 
 pub struct Parser<'a> {
-    scanner: &'a mut Scanner,
+    preprocessor: &'a mut Preprocessor,
     previous_token: Token,
     current_token: Token,
-    had_error: bool
+    had_error: bool,
+    dialect: Dialect,
+    depth: usize,
+    max_nesting: usize,
+    // Nesting of the traced calls, `None` when tracing is off.
+    trace: Option<Rc<Cell<usize>>>,
+    // Tokens given back by a failed `try_parse`, in reverse order.
+    replay: Vec<Token>,
+    // Tokens read inside `try_parse`, to give back if it fails.
+    recorded: Vec<Token>,
+    speculation: usize,
+    // An error token was read inside `try_parse`, it is reported when read again.
+    speculation_failed: bool
+}
+
+#[derive(Clone, Default)]
+pub struct Dialect {
+    // Rejects the extensions below.
+    pub pedantic: bool,
+    // Allows any statement as a function body, not only a block.
+    pub braceless_functions: bool
+}
+
+// Features that are not part of the original B, rejected in pedantic mode.
+#[derive(Clone, Copy)]
+enum Extension {
+    ByteStrings,
+    LenBuiltin,
+    WordSizeBuiltin,
+    LineBuiltin,
+    FileBuiltin,
+    InlineAsm,
+    LogicalShift,
+    LoopLevels
+}
+
+impl Extension {
+    fn name(self) -> &'static str {
+        match self {
+            Extension::ByteStrings => "byte string literals",
+            Extension::LenBuiltin => "the '__len' builtin",
+            Extension::WordSizeBuiltin => "the '__wordsize' builtin",
+            Extension::LineBuiltin => "the '__line' builtin",
+            Extension::FileBuiltin => "the '__file' builtin",
+            Extension::InlineAsm => "inline assembly",
+            Extension::LogicalShift => "the '>>>' operator",
+            Extension::LoopLevels => "'break N' and 'continue N'"
+        }
+    }
+}
+
+// Logs the entry to a `parse_*` function, and the exit when dropped.
+struct TraceGuard {
+    name: &'static str,
+    depth: Rc<Cell<usize>>
+}
+
+impl TraceGuard {
+    fn enter(name: &'static str, depth: &Rc<Cell<usize>>, token: &Token) -> Self {
+        eprintln!("{}{} at '{}' line {}", "  ".repeat(depth.get()), name, token.text(), token.pos.line);
+        depth.set(depth.get() + 1);
+
+        TraceGuard { name, depth: depth.clone() }
+    }
+}
+
+impl Drop for TraceGuard {
+    fn drop(&mut self) {
+        self.depth.set(self.depth.get() - 1);
+        eprintln!("{}end {}", "  ".repeat(self.depth.get()), self.name);
+    }
+}
+
+// Only checks an `Option` when tracing is off.
+macro_rules! trace {
+    ($self:ident, $name:expr) => {
+        let _trace = $self.trace.as_ref().map(|depth| TraceGuard::enter($name, depth, &$self.current_token));
+    }
 }
 
 struct ParserError {
     pos: FilePosition,
-    msg: String
+    msg: String,
+    // Boxed to keep the results of the deeply recursive parse functions small.
+    note: Option<Box<(FilePosition, String)>>
+}
+
+impl ParserError {
+    fn report(&self) {
+        report_error(&self.pos, &self.msg);
+
+        if let Some(note) = &self.note {
+            report_note(&note.0, &note.1);
+        }
+    }
 }
 
 macro_rules! parse_expression_type {
     ($name:ident, $inner:ident, $type:expr $(, $types:expr)*) => {
         fn $name(&mut self) -> Result<Expr, ParserError> {
-            let mut left = self.$inner()?;
+            trace!(self, stringify!($name));
 
-            while self.matching($type) $( || self.matching($types) )* {
-                let token = self.previous_token.clone();
+            self.chain(|this| {
+                let mut left = this.$inner()?;
 
-                let right = self.$inner()?;
+                while this.matching($type) $( || this.matching($types) )* {
+                    let token = this.previous_token.clone();
+                    this.allow_operator(token.kind)?;
+                    this.link()?;
 
-                left = Expr {
-                    pos: token.pos,
-                    kind: ExprKind::BinOp(Box::new(left), token.kind, Box::new(right))
+                    let right = this.$inner()?;
+
+                    left = Expr {
+                        pos: token.pos,
+                        kind: ExprKind::BinOp(Box::new(left), token.kind, Box::new(right))
+                    }
                 }
-            }
 
-            Ok(left)
+                Ok(left)
+            })
         }
     }
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(scanner: &mut Scanner) -> Parser {
-        let token = scanner.next_token();
-        let dummy_token = Token {
-            kind: TokenType::Error,
-            pos: FilePosition {
-                file: Rc::new(File {
-                    path: String::from(""),
-                    data: Vec::new()
-                }),
-                line: 0
-            },
-            data: String::from("")
+    pub fn new(preprocessor: &mut Preprocessor, dialect: Dialect) -> Parser<'_> {
+        let dummy_token = Token::error(FilePosition {
+            file: Rc::new(File {
+                path: String::from(""),
+                data: Vec::new()
+            }),
+            line: 0,
+            offset: 0
+        }, "");
+
+        let mut parser = Parser {
+            preprocessor,
+            previous_token: dummy_token.clone(),
+            current_token: dummy_token,
+            had_error: false,
+            dialect,
+            depth: 0,
+            max_nesting: DEFAULT_MAX_NESTING,
+            trace: None,
+            replay: Vec::new(),
+            recorded: Vec::new(),
+            speculation: 0,
+            speculation_failed: false
         };
 
-        Parser {
-            scanner,
-            previous_token: dummy_token,
-            current_token: token,
-            had_error: false
-        }
+        parser.advance();
+        parser
     }
 
     pub fn is_at_end(&mut self) -> bool {
@@ -72,17 +180,33 @@ impl<'a> Parser<'a> {
         match decl {
             Ok(d) => Some(d),
             Err(e) => {
-                report_error(&e.pos, &e.msg);
+                e.report();
                 self.synchronize_decl();
                 None
             }
         }
     }
 
+    // Byte offset of the next token.
+    pub fn current_offset(&self) -> usize {
+        self.current_token.pos.offset
+    }
+
     pub fn had_error(&self) -> bool {
         self.had_error
     }
 
+    pub fn set_max_nesting(&mut self, depth: usize) {
+        self.max_nesting = depth;
+    }
+
+    // Logs every `parse_*` entry and exit to stderr.
+    pub fn enable_trace(&mut self) {
+        self.trace = Some(Rc::new(Cell::new(0)));
+    }
+
+    // Always consumes a token, so the loops calling `parse_one_decl` until
+    // the end of input terminate on any input.
     fn synchronize_decl(&mut self) {
         self.advance();
 
@@ -95,6 +219,8 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_decl(&mut self) -> Result<Decl, ParserError> {
+        trace!(self, "parse_decl");
+
         if self.matching(TokenType::Identifier) {
             let name = self.previous_token.clone();
             if self.matching(TokenType::LeftParen) {
@@ -102,12 +228,19 @@ impl<'a> Parser<'a> {
             } else {
                 self.continue_parse_var_decl(name)
             }
+        } else if matches!(self.current_token.kind,
+            TokenType::KeywordIf | TokenType::KeywordWhile | TokenType::KeywordDo | TokenType::KeywordReturn |
+            TokenType::KeywordAuto | TokenType::KeywordExtern | TokenType::KeywordBreak |
+            TokenType::KeywordContinue | TokenType::KeywordAsm | TokenType::LeftBracket) {
+            Err(self.error_at_current("statements are not allowed at file scope"))
         } else {
             Err(self.error_at_current("expected declaration"))
         }
     }
 
     fn parse_stmt(&mut self) -> Result<Stmt, ParserError> {
+        trace!(self, "parse_stmt");
+
         if self.matching(TokenType::LeftBracket) {
             self.parse_block_stmt()
         } else if self.matching(TokenType::KeywordReturn) {
@@ -126,24 +259,43 @@ impl<'a> Parser<'a> {
             self.parse_break_stmt()
         } else if self.matching(TokenType::KeywordContinue) {
             self.parse_continue_stmt()
+        } else if self.matching(TokenType::KeywordAsm) {
+            self.parse_asm_stmt()
+        } else if self.matching(TokenType::Semicolon) {
+            Ok(Stmt {
+                pos: self.previous_token.pos.clone(),
+                kind: StmtKind::Empty
+            })
         } else {
             self.parse_expr_stmt()
         }
     }
     
     fn parse_auto_stmt(&mut self) -> Result<Stmt, ParserError> {
+        trace!(self, "parse_auto_stmt");
+
         let token = self.previous_token.clone();
-        let var = self.parse_var()?;
+        let mut vars = vec![self.parse_var()?];
+
+        while self.matching(TokenType::Comma) {
+            vars.push(self.parse_var()?);
+        }
+
+        self.require(TokenType::Semicolon, "expected ';' at the end of declaration")?;
 
         Ok(Stmt {
             pos: token.pos,
-            kind: StmtKind::Auto(var)
+            kind: StmtKind::Auto(vars)
         })
     }
 
     fn continue_parse_var_decl(&mut self, name: Token) -> Result<Decl, ParserError> {
+        trace!(self, "continue_parse_var_decl");
+
         let pos = name.pos.clone();
-        let var = self.continue_parse_var(name)?;
+        let var = self.continue_parse_var(name, false)?;
+
+        self.require(TokenType::Semicolon, "expected ';' at the end of declaration")?;
 
         Ok(Decl {
             pos,
@@ -152,24 +304,74 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_var(&mut self) -> Result<Variable, ParserError> {
+        trace!(self, "parse_var");
+
         let name = self.require(TokenType::Identifier, "expected variable name")?;
 
-        self.continue_parse_var(name)
+        self.continue_parse_var(name, true)
     }
 
-    fn continue_parse_var(&mut self, name: Token) -> Result<Variable, ParserError> {
-        let initial = if !self.matching(TokenType::Semicolon) {
-            let res = self.parse_expr()?;
-            self.require(TokenType::Semicolon, "expected ';' at the end of declaration")?;
-            Some(res)
+    // Stops before the ';'. In an `auto` list a ',' right after the name or
+    // after the initializer of a scalar starts the next variable, and so does
+    // one before a name followed by ',', ';' or '[', `auto s[] "hi", n;`
+    // declares `n`. Otherwise it separates initializers.
+    fn continue_parse_var(&mut self, name: Token, auto: bool) -> Result<Variable, ParserError> {
+        trace!(self, "continue_parse_var");
+
+        let size = if self.matching(TokenType::LeftBrace) {
+            let size = if !self.check(TokenType::RightBrace) {
+                Some(self.parse_expr()?)
+            } else {
+                None
+            };
+
+            self.require(TokenType::RightBrace, "expected ']' after array size")?;
+            VariableSize::Vector(size)
         } else {
-            None
+            VariableSize::Scalar
         };
-        
-        Ok(Variable(name.data, initial))
+
+        // C writes `x = 5;`, the initializer is reported and parsed anyway so
+        // the variable is still declared.
+        if self.matching(TokenType::Equal) {
+            let error = self.error_at_previous("unexpected '=' in declaration; initializer follows the name directly");
+            self.report_recovered(error);
+        }
+
+        let mut initial = Vec::new();
+        let single = auto && matches!(size, VariableSize::Scalar);
+        let ends = self.check(TokenType::Semicolon) || (auto && self.check(TokenType::Comma));
+
+        if !ends {
+            initial.push(self.parse_assignment()?);
+
+            // A trailing comma before the ';' is allowed.
+            while !single && (!auto || !self.lookahead(Self::parse_next_auto_var)) &&
+                self.matching(TokenType::Comma) && !self.check(TokenType::Semicolon) {
+                    initial.push(self.parse_assignment()?);
+                }
+        }
+
+        Ok(Variable {
+            name: name.text().into_owned(),
+            size,
+            initial
+        })
+    }
+
+    fn parse_next_auto_var(&mut self) -> Result<(), ParserError> {
+        self.require(TokenType::Comma, "expected ','")?;
+        self.require(TokenType::Identifier, "expected variable name")?;
+
+        match self.current_token.kind {
+            TokenType::Comma | TokenType::Semicolon | TokenType::LeftBrace => Ok(()),
+            _ => Err(self.error_at_current("expected ',', ';' or '[' after variable name"))
+        }
     }
 
     fn parse_extern_stmt(&mut self) -> Result<Stmt, ParserError> {
+        trace!(self, "parse_extern_stmt");
+
         let token = self.previous_token.clone();
 
         let name = self.require(TokenType::Identifier, "expected identifier")?;
@@ -178,27 +380,31 @@ impl<'a> Parser<'a> {
 
         Ok(Stmt {
             pos: token.pos,
-            kind: StmtKind::Extern(name.data)
+            kind: StmtKind::Extern(name.text().into_owned())
         })
     }
 
     fn parse_expr(&mut self) -> Result<Expr, ParserError> {
+        trace!(self, "parse_expr");
+
         self.parse_comma_expr()
     }
 
     parse_expression_type!(parse_comma_expr, parse_assignment, TokenType::Comma);
 
     fn parse_assignment(&mut self) -> Result<Expr, ParserError> {
+        trace!(self, "parse_assignment");
+
         let left = self.parse_ternary()?;
         
         if self.matching(TokenType::Equal) {
             let equal_token = self.previous_token.clone();
             
-            let right = self.parse_assignment()?;
+            let right = self.nested(Self::parse_assignment)?;
 
             return Ok(Expr {
                 pos: equal_token.pos,
-                kind: ExprKind::BinOp(Box::new(left), equal_token.kind, Box::new(right))
+                kind: ExprKind::Assign(Box::new(left), Box::new(right))
             })
         }
 
@@ -206,16 +412,20 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_ternary(&mut self) -> Result<Expr, ParserError> {
+        trace!(self, "parse_ternary");
+
         let condition = self.parse_logical_or()?;
 
         if self.matching(TokenType::QuestionMark) {
             let question_mark = self.previous_token.clone();
 
-            let then_arm = self.parse_expr()?;
+            // The comma operator is not allowed in either arm, and the else arm
+            // recurses so that `a ? b : c ? d : e` groups as `a ? b : (c ? d : e)`.
+            let then_arm = self.nested(Self::parse_assignment)?;
 
             self.require(TokenType::Colon, "expected ':' in ternary expression")?;
             
-            let else_arm = self.parse_expr()?;
+            let else_arm = self.nested(Self::parse_ternary)?;
 
             return Ok(Expr {
                 pos: question_mark.pos,
@@ -234,22 +444,32 @@ impl<'a> Parser<'a> {
     parse_expression_type!(parse_equality, parse_comparison, TokenType::EqualEqual, TokenType::BangEqual);
     parse_expression_type!(parse_comparison, parse_shift, TokenType::Greater, TokenType::Less,
                            TokenType::GreaterEqual, TokenType::LessEqual);
-    parse_expression_type!(parse_shift, parse_term, TokenType::GreaterGreater, TokenType::LessLess);
+    parse_expression_type!(parse_shift, parse_term, TokenType::GreaterGreater, TokenType::GreaterGreaterGreater,
+                           TokenType::LessLess);
     parse_expression_type!(parse_term, parse_factor, TokenType::Plus, TokenType::Minus);
     parse_expression_type!(parse_factor, parse_unary, TokenType::Star, TokenType::Slash, TokenType::Percent);
 
     fn parse_unary(&mut self) -> Result<Expr, ParserError> {
+        trace!(self, "parse_unary");
+
         if self.matching(TokenType::Minus) || self.matching(TokenType::Plus) ||
             self.matching(TokenType::Ampersand) || self.matching(TokenType::Star) ||
             self.matching(TokenType::Bang) || self.matching(TokenType::Tilda) ||
             self.matching(TokenType::PlusPlus) || self.matching(TokenType::MinusMinus) {
                 let token = self.previous_token.clone();
 
-                let expr = self.parse_unary()?;
+                // The minus of a negative number belongs to the literal, so the
+                // most negative word can be written.
+                if token.kind == TokenType::Minus && self.matching(TokenType::IntLiteral) {
+                    let expr = self.parse_int_literal(Some(token))?;
+                    return self.continue_parse_postfix(expr);
+                }
+
+                let expr = self.nested(Self::parse_unary)?;
 
                 Ok(Expr {
                     pos: token.pos,
-                    kind: ExprKind::UnaryOp(token.kind, false, Box::new(expr))
+                    kind: ExprKind::UnaryOp(token.kind, Fixity::Prefix, Box::new(expr))
                 })
             } else {
                 self.parse_primary()
@@ -257,93 +477,228 @@ impl<'a> Parser<'a> {
     }
     
     fn parse_primary(&mut self) -> Result<Expr, ParserError> {
+        trace!(self, "parse_primary");
+
         let expr = if self.matching(TokenType::IntLiteral) {
+            self.parse_int_literal(None)
+        } else if self.matching(TokenType::Identifier) {
             let token = self.previous_token.clone();
-
-            let number_res = token.data.parse::<i32>();
-
-            match number_res {
-                Ok(num) => Ok(Expr {
-                    pos: token.pos,
-                    kind: ExprKind::IntLit(num)
-                }),
-
-                Err(e) => {
-                    Err(self.error_at_current(&e.to_string()))
-                }
+            let name = token.text().into_owned();
+
+            if name == LEN_BUILTIN {
+                self.allow_extension(Extension::LenBuiltin)?;
+            } else if name == WORDSIZE_BUILTIN {
+                self.allow_extension(Extension::WordSizeBuiltin)?;
+            } else if name == LINE_BUILTIN {
+                self.allow_extension(Extension::LineBuiltin)?;
+            } else if name == FILE_BUILTIN {
+                self.allow_extension(Extension::FileBuiltin)?;
             }
 
-        } else if self.matching(TokenType::Identifier) {
-            let token = self.previous_token.clone();
-            
             Ok(Expr {
                 pos: token.pos,
-                kind: ExprKind::Var(token.data.clone())
+                kind: ExprKind::Var(name)
             })
         } else if self.matching(TokenType::LeftParen) {
-            let expr = self.parse_expr()?;
-            self.require(TokenType::RightParen, "expected ')' after expression")?;
+            let open = self.previous_token.clone();
+            let expr = self.nested(Self::parse_expr)?;
+            self.require_closing(&open, TokenType::RightParen, "expected ')' after expression")?;
             Ok(expr)
             
-        } else if self.matching(TokenType::StringLiteral) {
+        } else if self.matching(TokenType::StringLiteral) || self.matching(TokenType::ByteStringLiteral) {
             let lit = self.previous_token.clone();
+            let value = self.decode_literal(&lit)?;
+
+            let kind = if lit.kind == TokenType::ByteStringLiteral {
+                self.allow_extension(Extension::ByteStrings)?;
+                StringKind::Raw
+            } else {
+                StringKind::NulTerminated
+            };
+
             Ok(Expr {
                 pos: lit.pos,
-                kind: ExprKind::StringLit(lit.data)
+                kind: ExprKind::StringLit(value, kind)
             })
+        } else if self.matching(TokenType::CharLiteral) {
+            let lit = self.previous_token.clone();
+            let value = self.decode_literal(&lit)?;
+
+            let mut chars = value.chars();
+            match (chars.next(), chars.next()) {
+                (Some(ch), None) => Ok(Expr {
+                    kind: ExprKind::IntLit(ch as i32, Some(Rc::from(lit.text()))),
+                    pos: lit.pos
+                }),
+
+                _ => Err(self.error_at_previous("character literal must contain exactly one character"))
+            }
         } else {
-            Err(self.error_at_current("expected expression"))
+            let found = self.current_token.kind;
+
+            let msg = if found.is_binary_only() {
+                format!("expected expression, found {} (binary operator without a left operand)", found)
+            } else {
+                format!("expected expression, found {}", found)
+            };
+
+            Err(self.error_at_current(&msg))
         }?;
 
         self.continue_parse_postfix(expr)
     }
 
+    // The number is the previous token, `minus` the sign before it if any.
+    // As in C, `0x` starts a hexadecimal literal and a leading `0` an octal one.
+    fn parse_int_literal(&mut self, minus: Option<Token>) -> Result<Expr, ParserError> {
+        let token = self.previous_token.clone();
+        let text = token.text();
+
+        let (radix, start) = if text.starts_with("0x") || text.starts_with("0X") {
+            (16, 2)
+        } else if text.len() > 1 && text.starts_with('0') {
+            (8, 1)
+        } else {
+            (10, 0)
+        };
+
+        let radix_name = match radix {
+            16 => "hexadecimal",
+            8 => "octal",
+            _ => "decimal"
+        };
+
+        let digits = &text[start..];
+
+        if let Some(i) = digits.chars().position(|ch| !ch.is_digit(radix)) {
+            self.had_error = true;
+
+            return Err(ParserError {
+                pos: FilePosition { offset: token.pos.offset + start + i, ..token.pos.clone() },
+                msg: format!("invalid {} digit '{}'", radix_name, &digits[i..i + 1]),
+                note: None
+            });
+        }
+
+        if digits.is_empty() {
+            return Err(self.error_at_previous("expected hexadecimal digits after '0x'"));
+        }
+
+        let magnitude = match u64::from_str_radix(digits, radix) {
+            Ok(magnitude) => i128::from(magnitude),
+            Err(_) => return Err(self.error_at_previous("integer literal is too large"))
+        };
+
+        let (pos, text, value) = match minus {
+            Some(minus) => (minus.pos, format!("-{}", text), -magnitude),
+            None => (token.pos.clone(), text.to_string(), magnitude)
+        };
+
+        match i32::try_from(value) {
+            Ok(num) => Ok(Expr {
+                pos,
+                kind: ExprKind::IntLit(num, Some(Rc::from(text)))
+            }),
+
+            Err(_) => {
+                Err(self.error_at_previous("integer literal is too large"))
+            }
+        }
+    }
+
+    // Strips the quotes of a character or string literal and decodes its escapes.
+    // An unknown escape is reported where it is, a string may span lines.
+    fn decode_literal(&mut self, lit: &Token) -> Result<String, ParserError> {
+        let prefix_len = if lit.kind == TokenType::ByteStringLiteral { 2 } else { 1 };
+        let text = lit.text();
+        let body = &text[prefix_len..text.len() - 1];
+
+        decode_escapes(body).map_err(|(index, msg)| {
+            self.had_error = true;
+
+            let line = lit.pos.line + body[..index].matches('\n').count();
+            let offset = lit.pos.offset + prefix_len + index;
+            ParserError { pos: FilePosition { line, offset, ..lit.pos.clone() }, msg, note: None }
+        })
+    }
+
+    // Postfix operators chain left to right, `a[i][j]` indexes the result of `a[i]`.
     fn continue_parse_postfix(&mut self, expr: Expr) -> Result<Expr, ParserError> {
-        let mut res = expr;
-
-        while self.matching(TokenType::PlusPlus) || self.matching(TokenType::MinusMinus) ||
-            self.matching(TokenType::LeftBrace) {
-                if self.previous_token.kind == TokenType::LeftBrace {
-                    let token = self.previous_token.clone();
-
-                    let index = self.parse_expr()?;
-                    self.require(TokenType::RightBrace, "expected ']' after index")?;
-
-                    res = Expr {
-                        pos: token.pos.clone(),
-                        kind: ExprKind::UnaryOp(TokenType::Star, false, Box::new(Expr {
-                            pos: token.pos.clone(),
-                            kind: ExprKind::BinOp(Box::new(res), TokenType::Plus, Box::new(Expr {
-                                pos: token.pos.clone(),
-                                kind: ExprKind::BinOp(Box::new(index), TokenType::Star, Box::new(Expr {
-                                    pos: token.pos,
-                                    kind: ExprKind::IntLit(4)
-                                }))
-                            }))
-                        }))
-                    };
-                } else {
-                    let token = self.previous_token.clone();
-                    res = Expr {
-                        pos: token.pos,
-                        kind: ExprKind::UnaryOp(token.kind, true, Box::new(res))
-                    };
+        trace!(self, "continue_parse_postfix");
+
+        self.chain(|this| {
+            let mut res = expr;
+
+            while this.matching(TokenType::PlusPlus) || this.matching(TokenType::MinusMinus) ||
+                this.matching(TokenType::LeftBrace) || this.matching(TokenType::LeftParen) {
+                    this.link()?;
+
+                    if this.previous_token.kind == TokenType::LeftParen {
+                        let token = this.previous_token.clone();
+                        let args = this.parse_arguments()?;
+
+                        res = Expr {
+                            pos: token.pos,
+                            kind: ExprKind::Call(Box::new(res), args)
+                        };
+                    } else if this.previous_token.kind == TokenType::LeftBrace {
+                        let token = this.previous_token.clone();
+
+                        let index = this.nested(Self::parse_expr)?;
+                        this.require_closing(&token, TokenType::RightBrace, "expected ']' after index")?;
+
+                        res = Expr {
+                            pos: token.pos,
+                            kind: ExprKind::Index(Box::new(res), Box::new(index))
+                        };
+                    } else {
+                        let token = this.previous_token.clone();
+                        res = Expr {
+                            pos: token.pos,
+                            kind: ExprKind::UnaryOp(token.kind, Fixity::Postfix, Box::new(res))
+                        };
+                    }
                 }
+
+            Ok(res)
+        })
+    }
+
+    fn parse_arguments(&mut self) -> Result<Vec<Expr>, ParserError> {
+        trace!(self, "parse_arguments");
+
+        let open = self.previous_token.clone();
+        let mut res = Vec::new();
+
+        if !self.check(TokenType::RightParen) {
+            res.push(self.nested(Self::parse_assignment)?);
+
+            while self.matching(TokenType::Comma) {
+                res.push(self.nested(Self::parse_assignment)?);
             }
+        }
+
+        self.require_closing(&open, TokenType::RightParen, "expected ')' after function arguments")?;
 
         Ok(res)
     }
 
     fn continue_parse_fn(&mut self, name: Token) -> Result<Decl, ParserError> {
+        trace!(self, "continue_parse_fn");
+
         let params = self.parse_parameters()?;
 
-        self.require(TokenType::LeftBracket, "expected '{' before function body")?;
-        let body = self.parse_block_stmt()?;
+        let body = if self.dialect.braceless_functions {
+            self.nested(Self::parse_stmt)?
+        } else {
+            self.require(TokenType::LeftBracket, "expected '{' before function body")?;
+            self.parse_block_stmt()?
+        };
 
         Ok(Decl {
-            pos: name.pos,
+            pos: name.pos.clone(),
             kind: DeclKind::Function {
-                name: name.data,
+                name: name.text().into_owned(),
                 params,
                 body
             }
@@ -351,33 +706,40 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_parameters(&mut self) -> Result<Vec<String>, ParserError> {
-        self.require(TokenType::LeftParen, "expected '(' before function parameters")?;
+        trace!(self, "parse_parameters");
 
+        let open = self.previous_token.clone();
         let mut res = Vec::new();
         
-        if !self.matching(TokenType::RightParen) {
-            res.push(self.previous_token.data.clone());
+        if !self.check(TokenType::RightParen) {
+            res.push(self.require(TokenType::Identifier, "expected parameter name")?.text().into_owned());
 
             while self.matching(TokenType::Comma) {
-                res.push(self.previous_token.data.clone());
+                res.push(self.require(TokenType::Identifier, "expected parameter name")?.text().into_owned());
             }
         }
 
-        self.require(TokenType::RightParen, "expected ')' after function parameters")?;
+        self.require_closing(&open, TokenType::RightParen, "expected ')' after function parameters")?;
 
         Ok(res)
     }
 
     fn parse_block_stmt(&mut self) -> Result<Stmt, ParserError> {
+        trace!(self, "parse_block_stmt");
+
         let left_bracket = self.previous_token.clone();
         
         let mut res = Vec::new();
 
         while !self.is_at_end() && !self.matching(TokenType::RightBracket) {
-            match self.parse_stmt() {
+            match self.nested(Self::parse_stmt) {
                 Ok(stmt) => res.push(stmt),
+
+                // A speculative parse fails at the first error instead of recovering.
+                Err(e) if self.speculation > 0 => return Err(e),
+
                 Err(e) => {
-                    report_error(&e.pos, &e.msg);
+                    e.report();
                     self.synchronize_stmt()
                 } 
             }
@@ -385,7 +747,10 @@ impl<'a> Parser<'a> {
 
         if self.previous_token.kind != TokenType::RightBracket {
             // TODO: At current or at previous?
-            Err(self.error_at_current("expected '}'"))
+            Err(ParserError {
+                note: Some(Box::new((left_bracket.pos, String::from("unclosed '{' opened here")))),
+                ..self.error_at_current("expected '}'")
+            })
         } else {
             Ok(Stmt {
                 pos: left_bracket.pos,
@@ -413,7 +778,10 @@ impl<'a> Parser<'a> {
         }
     }
 
+    // The value is a full expression, `return a, b;` evaluates `a` and returns `b`.
     fn parse_return_stmt(&mut self) -> Result<Stmt, ParserError> {
+        trace!(self, "parse_return_stmt");
+
         let return_keyword = self.previous_token.clone();
         let expr = if self.matching(TokenType::Semicolon) {
             None
@@ -430,6 +798,8 @@ impl<'a> Parser<'a> {
     }
     
     fn parse_expr_stmt(&mut self) -> Result<Stmt, ParserError> {
+        trace!(self, "parse_expr_stmt");
+
         let expr = self.parse_expr()?;
         self.require(TokenType::Semicolon, "expected ';' after expression statement")?;
 
@@ -440,15 +810,17 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_if_stmt(&mut self) -> Result<Stmt, ParserError> {
+        trace!(self, "parse_if_stmt");
+
         let if_keyword = self.previous_token.clone();
 
-        self.require(TokenType::LeftParen, "expected '(' before if condition")?;
+        let open = self.require(TokenType::LeftParen, "expected '(' before if condition")?;
         let condition = self.parse_expr()?;
-        self.require(TokenType::RightParen, "expected ')' after if condition")?;
+        self.require_closing(&open, TokenType::RightParen, "expected ')' after if condition")?;
 
-        let then_arm = self.parse_stmt()?;
+        let then_arm = self.nested(Self::parse_stmt)?;
         let else_arm = if self.matching(TokenType::KeywordElse) {
-            Some(Box::new(self.parse_stmt()?))
+            Some(Box::new(self.nested(Self::parse_stmt)?))
         } else {
             None
         };
@@ -460,14 +832,16 @@ impl<'a> Parser<'a> {
     }
     
     fn parse_while_stmt(&mut self) -> Result<Stmt, ParserError> {
+        trace!(self, "parse_while_stmt");
+
         let while_token = self.previous_token.clone();
 
-        self.require(TokenType::LeftParen, "expected '(' before while loop condition")?;
+        let open = self.require(TokenType::LeftParen, "expected '(' before while loop condition")?;
         let condition = self.parse_expr()?;
-        self.require(TokenType::RightParen, "expected ')' after while loop condition")?;
+        self.require_closing(&open, TokenType::RightParen, "expected ')' after while loop condition")?;
 
         let body = if !self.matching(TokenType::Semicolon) {
-            Some(Box::new(self.parse_stmt()?))
+            Some(Box::new(self.nested(Self::parse_stmt)?))
         } else {
             None
         };
@@ -479,13 +853,16 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_do_stmt(&mut self) -> Result<Stmt, ParserError> {
+        trace!(self, "parse_do_stmt");
+
         let do_token = self.previous_token.clone();
 
-        let body = self.parse_stmt()?;
+        let body = self.nested(Self::parse_stmt)?;
 
-        self.require(TokenType::LeftParen, "expected '(' before do loop condition")?;
+        self.require(TokenType::KeywordWhile, "expected 'while' after do loop body")?;
+        let open = self.require(TokenType::LeftParen, "expected '(' before do loop condition")?;
         let condition = self.parse_expr()?;
-        self.require(TokenType::RightParen, "expected ')' after do loop condition")?;
+        self.require_closing(&open, TokenType::RightParen, "expected ')' after do loop condition")?;
         self.require(TokenType::Semicolon, "expected ';' after do loop condition")?;
 
         Ok(Stmt {
@@ -494,25 +871,63 @@ impl<'a> Parser<'a> {
         })
     }
     
+    fn parse_asm_stmt(&mut self) -> Result<Stmt, ParserError> {
+        trace!(self, "parse_asm_stmt");
+
+        let token = self.previous_token.clone();
+        self.allow_extension(Extension::InlineAsm)?;
+
+        let open = self.require(TokenType::LeftParen, "expected '(' after 'asm'")?;
+        let lit = self.require(TokenType::StringLiteral, "expected string literal in 'asm'")?;
+        let text = self.decode_literal(&lit)?;
+        self.require_closing(&open, TokenType::RightParen, "expected ')' after assembly")?;
+        self.require_and_skip_semicolons("after asm statement")?;
+
+        Ok(Stmt {
+            pos: token.pos,
+            kind: StmtKind::Asm(text)
+        })
+    }
+
     fn parse_break_stmt(&mut self) -> Result<Stmt, ParserError> {
+        trace!(self, "parse_break_stmt");
+
         let token = self.previous_token.clone();
+        let levels = self.parse_loop_levels()?;
         self.require_and_skip_semicolons("after break statement")?;
 
         Ok(Stmt {
             pos: token.pos,
-            kind: StmtKind::Break
+            kind: StmtKind::Break(levels)
         })
     }
     
     fn parse_continue_stmt(&mut self) -> Result<Stmt, ParserError> {
+        trace!(self, "parse_continue_stmt");
+
         let token = self.previous_token.clone();
+        let levels = self.parse_loop_levels()?;
         self.require_and_skip_semicolons("after continue statement")?;
 
         Ok(Stmt {
             pos: token.pos,
-            kind: StmtKind::Continue
+            kind: StmtKind::Continue(levels)
         })
     }
+
+    // The optional number of loops after `break` or `continue`.
+    fn parse_loop_levels(&mut self) -> Result<usize, ParserError> {
+        if !self.matching(TokenType::IntLiteral) {
+            return Ok(1);
+        }
+
+        self.allow_extension(Extension::LoopLevels)?;
+
+        match self.previous_token.text().parse::<usize>() {
+            Ok(levels) if levels > 0 => Ok(levels),
+            _ => Err(self.error_at_previous("expected a positive number of loops"))
+        }
+    }
     
     fn matching(&mut self, kind: TokenType) -> bool {
         if self.check(kind) {
@@ -520,7 +935,7 @@ impl<'a> Parser<'a> {
             return true;
         }
 
-        return false;
+        false
     }
 
     fn require(&mut self, kind: TokenType, error_msg: &str) -> Result<Token, ParserError> {
@@ -531,6 +946,14 @@ impl<'a> Parser<'a> {
         }
     }
 
+    // Also points at the unclosed `open` token, which may be far away.
+    fn require_closing(&mut self, open: &Token, kind: TokenType, error_msg: &str) -> Result<Token, ParserError> {
+        self.require(kind, error_msg).map_err(|e| ParserError {
+            note: Some(Box::new((open.pos.clone(), format!("unclosed '{}' opened here", open.text())))),
+            ..e
+        })
+    }
+
     fn check(&self, kind: TokenType) -> bool {
         self.current_token.kind == kind
     }
@@ -547,12 +970,335 @@ impl<'a> Parser<'a> {
 
     fn advance(&mut self) {
         self.previous_token = self.current_token.clone();
-        // TODO: Scanner error.
-        self.current_token = self.scanner.next_token();
+        self.current_token = self.next_token();
+
+        while self.current_token.kind == TokenType::Error {
+            if self.speculation > 0 {
+                self.speculation_failed = true;
+                return;
+            }
+
+            report_error(&self.current_token.pos, &self.current_token.text());
+            self.had_error = true;
+            self.current_token = self.next_token();
+        }
+    }
+
+    fn next_token(&mut self) -> Token {
+        let token = self.replay.pop().unwrap_or_else(|| self.preprocessor.next_token());
+
+        if self.speculation > 0 {
+            self.recorded.push(token.clone());
+        }
+
+        token
+    }
+
+    // Runs `parse` and keeps its result if it succeeds. Otherwise the parser is
+    // put back where it was, as if nothing was read, and no errors are reported.
+    //
+    // Tokens are rewound here rather than in the scanner, the preprocessor
+    // between them has its own state.
+    fn try_parse<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, ParserError>) -> Option<T> {
+        let previous_token = self.previous_token.clone();
+        let current_token = self.current_token.clone();
+        let had_error = self.had_error;
+        let failed = std::mem::take(&mut self.speculation_failed);
+        let start = self.recorded.len();
+
+        self.speculation += 1;
+        let res = parse(self);
+        self.speculation -= 1;
+
+        let res = match res {
+            Ok(value) if !self.speculation_failed => Some(value),
+
+            _ => {
+                let tokens = self.recorded.split_off(start);
+                self.replay.extend(tokens.into_iter().rev());
+
+                self.previous_token = previous_token;
+                self.current_token = current_token;
+                self.had_error = had_error;
+                None
+            }
+        };
+
+        self.speculation_failed = failed;
+
+        if self.speculation == 0 {
+            self.recorded.clear();
+        }
+
+        res
+    }
+
+    // Whether the tokens ahead parse with `parse`, nothing is read either way.
+    fn lookahead(&mut self, parse: fn(&mut Self) -> Result<(), ParserError>) -> bool {
+        let mut matched = false;
+
+        self.try_parse(|this| {
+            matched = parse(this).is_ok();
+
+            // Failing always gives the tokens back.
+            Err::<(), _>(ParserError { pos: this.current_token.pos.clone(), msg: String::new(), note: None })
+        });
+
+        matched
+    }
+
+    // Every recursion of the parser goes through here.
+    fn nested<T>(&mut self, parse: fn(&mut Self) -> Result<T, ParserError>) -> Result<T, ParserError> {
+        if self.depth >= self.max_nesting {
+            return Err(self.error_at_current("nesting is too deep"));
+        }
+
+        self.depth += 1;
+        let res = parse(self);
+        self.depth -= 1;
+        res
+    }
+
+    // Chains like `a + b + c` or `f(x)[i]` are parsed in a loop but nest in
+    // the tree just as deep, so every link counts as a level too. The levels
+    // are given back when the chain ends, even on an error.
+    fn chain<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, ParserError>) -> Result<T, ParserError> {
+        let depth = self.depth;
+        let res = parse(self);
+        self.depth = depth;
+        res
+    }
+
+    fn link(&mut self) -> Result<(), ParserError> {
+        if self.depth >= self.max_nesting {
+            return Err(self.error_at_previous("nesting is too deep"));
+        }
+
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn allow_operator(&mut self, op: TokenType) -> Result<(), ParserError> {
+        match op {
+            TokenType::GreaterGreaterGreater => self.allow_extension(Extension::LogicalShift),
+            _ => Ok(())
+        }
+    }
+
+    // Checked right after the token that uses the extension.
+    fn allow_extension(&mut self, extension: Extension) -> Result<(), ParserError> {
+        if self.dialect.pedantic {
+            let msg = format!("feature not allowed in pedantic mode: {}", extension.name());
+            return Err(self.error_at_previous(&msg));
+        }
+
+        Ok(())
+    }
+
+    // Reports an error the parser goes on after. A speculative parse fails
+    // instead, the tokens may be read differently after rewinding.
+    fn report_recovered(&mut self, error: ParserError) {
+        if self.speculation > 0 {
+            self.speculation_failed = true;
+        } else {
+            error.report();
+        }
     }
 
     fn error_at_current(&mut self, msg: &str) -> ParserError {
         self.had_error = true;
-        ParserError { pos: self.current_token.pos.clone(), msg: String::from(msg) }
+        ParserError { pos: self.current_token.pos.clone(), msg: String::from(msg), note: None }
+    }
+
+    fn error_at_previous(&mut self, msg: &str) -> ParserError {
+        self.had_error = true;
+        ParserError { pos: self.previous_token.pos.clone(), msg: String::from(msg), note: None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::Scanner;
+
+    // Parses `source` as the body of a function.
+    fn parse_body(source: &str) -> Vec<Stmt> {
+        let file = Rc::new(File {
+            path: String::from("test.b"),
+            data: format!("f() {{ {} }}", source).into_bytes()
+        });
+
+        let mut preprocessor = Preprocessor::new(Scanner::new(file), Vec::new());
+        let mut parser = Parser::new(&mut preprocessor, Dialect::default());
+
+        let Some(Decl { kind: DeclKind::Function { body, .. }, .. }) = parser.parse_one_decl() else {
+            panic!("'{}' does not parse", source);
+        };
+
+        match body.kind {
+            StmtKind::Block(stmts) => stmts,
+            _ => panic!("expected a block")
+        }
+    }
+
+    // Parses `source` as the value of a return statement.
+    fn parse_expr(source: &str) -> Expr {
+        match parse_body(&format!("return {};", source)).pop() {
+            Some(Stmt { kind: StmtKind::Return(Some(expr)), .. }) => expr,
+            _ => panic!("expected a return statement")
+        }
+    }
+
+    fn is_var(expr: &Expr, expected: &str) -> bool {
+        matches!(&expr.kind, ExprKind::Var(name) if name == expected)
+    }
+
+    #[test]
+    fn ternary_in_else_arm_is_right_associative() {
+        let ExprKind::Ternary(cond, then_arm, else_arm) = parse_expr("a ? b : c ? d : e").kind else {
+            panic!("expected a ternary");
+        };
+
+        assert!(is_var(&cond, "a") && is_var(&then_arm, "b"));
+        assert!(matches!(else_arm.kind, ExprKind::Ternary(..)));
+    }
+
+    #[test]
+    fn ternary_in_then_arm_needs_no_parentheses() {
+        let ExprKind::Ternary(cond, then_arm, else_arm) = parse_expr("a ? b ? c : d : e").kind else {
+            panic!("expected a ternary");
+        };
+
+        assert!(is_var(&cond, "a") && is_var(&else_arm, "e"));
+        assert!(matches!(then_arm.kind, ExprKind::Ternary(..)));
+    }
+
+    #[test]
+    fn call_takes_argument_list() {
+        let ExprKind::Call(callee, args) = parse_expr("f(1, g(), x = 2)").kind else {
+            panic!("expected a call");
+        };
+
+        assert!(is_var(&callee, "f"));
+        assert_eq!(args.len(), 3);
+        assert!(matches!(args[1].kind, ExprKind::Call(_, ref args) if args.is_empty()));
+    }
+
+    #[test]
+    fn do_while_takes_empty_body() {
+        let stmts = parse_body("do ; while (c); ;");
+
+        assert_eq!(stmts.len(), 2);
+        assert!(matches!(&stmts[0].kind, StmtKind::DoWhile(_, body) if matches!(body.kind, StmtKind::Empty)));
+        assert!(matches!(stmts[1].kind, StmtKind::Empty));
+    }
+
+    #[test]
+    fn increment_records_its_fixity() {
+        let ExprKind::BinOp(left, _, right) = parse_expr("x++ + ++y").kind else {
+            panic!("expected a binary operation");
+        };
+
+        assert!(matches!(&left.kind, ExprKind::UnaryOp(TokenType::PlusPlus, Fixity::Postfix, x) if is_var(x, "x")));
+        assert!(matches!(&right.kind, ExprKind::UnaryOp(TokenType::PlusPlus, Fixity::Prefix, y) if is_var(y, "y")));
+    }
+
+    #[test]
+    fn chained_index_applies_to_previous_result() {
+        let ExprKind::Index(inner, j) = parse_expr("a[i][j]").kind else {
+            panic!("expected an index");
+        };
+
+        assert!(is_var(&j, "j"));
+        assert!(matches!(&inner.kind, ExprKind::Index(a, i) if is_var(a, "a") && is_var(i, "i")));
+    }
+
+    #[test]
+    fn return_takes_comma_expression() {
+        let ExprKind::BinOp(left, TokenType::Comma, right) = parse_expr("a = 1, b").kind else {
+            panic!("expected a comma expression");
+        };
+
+        assert!(matches!(left.kind, ExprKind::Assign(..)));
+        assert!(is_var(&right, "b"));
+    }
+
+    #[test]
+    fn failed_declaration_always_makes_progress() {
+        for source in [";", ")", "123 ;", "}", "x(", "\"text\" y 1;"] {
+            let file = Rc::new(File {
+                path: String::from("test.b"),
+                data: source.as_bytes().to_vec()
+            });
+
+            let mut preprocessor = Preprocessor::new(Scanner::new(file), Vec::new());
+            let mut parser = Parser::new(&mut preprocessor, Dialect::default());
+            let mut decls = 0;
+
+            while !parser.is_at_end() {
+                let offset = parser.current_offset();
+
+                if parser.parse_one_decl().is_some() {
+                    decls += 1;
+                }
+
+                assert!(parser.is_at_end() || parser.current_offset() > offset, "stuck in '{}'", source);
+            }
+
+            assert!(decls <= 1, "{}", source);
+        }
+    }
+    // The names and initializer counts of the variables of an `auto`.
+    fn auto_vars(source: &str) -> Vec<(String, usize)> {
+        match parse_body(source).pop() {
+            Some(Stmt { kind: StmtKind::Auto(vars), .. }) => vars.into_iter().map(|var| (var.name, var.initial.len())).collect(),
+            _ => panic!("expected an auto statement")
+        }
+    }
+
+    fn names(vars: &[(&str, usize)]) -> Vec<(String, usize)> {
+        vars.iter().map(|(name, count)| (name.to_string(), *count)).collect()
+    }
+
+    #[test]
+    fn auto_declares_a_list_of_variables() {
+        assert_eq!(auto_vars("auto a, b, c;"), names(&[("a", 0), ("b", 0), ("c", 0)]));
+        assert_eq!(auto_vars("auto a 1, v[2], b 2;"), names(&[("a", 1), ("v", 0), ("b", 1)]));
+        assert_eq!(auto_vars("auto v[] 1, -2, 3,;"), names(&[("v", 3)]));
+    }
+
+    #[test]
+    fn name_after_vector_initializers_starts_next_auto() {
+        assert_eq!(auto_vars("auto s[] \"hi\", n;"), names(&[("s", 1), ("n", 0)]));
+        assert_eq!(auto_vars("auto v[2] 1, 2, w[1] 3, x;"), names(&[("v", 2), ("w", 1), ("x", 0)]));
+    }
+
+    #[test]
+    fn name_in_an_expression_is_an_initializer() {
+        assert_eq!(auto_vars("auto v[] 1, x + 1, f(2), 3;"), names(&[("v", 4)]));
+        assert_eq!(auto_vars("auto v[] 1, x, y[2];"), names(&[("v", 1), ("x", 0), ("y", 0)]));
+    }
+
+    fn spelling(expr: &Expr) -> Option<&str> {
+        match &expr.kind {
+            ExprKind::IntLit(_, text) => text.as_deref(),
+            _ => panic!("expected an integer literal")
+        }
+    }
+
+    #[test]
+    fn integer_literal_keeps_its_spelling() {
+        assert_eq!(spelling(&parse_expr("007")), Some("007"));
+        assert_eq!(spelling(&parse_expr("-12")), Some("-12"));
+        assert_eq!(spelling(&parse_expr("'a'")), Some("'a'"));
+        assert_eq!(spelling(&parse_expr("'\\n'")), Some("'\\n'"));
+        assert!(matches!(parse_expr("'\\n'").kind, ExprKind::IntLit(10, _)));
+    }
+
+    #[test]
+    fn integer_literal_radix_follows_its_prefix() {
+        for (source, value) in [("010", 8), ("0", 0), ("00", 0), ("0x1F", 31), ("0XfF", 255), ("-017", -15), ("10", 10)] {
+            assert!(matches!(parse_expr(source).kind, ExprKind::IntLit(v, _) if v == value), "{}", source);
+        }
     }
 }
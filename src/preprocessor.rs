@@ -0,0 +1,423 @@
+use crate::{file::*, scanner::Scanner, token::*};
+use std::{path::{Path, PathBuf}, rc::Rc, time::{Duration, Instant}};
+
+// Includes nested deeper than this are an error rather than exhausting memory.
+pub const DEFAULT_MAX_INCLUDE_DEPTH: usize = 50;
+
+pub struct Preprocessor {
+    scanners: Vec<Scanner>,
+    include_dirs: Vec<String>,
+    max_include_depth: usize,
+    macros: Vec<(String, Vec<Token>)>,
+    conditionals: Vec<Conditional>,
+    // Canonical paths of the files marked with `#pragma once`.
+    once_files: Vec<PathBuf>,
+    // Paths of the included files in the order they were first read.
+    included: Vec<String>,
+    // Expanded macro tokens, in reverse order.
+    pending: Vec<Token>,
+    // Time spent in the scanners, `None` when it is not measured.
+    scan_time: Option<Duration>
+}
+
+struct Conditional {
+    pos: FilePosition,
+    // Number of open files when the conditional started, it must end in the same file.
+    depth: usize,
+    taken: bool,
+    parent_active: bool,
+    had_else: bool
+}
+
+impl Preprocessor {
+    pub fn new(scanner: Scanner, include_dirs: Vec<String>) -> Preprocessor {
+        Preprocessor {
+            scanners: vec![scanner],
+            include_dirs,
+            max_include_depth: DEFAULT_MAX_INCLUDE_DEPTH,
+            macros: Vec::new(),
+            conditionals: Vec::new(),
+            once_files: Vec::new(),
+            included: Vec::new(),
+            pending: Vec::new(),
+            scan_time: None
+        }
+    }
+
+    pub fn next_token(&mut self) -> Token {
+        loop {
+            if let Some(token) = self.pending.pop() {
+                return token;
+            }
+
+            let start = self.scan_time.is_some().then(Instant::now);
+            let token = self.current_scanner().next_token();
+
+            if let (Some(start), Some(time)) = (start, &mut self.scan_time) {
+                *time += start.elapsed();
+            }
+
+            match token.kind {
+                TokenType::Directive => {
+                    if let Some(error) = self.directive(&token) {
+                        return error;
+                    }
+                },
+
+                TokenType::EndOfFile => {
+                    if let Some(error) = self.close_conditionals() {
+                        return error;
+                    }
+
+                    if self.scanners.len() == 1 {
+                        return token;
+                    }
+
+                    self.scanners.pop();
+                },
+
+                _ if !self.is_active() => {},
+
+                TokenType::Identifier if self.is_defined(&token.text()) => {
+                    let mut expansion = Vec::new();
+                    self.expand(&token, &mut Vec::new(), &mut expansion);
+
+                    expansion.reverse();
+                    self.pending = expansion;
+                },
+
+                _ => return token
+            }
+        }
+    }
+
+    pub fn included_files(&self) -> &[String] {
+        &self.included
+    }
+
+    pub fn enable_scan_timing(&mut self) {
+        self.scan_time = Some(Duration::ZERO);
+    }
+
+    pub fn scan_time(&self) -> Duration {
+        self.scan_time.unwrap_or_default()
+    }
+
+    pub fn set_max_include_depth(&mut self, depth: usize) {
+        self.max_include_depth = depth;
+    }
+
+    // `path` is only used to report errors inside the macro body.
+    pub fn define(&mut self, name: &str, value: &str, path: &str) {
+        let file = Rc::new(File {
+            path: String::from(path),
+            data: value.as_bytes().to_vec()
+        });
+
+        let mut scanner = Scanner::new(file);
+        let mut body = Vec::new();
+
+        loop {
+            let token = scanner.next_token();
+
+            if token.kind == TokenType::EndOfFile {
+                break;
+            }
+
+            body.push(token);
+        }
+
+        self.undefine(name);
+        self.macros.push((String::from(name), body));
+    }
+
+    fn undefine(&mut self, name: &str) {
+        self.macros.retain(|(defined, _)| defined != name);
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.macros.iter().any(|(defined, _)| defined == name)
+    }
+
+    // Expanded tokens take the position of the macro use. A macro is not
+    // expanded again inside its own expansion.
+    fn expand(&self, token: &Token, active: &mut Vec<String>, out: &mut Vec<Token>) {
+        let body = match self.macros.iter().find(|(name, _)| *name == token.text()) {
+            Some((_, body)) => body,
+            None => return
+        };
+
+        active.push(token.text().into_owned());
+
+        for body_token in body {
+            let mut body_token = body_token.clone();
+            body_token.pos = token.pos.clone();
+
+            if body_token.kind == TokenType::Identifier && self.is_defined(&body_token.text()) &&
+                !active.iter().any(|name| *name == body_token.text()) {
+                self.expand(&body_token, active, out);
+            } else {
+                out.push(body_token);
+            }
+        }
+
+        active.pop();
+    }
+
+    fn is_active(&self) -> bool {
+        self.conditionals.last().is_none_or(|cond| cond.parent_active && cond.taken)
+    }
+
+    fn close_conditionals(&mut self) -> Option<Token> {
+        let depth = self.scanners.len();
+        let mut error = None;
+
+        while let Some(cond) = self.conditionals.last() {
+            if cond.depth != depth {
+                break;
+            }
+
+            error = Some(Token::error(cond.pos.clone(), "unterminated conditional directive"));
+
+            self.conditionals.pop();
+        }
+
+        error
+    }
+
+    fn current_scanner(&mut self) -> &mut Scanner {
+        self.scanners.last_mut().expect("preprocessor always has the main file scanner")
+    }
+
+    fn directive(&mut self, token: &Token) -> Option<Token> {
+        let text = token.text();
+        let (name, rest) = split_word(&text[1..]);
+
+        match name {
+            "ifdef" | "ifndef" => self.ifdef(token, rest, name == "ifdef"),
+            "else" => self.else_directive(token),
+            "endif" => self.endif(token),
+
+            // Everything else is ignored inside a skipped block.
+            _ if !self.is_active() => None,
+
+            "include" => self.include(token, rest),
+            "define" => self.define_directive(token, rest),
+            "pragma" => self.pragma(token, rest),
+
+            "undef" => match macro_name(rest) {
+                Some(name) => {
+                    self.undefine(name);
+                    None
+                },
+
+                None => Some(make_error(token, String::from("expected macro name after #undef")))
+            },
+
+            _ => Some(make_error(token, String::from("unknown preprocessor directive")))
+        }
+    }
+
+    fn define_directive(&mut self, token: &Token, arg: &str) -> Option<Token> {
+        let (name, value) = split_word(arg);
+
+        match macro_name(name) {
+            Some(name) => {
+                self.define(name, value, &token.pos.file.path);
+                None
+            },
+
+            None => Some(make_error(token, String::from("expected macro name after #define")))
+        }
+    }
+
+    fn pragma(&mut self, token: &Token, arg: &str) -> Option<Token> {
+        if arg != "once" {
+            return Some(make_error(token, format!("unknown pragma '{}'", arg)));
+        }
+
+        let path = canonical_path(&token.pos.file.path);
+
+        if !self.once_files.contains(&path) {
+            self.once_files.push(path);
+        }
+
+        None
+    }
+
+    fn ifdef(&mut self, token: &Token, arg: &str, expected: bool) -> Option<Token> {
+        let name = match macro_name(arg) {
+            Some(name) => name,
+            None => return Some(make_error(token, String::from("expected macro name after conditional directive")))
+        };
+
+        self.conditionals.push(Conditional {
+            pos: token.pos.clone(),
+            depth: self.scanners.len(),
+            taken: self.is_defined(name) == expected,
+            parent_active: self.is_active(),
+            had_else: false
+        });
+
+        None
+    }
+
+    fn else_directive(&mut self, token: &Token) -> Option<Token> {
+        match self.conditionals.last_mut() {
+            Some(cond) if !cond.had_else => {
+                cond.taken = !cond.taken;
+                cond.had_else = true;
+                None
+            },
+
+            Some(_) => Some(make_error(token, String::from("'#else' after '#else'"))),
+            None => Some(make_error(token, String::from("'#else' without '#ifdef'")))
+        }
+    }
+
+    fn endif(&mut self, token: &Token) -> Option<Token> {
+        match self.conditionals.last() {
+            Some(cond) if cond.depth == self.scanners.len() => {
+                self.conditionals.pop();
+                None
+            },
+
+            _ => Some(make_error(token, String::from("unbalanced '#endif'")))
+        }
+    }
+
+    fn include(&mut self, token: &Token, arg: &str) -> Option<Token> {
+        let (name, search_current) = if let Some(name) = strip_delimiters(arg, '"', '"') {
+            (name, true)
+        } else if let Some(name) = strip_delimiters(arg, '<', '>') {
+            (name, false)
+        } else {
+            return Some(make_error(token, String::from("expected \"FILE\" or <FILE> after #include")));
+        };
+
+        // The main file is not an include.
+        if self.scanners.len() > self.max_include_depth {
+            return Some(make_error(token, String::from("include nesting too deep")));
+        }
+
+        match self.resolve_include(&token.pos, name, search_current) {
+            Some(path) if self.once_files.contains(&canonical_path(&path)) => None,
+
+            Some(path) => match read_file(&path) {
+                Ok(file) => {
+                    if !self.included.contains(&path) {
+                        self.included.push(path);
+                    }
+
+                    self.scanners.push(Scanner::new(file));
+                    None
+                },
+
+                Err(e) => Some(make_error(token, format!("cannot read include '{}': {}", name, e)))
+            },
+
+            None => Some(make_error(token, format!("cannot find include '{}'", name)))
+        }
+    }
+
+    // Quoted includes are looked up next to the including file first, angle-bracket
+    // includes only in the include directories.
+    fn resolve_include(&self, pos: &FilePosition, name: &str, search_current: bool) -> Option<String> {
+        let current_dir = Path::new(&pos.file.path)
+            .parent()
+            .map(|dir| dir.to_path_buf())
+            .unwrap_or_default();
+
+        let current = if search_current { Some(current_dir.as_path()) } else { None };
+
+        current.into_iter()
+            .chain(self.include_dirs.iter().map(Path::new))
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+            .map(|path| path.to_string_lossy().into_owned())
+    }
+}
+
+// Splits off the first whitespace-separated word, the rest is trimmed.
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+
+    match text.find(|ch: char| ch.is_ascii_whitespace()) {
+        Some(end) => (&text[..end], text[end..].trim()),
+        None => (text, "")
+    }
+}
+
+// The same file can be reached through different relative paths.
+fn canonical_path(path: &str) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path))
+}
+
+pub fn macro_name(text: &str) -> Option<&str> {
+    let valid = text.bytes().enumerate().all(|(i, ch)| {
+        ch.is_ascii_alphabetic() || ch == b'_' || (i > 0 && ch.is_ascii_digit())
+    });
+
+    if valid && !text.is_empty() { Some(text) } else { None }
+}
+
+fn strip_delimiters(arg: &str, open: char, close: char) -> Option<&str> {
+    arg.strip_prefix(open)?.strip_suffix(close)
+}
+
+fn make_error(token: &Token, msg: String) -> Token {
+    Token::error(token.pos.clone(), &msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The text of every token up to the end of the file, errors included.
+    fn preprocess(source: &str) -> Vec<String> {
+        let file = Rc::new(File {
+            path: String::from("test.b"),
+            data: source.as_bytes().to_vec()
+        });
+
+        let mut preprocessor = Preprocessor::new(Scanner::new(file), Vec::new());
+        let mut tokens = Vec::new();
+
+        loop {
+            let token = preprocessor.next_token();
+
+            if token.kind == TokenType::EndOfFile {
+                return tokens;
+            }
+
+            tokens.push(token.text().into_owned());
+        }
+    }
+
+    #[test]
+    fn define_expands_nested_macros() {
+        let tokens = preprocess("#define ONE 1\n#define TWO ONE + ONE\nx TWO;\n#undef ONE\nONE;\n");
+
+        assert_eq!(tokens, ["x", "1", "+", "1", ";", "ONE", ";"]);
+    }
+
+    #[test]
+    fn macro_is_not_expanded_in_its_own_expansion() {
+        assert_eq!(preprocess("#define x x + 1\nx;\n"), ["x", "+", "1", ";"]);
+    }
+
+    #[test]
+    fn conditionals_select_one_branch() {
+        let source = "#define A\n#ifdef A\na;\n#ifndef A\nskipped;\n#else\nnested;\n#endif\n#else\nb;\n#endif\n";
+
+        assert_eq!(preprocess(source), ["a", ";", "nested", ";"]);
+    }
+
+    #[test]
+    fn unbalanced_conditionals_are_errors() {
+        assert_eq!(preprocess("#endif\n"), ["unbalanced '#endif'"]);
+        assert_eq!(preprocess("#else\n"), ["'#else' without '#ifdef'"]);
+        assert_eq!(preprocess("#ifdef A\n#else\n#else\n#endif\n"), ["'#else' after '#else'"]);
+        assert_eq!(preprocess("#ifndef A\nx;\n"), ["x", ";", "unterminated conditional directive"]);
+    }
+}
@@ -0,0 +1,59 @@
+// Wall-clock time of every phase of a compilation, for --profile. The phases
+// run declaration by declaration, so each time is summed over all of them.
+
+use std::{fmt, time::{Duration, Instant}};
+
+#[derive(Default)]
+pub struct Profile {
+    pub scanning: Duration,
+    // Without the scanning the parser asked for.
+    pub parsing: Duration,
+    pub validation: Duration,
+    pub optimization: Duration,
+    pub codegen: Duration
+}
+
+// Runs `f` and adds the time it took to `phase`.
+pub fn time<T>(phase: &mut Duration, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let res = f();
+    *phase += start.elapsed();
+    res
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let phases = [
+            ("scanning", self.scanning),
+            ("parsing", self.parsing),
+            ("validation", self.validation),
+            ("optimization", self.optimization),
+            ("codegen", self.codegen)
+        ];
+
+        for (name, duration) in phases {
+            writeln!(f, "{:<14}{:>10.3} ms", name, duration.as_secs_f64() * 1000.0)?;
+        }
+
+        let total: Duration = phases.iter().map(|(_, duration)| *duration).sum();
+        writeln!(f, "{:<14}{:>10.3} ms", "total", total.as_secs_f64() * 1000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn total_sums_the_phases() {
+        let profile = Profile {
+            scanning: Duration::from_micros(1500),
+            codegen: Duration::from_millis(2),
+            ..Profile::default()
+        };
+
+        let text = profile.to_string();
+        assert!(text.starts_with("scanning           1.500 ms\n"), "{}", text);
+        assert!(text.ends_with("total              3.500 ms\n"), "{}", text);
+    }
+}
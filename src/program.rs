@@ -0,0 +1,126 @@
+// The compiler's output, independent of the target assembly syntax.
+//
+// Functions are code for a stack machine: every instruction pops its operands
+// from the stack and pushes its result.
+
+use crate::file::FilePosition;
+
+pub struct Program {
+    pub word_size: usize,
+    pub string_encoding: StringEncoding,
+    // Both in source order, the backends emit them in this order so the output
+    // is the same on every run. Hash maps are only used for lookups.
+    pub data: Vec<Data>,
+    pub functions: Vec<Function>
+}
+
+pub struct Data {
+    pub label: String,
+    pub global: bool,
+    pub values: Vec<DataValue>
+}
+
+// How byte data is written out. Either way every byte of the UTF-8 source is
+// emitted as is, nothing is re-encoded.
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum StringEncoding {
+    // A list of numbers.
+    #[default]
+    Bytes,
+    // A quoted string, bytes outside printable ASCII are octal escapes.
+    Escaped
+}
+
+#[derive(Clone)]
+pub enum DataValue {
+    Word(i32),
+    Address(String),
+    Bytes(Vec<u8>)
+}
+
+pub struct Function {
+    pub name: String,
+    // Bytes of local storage below the frame pointer.
+    pub frame_size: usize,
+    // Parameters passed in registers, the prologue stores them into the
+    // lowest locals in order. Only the x86-64 entry function has any.
+    pub register_params: usize,
+    pub code: Vec<Instruction>
+}
+
+pub enum Instruction {
+    // Marks the start of the code generated for a source line, emits nothing.
+    Line(FilePosition),
+
+    Push(i32),
+    PushAddress(String),
+    // Address of a local or parameter, relative to the frame pointer.
+    PushLocalAddress(i32),
+    Dup,
+    Pop,
+
+    // Pops an address, pushes the word stored there.
+    Load,
+    // Pops a value and an address, stores the value, pushes it back.
+    Store,
+    // Like `Load` and `Store`, for a single byte. The value is zero-extended.
+    LoadByte,
+    StoreByte,
+
+    Unary(UnaryOp),
+    Binary(BinaryOp),
+
+    Label(usize),
+    Jump(usize),
+    JumpIfZero(usize),
+    JumpIfNotZero(usize),
+
+    // Arguments are pushed from last to first, the callee is popped and then
+    // the arguments, the result is pushed.
+    Call(usize),
+    CallDirect(String, usize),
+    Return,
+
+    // Inline assembly, emitted as is.
+    Asm(String)
+}
+
+#[derive(Clone, Copy)]
+pub enum UnaryOp {
+    Negate,
+    Complement,
+    Not
+}
+
+#[derive(Clone, Copy)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    ShiftLeft,
+    // Arithmetic, keeps the sign.
+    ShiftRight,
+    ShiftRightLogical,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual
+}
+
+impl Program {
+    pub fn new(word_size: usize) -> Self {
+        Program {
+            word_size,
+            string_encoding: StringEncoding::default(),
+            data: Vec::new(),
+            functions: Vec::new()
+        }
+    }
+}
@@ -8,6 +8,9 @@ pub struct Scanner {
     current: usize
 }
 
+// The kind of a scanned token, or the message of an error token.
+type Scan = Result<TokenType, &'static str>;
+
 fn is_alpha(ch: u8) -> bool {
     u8_in_range(ch, b'a', b'z') || u8_in_range(ch, b'A', b'Z')
 }
@@ -23,197 +26,235 @@ fn u8_in_range(ch: u8, first: u8, last: u8) -> bool {
 // TODO: Use chars. Use iterators.
 impl Scanner {
     pub fn new(file: Rc<File>) -> Scanner {
+        let start = file.content_start();
+        Scanner::starting_at(file, start, 1)
+    }
+
+    // Scans from the middle of a file, `line` is the line of `offset`.
+    pub fn starting_at(file: Rc<File>, offset: usize, line: usize) -> Scanner {
         Scanner {
             file,
-            line: 1,
-            start: 0,
-            current: 0
+            line,
+            start: offset,
+            current: offset
         }
     }
 
     pub fn next_token(&mut self) -> Token {
+        match self.scan() {
+            Ok(TokenType::EndOfFile) => self.make_eof_token(),
+            Ok(kind) => self.make_token(kind),
+            Err(msg) => self.make_error_token(msg)
+        }
+    }
+
+    // Counts the tokens up to the end of the file, errors included, without
+    // building them. For measuring the scanner alone.
+    pub fn count_tokens(&mut self) -> usize {
+        let mut count = 0;
+
+        while self.scan() != Ok(TokenType::EndOfFile) {
+            count += 1;
+        }
+
+        count
+    }
+
+    // Finds the next token, leaving it between `start` and `current`.
+    fn scan(&mut self) -> Scan {
         self.skip_whitespace();
 
         self.start = self.current;
 
         match self.advance() {
-            Some(ch) => match *ch {
-                b'(' => self.make_token(TokenType::LeftParen),
-                b')' => self.make_token(TokenType::RightParen),
-                b'{' => self.make_token(TokenType::LeftBracket),
-                b'}' => self.make_token(TokenType::RightBracket),
-                b'[' => self.make_token(TokenType::LeftBrace),
-                b']' => self.make_token(TokenType::RightBrace),
-                b';' => self.make_token(TokenType::Semicolon),
-                b':' => self.make_token(TokenType::Colon),
-                b',' => self.make_token(TokenType::Comma),
-                b'?' => self.make_token(TokenType::QuestionMark),
+            Some(&ch) => match ch {
+                b'(' => Ok(TokenType::LeftParen),
+                b')' => Ok(TokenType::RightParen),
+                b'{' => Ok(TokenType::LeftBracket),
+                b'}' => Ok(TokenType::RightBracket),
+                b'[' => Ok(TokenType::LeftBrace),
+                b']' => Ok(TokenType::RightBrace),
+                b';' => Ok(TokenType::Semicolon),
+                b':' => Ok(TokenType::Colon),
+                b',' => Ok(TokenType::Comma),
+                b'?' => Ok(TokenType::QuestionMark),
 
                 b'+' => if self.matching(b'+') {
-                    self.make_token(TokenType::PlusPlus)
+                    Ok(TokenType::PlusPlus)
                 } else {
-                    self.make_token(TokenType::Plus)
+                    Ok(TokenType::Plus)
                 },
 
-                b'*' => self.make_token(TokenType::Star),
-                b'/' => self.make_token(TokenType::Slash),
-                b'%' => self.make_token(TokenType::Percent),
-                b'~' => self.make_token(TokenType::Tilda),
+                b'*' => Ok(TokenType::Star),
+                b'/' => Ok(TokenType::Slash),
+                b'%' => Ok(TokenType::Percent),
+                b'~' => Ok(TokenType::Tilda),
 
+                // The sign of a negative number is left to the parser, `x-5`
+                // is a subtraction.
                 b'-' => if self.matching(b'-') {
-                    self.make_token(TokenType::MinusMinus)
-                } else if is_digit(self.peek().map_or(b'_', |ch| *ch)) {
-                    self.number()
+                    Ok(TokenType::MinusMinus)
                 } else {
-                    self.make_token(TokenType::Minus)
+                    Ok(TokenType::Minus)
                 },
 
                 b'!' => if self.matching(b'=') {
-                    self.make_token(TokenType::BangEqual)
+                    Ok(TokenType::BangEqual)
                 } else {
-                    self.make_token(TokenType::Bang)
+                    Ok(TokenType::Bang)
                 }
 
                 b'=' => if self.matching(b'=') {
-                    self.make_token(TokenType::EqualEqual)
+                    Ok(TokenType::EqualEqual)
                 } else {
-                    self.make_token(TokenType::Equal)
+                    Ok(TokenType::Equal)
                 }
 
                 b'>' => if self.matching(b'=') {
-                    self.make_token(TokenType::GreaterEqual)
+                    Ok(TokenType::GreaterEqual)
                 } else {
                     if self.matching(b'>') {
-                        self.make_token(TokenType::GreaterGreater)
+                        if self.matching(b'>') {
+                            Ok(TokenType::GreaterGreaterGreater)
+                        } else {
+                            Ok(TokenType::GreaterGreater)
+                        }
                     } else {
-                        self.make_token(TokenType::Greater)
+                        Ok(TokenType::Greater)
                     }
                 }
 
                 b'<' => if self.matching(b'=') {
-                    self.make_token(TokenType::LessEqual)
+                    Ok(TokenType::LessEqual)
                 } else {
                     if self.matching(b'<') {
-                        self.make_token(TokenType::LessLess)
+                        Ok(TokenType::LessLess)
                     } else {
-                        self.make_token(TokenType::Less)
+                        Ok(TokenType::Less)
                     }
                 }
 
                 b'|' => if self.matching(b'|') {
-                    self.make_token(TokenType::BarBar)
+                    Ok(TokenType::BarBar)
                 } else {
-                    self.make_token(TokenType::Bar)
+                    Ok(TokenType::Bar)
                 }
 
                 b'&' => if self.matching(b'&') {
-                    self.make_token(TokenType::AmpersandAmpersand)
+                    Ok(TokenType::AmpersandAmpersand)
                 } else {
-                    self.make_token(TokenType::Ampersand)
+                    Ok(TokenType::Ampersand)
                 }
 
-                b'^' => self.make_token(TokenType::UpArrow),
+                b'^' => Ok(TokenType::UpArrow),
 
                 b'\'' => self.character_literal(),
 
-                b'\"' => self.string(),
+                b'\"' => self.string(TokenType::StringLiteral),
+
+                b'b' if self.peek() == Some(&b'\"') => {
+                    self.advance(); // Consume ".
+                    self.string(TokenType::ByteStringLiteral)
+                },
+
+                b'#' => self.directive(),
 
                 _ => {
-                    if is_alpha(*ch) || *ch == b'_' {
+                    if is_alpha(ch) || ch == b'_' {
                         self.identifier_or_keyword()
-                    } else if is_digit(*ch) {
+                    } else if is_digit(ch) {
                         self.number()
                     } else {
-                        self.make_error_token("unrecognized character")
+                        Err("unrecognized character")
                     }
                 }
             }
 
-            None => self.make_eof_token()
+            None => Ok(TokenType::EndOfFile)
+        }
+    }
+
+    fn character_literal(&mut self) -> Scan {
+        while let Some(ch) = self.peek() {
+            if *ch == b'\'' || *ch == b'\n' {
+                break;
+            }
+
+            self.literal_character();
+        }
+
+        if !self.matching(b'\'') {
+            Err("unterminated character literal")
+        } else {
+            Ok(TokenType::CharLiteral)
         }
     }
 
-    fn character_literal(&mut self) -> Token {
+    fn string(&mut self, kind: TokenType) -> Scan {
+        while let Some(ch) = self.peek() {
+            if *ch == b'\"' {
+                break;
+            }
+
+            self.literal_character();
+        }
+
         if self.is_at_end() {
-            self.make_error_token("expected character")
+            Err("unterminated string literal")
         } else {
-            self.advance(); // Consume character.
+            self.advance(); // Consume ".
+            Ok(kind)
+        }
+    }
 
-            if self.is_at_end() {
-                self.make_error_token("unterminated character literal")
-            } else {
-                self.advance(); // Consume '.
-                self.make_token(TokenType::CharLiteral)
+    // Escape sequences are decoded by the parser, the scanner only has to skip
+    // over them so that an escaped quote does not end the literal.
+    fn literal_character(&mut self) {
+        if let Some(b'\\') = self.advance() {
+            if !self.is_at_end() {
+                self.advance();
             }
         }
     }
 
-    fn string(&mut self) -> Token {
+    fn directive(&mut self) -> Scan {
         self.advance_while(|ch| {
-            ch != b'\"'
+            ch != b'\n'
         });
 
-        if self.is_at_end() {
-            self.make_error_token("unterminated character literal")
-        } else {
-            self.advance(); // Consume ".
-            self.make_token(TokenType::StringLiteral)
-        }
+        Ok(TokenType::Directive)
     }
 
-    fn number(&mut self) -> Token {
-        self.advance_while(is_digit);
-        self.make_token(TokenType::IntLiteral)
+    // Letters are taken too, so that `12ab` is reported as a bad literal rather
+    // than a number followed by a name.
+    fn number(&mut self) -> Scan {
+        self.advance_while(|ch| is_alpha(ch) || is_digit(ch) || ch == b'_');
+        Ok(TokenType::IntLiteral)
     }
 
-    fn identifier_or_keyword(&mut self) -> Token {
+    fn identifier_or_keyword(&mut self) -> Scan {
         self.advance_while(|ch| {
             is_alpha(ch) || is_digit(ch) || ch == b'_'
         });
 
-        self.make_token(self.check_identifier())
+        Ok(self.check_identifier())
     }
 
+    // The whole word is compared, so `ifx` and `retur` stay identifiers.
     fn check_identifier(&self) -> TokenType {
-        match self.file.data[self.start] {
-            b'i' => self.check_rest(1, b"f", TokenType::KeywordIf),
-            b'r' => self.check_rest(1, b"eturn", TokenType::KeywordReturn),
-
-            b'e' => if self.current - self.start > 1 {
-                match self.file.data[self.start + 1] {
-                    b'l' => self.check_rest(2, b"se", TokenType::KeywordElse),
-                    b'x' => self.check_rest(2, b"tern", TokenType::KeywordExtern),
-                    _ => TokenType::Identifier
-                }
-            } else {
-                TokenType::Identifier
-            }
-            
-            b'w' => self.check_rest(1, b"hile", TokenType::KeywordWhile),
-            b'd' => self.check_rest(1, b"o", TokenType::KeywordDo),
-            b'b' => self.check_rest(1, b"reak", TokenType::KeywordBreak),
-            b'c' => self.check_rest(1, b"ontinue", TokenType::KeywordContinue),
-            b'a' => self.check_rest(1, b"uto", TokenType::KeywordAuto),
-
-            _    => TokenType::Identifier
-        }
-    }
-
-    fn check_rest(&self, already: usize, rest: &[u8], keyword: TokenType) -> TokenType {
-        for i in already..(rest.len() + already) {
-            match self.file.data.get(self.start + i) {
-                Some(ch) => {
-                    if *ch == rest[i - already] {
-                        continue
-                    } else {
-                        return TokenType::Identifier;
-                    }
-                },
-                None => return TokenType::Identifier
-            }
+        match &self.file.data[self.start..self.current] {
+            b"if" => TokenType::KeywordIf,
+            b"else" => TokenType::KeywordElse,
+            b"return" => TokenType::KeywordReturn,
+            b"extern" => TokenType::KeywordExtern,
+            b"auto" => TokenType::KeywordAuto,
+            b"asm" => TokenType::KeywordAsm,
+            b"while" => TokenType::KeywordWhile,
+            b"do" => TokenType::KeywordDo,
+            b"break" => TokenType::KeywordBreak,
+            b"continue" => TokenType::KeywordContinue,
+            _ => TokenType::Identifier
         }
-
-        return keyword;
     }
 
     fn is_at_end(&self) -> bool {
@@ -260,7 +301,7 @@ impl Scanner {
             }
         }
 
-        return false;
+        false
     }
 
     fn peek(&self) -> Option<&u8> {
@@ -271,31 +312,152 @@ impl Scanner {
     fn make_current_position(&self) -> FilePosition {
         FilePosition {
             file: self.file.clone(),
-            line: self.line
+            line: self.line,
+            offset: self.start
         }
     }
 
     fn make_token(&self, kind: TokenType) -> Token {
-        Token {
-            kind,
-            pos: self.make_current_position(),
-            data: String::from_utf8_lossy(&self.file.data[self.start..self.current]).into_owned()
-        }
+        Token::new(kind, self.make_current_position(), self.current)
     }
 
     fn make_error_token(&self, msg: &'static str) -> Token {
-        Token {
-            kind: TokenType::Error,
-            pos: self.make_current_position(),
-            data: String::from(msg)
-        }
+        Token::error(self.make_current_position(), msg)
     }
 
     fn make_eof_token(&self) -> Token {
-        Token {
-            kind: TokenType::EndOfFile,
-            pos: self.make_current_position(),
-            data: String::from("")
+        Token::end_of_file(self.make_current_position())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_whitespace(ch: u8) -> bool {
+        matches!(ch, b' ' | b'\t' | b'\r' | b'\n')
+    }
+
+    // Checks that the tokens reproduce every non-whitespace byte of the file
+    // exactly once and in order. Returns the first token that doesn't.
+    fn verify_round_trip(file: Rc<File>) -> Result<(), Token> {
+        let mut scanner = Scanner::new(file.clone());
+        let mut offset = file.content_start();
+
+        loop {
+            let token = scanner.next_token();
+
+            while offset < file.data.len() && is_whitespace(file.data[offset]) {
+                offset += 1;
+            }
+
+            match token.kind {
+                TokenType::EndOfFile => {
+                    return if offset == file.data.len() { Ok(()) } else { Err(token) };
+                },
+
+                TokenType::Error => return Err(token),
+
+                _ => if file.data[offset..].starts_with(token.text().as_bytes()) {
+                    offset += token.text().len();
+                } else {
+                    return Err(token);
+                }
+            }
+        }
+    }
+
+    fn file(source: &str) -> Rc<File> {
+        Rc::new(File { path: String::from("test.b"), data: source.as_bytes().to_vec() })
+    }
+
+    fn assert_round_trip(source: &str) {
+        if let Err(token) = verify_round_trip(file(source)) {
+            panic!("{:?} '{}' on line {} does not round-trip in:\n{}", token.kind, token.text(), token.pos.line, source);
         }
     }
+
+    fn kinds(source: &str) -> Vec<TokenType> {
+        let mut scanner = Scanner::new(file(source));
+        let mut kinds = Vec::new();
+
+        loop {
+            match scanner.next_token().kind {
+                TokenType::EndOfFile => return kinds,
+                kind => kinds.push(kind)
+            }
+        }
+    }
+
+    const SAMPLES: [&str; 7] = [
+        "",
+        "count 10;\nname \"blang\";\nnewline '\\n';\n",
+        "main() {\n    extern count;\n    auto i;\n    i = 0;\n    while (i < count)\n        i++;\n    return i;\n}\n",
+        "max(a, b) {\n    if (a >= b) return a; else return b;\n}\n",
+        "f(x,y){return(x-5)*-y+~x%2/3<<1>>2&x|y^!x&&x||y?x:-1;}\n",
+        "#include \"lib.b\"\n\tmain ( ) { return --x + x++ - -x != x == 'a' <= \"q\\\"uote\" ; }\r\n",
+        "raw b\"no\\0nul\";\nbyte b;\n"
+    ];
+
+    #[test]
+    fn samples_round_trip() {
+        for source in SAMPLES {
+            assert_round_trip(source);
+        }
+    }
+
+    #[test]
+    fn lost_bytes_do_not_round_trip() {
+        assert!(verify_round_trip(file("main() { return 1 @ 2; }")).is_err());
+        assert!(verify_round_trip(file("s \"unterminated;")).is_err());
+    }
+
+    #[test]
+    fn minus_before_a_number_is_an_operator() {
+        use TokenType::*;
+
+        assert_round_trip("x-5");
+        assert_eq!(kinds("x-5"), [Identifier, Minus, IntLiteral]);
+        assert_eq!(kinds("x = -5"), [Identifier, Equal, Minus, IntLiteral]);
+        assert_eq!(kinds("x--5"), [Identifier, MinusMinus, IntLiteral]);
+    }
+
+    #[test]
+    fn keywords_match_the_whole_identifier() {
+        use TokenType::*;
+
+        assert_eq!(kinds("if ifx i else elsewhere auto autox asm asmx"), [
+            KeywordIf, Identifier, Identifier, KeywordElse, Identifier, KeywordAuto, Identifier, KeywordAsm, Identifier
+        ]);
+        assert_eq!(kinds("retur return returns do done"), [Identifier, KeywordReturn, Identifier, KeywordDo, Identifier]);
+    }
+
+    #[test]
+    fn leading_bom_is_skipped() {
+        use TokenType::*;
+
+        assert_round_trip("\u{feff}x 1;\n");
+        assert_eq!(kinds("\u{feff}x 1;"), [Identifier, IntLiteral, Semicolon]);
+
+        // Only at the start of the file.
+        assert!(kinds("x \u{feff}1;").contains(&Error));
+    }
+
+    #[test]
+    fn count_matches_the_tokens_built() {
+        for source in ["", "main() {\n    return x-5;\n}\n", "x 'ab @ \"unterminated"] {
+            assert_eq!(Scanner::new(file(source)).count_tokens(), kinds(source).len(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn token_text_is_read_from_the_file_on_demand() {
+        let mut scanner = Scanner::new(file("s \"h\u{e9}\";\n@ x"));
+        let tokens: Vec<Token> = std::iter::from_fn(|| Some(scanner.next_token()).filter(|t| t.kind != TokenType::EndOfFile)).collect();
+        let texts: Vec<String> = tokens.iter().map(|token| token.text().into_owned()).collect();
+
+        assert_eq!(texts, ["s", "\"h\u{e9}\"", ";", "unrecognized character", "x"]);
+        assert_eq!(tokens[1].pos.offset, 2);
+        assert_eq!(scanner.next_token().text(), "");
+    }
 }
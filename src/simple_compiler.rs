@@ -1,21 +1,744 @@
-use crate::ast::Decl;
+use crate::{ast::*, file::*, gas, listing, program::*, token::TokenType, toy};
+use std::rc::Rc;
 
 pub struct Compiler {
-    code: String
+    word_size: usize,
+    // String buffers are indexed by byte instead of by word.
+    packed_chars: bool,
+    program: Program,
+    string_count: usize,
+    label_count: usize,
+    // Globals defined so far that are vectors.
+    vectors: Vec<String>,
+    // Data words initialized with the value of a global: data index, word index
+    // and the global.
+    value_refs: Vec<(usize, usize, String)>,
+    // The function the C runtime calls.
+    entry: String,
+    function: Option<FunctionState>
+}
+
+struct FunctionState {
+    locals: Vec<(String, Location)>,
+    // Names of the vectors visible in the function.
+    vectors: Vec<String>,
+    // Offsets of the locals holding the address of a packed string buffer.
+    byte_arrays: Vec<i32>,
+    frame_size: usize,
+    // Continue and break targets of the enclosing loops. Only loops push here:
+    // a `switch`, if one is added, must keep its break target separately so
+    // that `continue` inside it still goes to the loop.
+    loops: Vec<(usize, usize)>,
+    // Source line of the last `Line` marker.
+    line: usize,
+    code: Vec<Instruction>
+}
+
+#[derive(Clone)]
+enum Location {
+    Local(i32),
+    Global(String)
 }
 
 impl Compiler {
-    pub fn new() -> Self {
+    pub fn new(word_size: usize, packed_chars: bool) -> Self {
         Compiler {
-            code: String::new() // TODO: Add init code.
+            word_size,
+            packed_chars,
+            program: Program::new(word_size), // TODO: Add init code.
+            string_count: 0,
+            label_count: 0,
+            vectors: Vec::new(),
+            value_refs: Vec::new(),
+            entry: String::from("main"),
+            function: None
+        }
+    }
+
+    pub fn set_entry(&mut self, name: &str) {
+        self.entry = String::from(name);
+    }
+
+    pub fn set_string_encoding(&mut self, encoding: StringEncoding) {
+        self.program.string_encoding = encoding;
+    }
+
+    pub fn compile_one_decl(&mut self, decl: &Decl) {
+        match &decl.kind {
+            DeclKind::External(var) => self.compile_global(var),
+            DeclKind::Function { name, params, body } => self.compile_function(&decl.pos, name, params, body)
+        }
+    }
+
+    pub fn get_code(&self) -> String {
+        gas::render(&self.program)
+    }
+
+    pub fn get_binary(&self, entry: &str) -> Result<Vec<u8>, String> {
+        toy::assemble(&self.program, entry)
+    }
+
+    // One `OUTPUT_LINE<TAB>PATH:LINE` entry per source line with generated code.
+    pub fn get_source_map(&self) -> String {
+        let (_, map) = gas::render_with_source_map(&self.program);
+
+        map.iter()
+            .map(|(line, pos)| format!("{}\t{}:{}\n", line, pos.file.path, pos.line))
+            .collect()
+    }
+
+    pub fn get_listing(&self, file: &Rc<File>) -> String {
+        listing::render(&self.program, file)
+    }
+
+    // Value references are resolved once every global is compiled, the validator
+    // rejects cycles and references to anything but data.
+    pub fn resolve_initializers(&mut self) {
+        let refs = std::mem::take(&mut self.value_refs);
+
+        for (data, word, target) in &refs {
+            if let Some(value) = self.global_value(&refs, target) {
+                self.program.data[*data].values[*word] = value;
+            }
+        }
+    }
+
+    // With --keep-going the references of a broken program are resolved too,
+    // those to missing globals or in a cycle are left as zero.
+    fn global_value(&self, refs: &[(usize, usize, String)], name: &str) -> Option<DataValue> {
+        let mut name = name;
+
+        for _ in 0..=refs.len() {
+            let data = self.program.data.iter().position(|data| data.label == name)?;
+
+            match refs.iter().find(|(ref_data, word, _)| *ref_data == data && *word == 0) {
+                Some((_, _, target)) => name = target,
+                None => return Some(self.program.data[data].values[0].clone())
+            }
+        }
+
+        None
+    }
+
+    fn compile_global(&mut self, var: &Variable) {
+        let mut values = Vec::new();
+        let mut refs = Vec::new();
+
+        for expr in &var.initial {
+            let value = match &expr.kind {
+                ExprKind::Var(target) => {
+                    refs.push((values.len(), target.clone()));
+                    DataValue::Word(0)
+                },
+
+                _ => self.compile_constant(expr)
+            };
+
+            values.push(value);
+        }
+
+        match &var.size {
+            VariableSize::Scalar => {
+                if values.is_empty() {
+                    values.push(DataValue::Word(0));
+                }
+            },
+
+            // The variable holds the address of the vector's storage.
+            VariableSize::Vector(size) => {
+                let size = match size {
+                    Some(Expr { kind: ExprKind::IntLit(size, _), .. }) => *size as usize,
+                    _ => values.len()
+                };
+
+                values.resize_with(size.max(values.len()), || DataValue::Word(0));
+
+                let storage = format!(".Lvec_{}", var.name);
+                self.vectors.push(var.name.clone());
+
+                self.program.data.push(Data {
+                    label: storage.clone(),
+                    global: false,
+                    values
+                });
+
+                self.add_value_refs(refs);
+                refs = Vec::new();
+
+                values = vec![DataValue::Address(storage)];
+            }
+        }
+
+        self.program.data.push(Data {
+            label: var.name.clone(),
+            global: true,
+            values
+        });
+
+        self.add_value_refs(refs);
+    }
+
+    // The words belong to the last data block.
+    fn add_value_refs(&mut self, refs: Vec<(usize, String)>) {
+        let data = self.program.data.len() - 1;
+        self.value_refs.extend(refs.into_iter().map(|(word, target)| (data, word, target)));
+    }
+
+    fn compile_constant(&mut self, expr: &Expr) -> DataValue {
+        match &expr.kind {
+            ExprKind::IntLit(value, _) => DataValue::Word(*value),
+            ExprKind::StringLit(value, kind) => DataValue::Address(self.add_string(value, *kind)),
+            ExprKind::UnaryOp(TokenType::Ampersand, Fixity::Prefix, operand) => match &operand.kind {
+                ExprKind::Var(name) => DataValue::Address(name.clone()),
+                _ => unreachable!("validator allows only addresses of globals")
+            },
+            _ => unreachable!("validator allows only literal initializers")
+        }
+    }
+
+    // Strings live in their own data blocks, expressions refer to them by address.
+    fn add_string(&mut self, value: &str, kind: StringKind) -> String {
+        let label = format!(".Lstr{}", self.string_count);
+        self.string_count += 1;
+
+        let mut bytes = value.as_bytes().to_vec();
+
+        if kind == StringKind::NulTerminated {
+            bytes.push(0);
+        }
+
+        self.program.data.push(Data {
+            label: label.clone(),
+            global: false,
+            values: vec![DataValue::Bytes(bytes)]
+        });
+
+        label
+    }
+
+    fn compile_function(&mut self, pos: &FilePosition, name: &str, params: &[String], body: &Stmt) {
+        // Parameters are above the saved frame pointer and the return address.
+        // That is the 32-bit C convention, so `main(argc, argv)` linked with the C
+        // runtime gets its arguments there. The x86-64 one passes the first six
+        // in registers, the entry function keeps those in locals instead.
+        let register_params = if self.word_size == 8 && name == self.entry { params.len().min(6) } else { 0 };
+
+        self.function = Some(FunctionState {
+            locals: Vec::new(),
+            vectors: Vec::new(),
+            byte_arrays: Vec::new(),
+            frame_size: 0,
+            loops: Vec::new(),
+            line: 0,
+            code: Vec::new()
+        });
+
+        for (i, param) in params.iter().enumerate() {
+            let location = if i < register_params {
+                Location::Local(self.allocate(1))
+            } else {
+                Location::Local(((i - register_params + 2) * self.word_size) as i32)
+            };
+
+            self.state().locals.push((param.clone(), location));
+        }
+
+        self.mark_line(pos);
+
+        self.compile_stmt(body);
+
+        // Falling off the end returns 0.
+        self.emit(Instruction::Push(0));
+        self.emit(Instruction::Return);
+
+        let state = self.function.take().unwrap();
+
+        self.program.functions.push(Function {
+            name: String::from(name),
+            frame_size: state.frame_size,
+            register_params,
+            code: state.code
+        });
+    }
+
+    fn compile_stmt(&mut self, stmt: &Stmt) {
+        if !matches!(stmt.kind, StmtKind::Block(_)) {
+            self.mark_line(&stmt.pos);
         }
+
+        match &stmt.kind {
+            StmtKind::Block(stmts) => {
+                for stmt in stmts {
+                    self.compile_stmt(stmt);
+                }
+            },
+
+            StmtKind::Expr(expr) => {
+                self.compile_expr(expr);
+                self.emit(Instruction::Pop);
+            },
+
+            StmtKind::Auto(vars) => {
+                for var in vars {
+                    self.compile_auto(var);
+                }
+            },
+
+            StmtKind::Extern(name) => {
+                if self.vectors.contains(name) {
+                    self.state().vectors.push(name.clone());
+                }
+
+                self.state().locals.push((name.clone(), Location::Global(name.clone())));
+            },
+
+            StmtKind::If(cond, then_arm, else_arm) => {
+                let else_label = self.new_label();
+                let end_label = self.new_label();
+
+                self.compile_expr(cond);
+                self.emit(Instruction::JumpIfZero(else_label));
+                self.compile_stmt(then_arm);
+                self.emit(Instruction::Jump(end_label));
+                self.emit(Instruction::Label(else_label));
+
+                if let Some(else_arm) = else_arm {
+                    self.compile_stmt(else_arm);
+                }
+
+                self.emit(Instruction::Label(end_label));
+            },
+
+            StmtKind::While(cond, body) => {
+                let continue_label = self.new_label();
+                let break_label = self.new_label();
+
+                self.emit(Instruction::Label(continue_label));
+                self.compile_expr(cond);
+                self.emit(Instruction::JumpIfZero(break_label));
+
+                if let Some(body) = body {
+                    self.compile_loop_body(body, continue_label, break_label);
+                }
+
+                self.emit(Instruction::Jump(continue_label));
+                self.emit(Instruction::Label(break_label));
+            },
+
+            StmtKind::DoWhile(cond, body) => {
+                let start_label = self.new_label();
+                let continue_label = self.new_label();
+                let break_label = self.new_label();
+
+                self.emit(Instruction::Label(start_label));
+                self.compile_loop_body(body, continue_label, break_label);
+                self.emit(Instruction::Label(continue_label));
+                self.compile_expr(cond);
+                self.emit(Instruction::JumpIfNotZero(start_label));
+                self.emit(Instruction::Label(break_label));
+            },
+
+            StmtKind::Return(expr) => {
+                match expr {
+                    Some(expr) => self.compile_expr(expr),
+                    None => self.emit(Instruction::Push(0))
+                }
+
+                self.emit(Instruction::Return);
+            },
+
+            StmtKind::Break(levels) => {
+                let (_, break_label) = self.enclosing_loop(*levels);
+                self.emit(Instruction::Jump(break_label));
+            },
+
+            StmtKind::Continue(levels) => {
+                let (continue_label, _) = self.enclosing_loop(*levels);
+                self.emit(Instruction::Jump(continue_label));
+            },
+
+            StmtKind::Asm(text) => self.emit(Instruction::Asm(text.clone())),
+
+            StmtKind::Empty => {}
+        }
+    }
+
+    fn compile_loop_body(&mut self, body: &Stmt, continue_label: usize, break_label: usize) {
+        self.state().loops.push((continue_label, break_label));
+        self.compile_stmt(body);
+        self.state().loops.pop();
+    }
+
+    fn compile_auto(&mut self, var: &Variable) {
+        if let Some((value, kind)) = var.string_buffer() {
+            self.compile_string_buffer(&var.name, value, kind);
+            return;
+        }
+
+        match &var.size {
+            VariableSize::Scalar => {
+                let offset = self.allocate(1);
+                self.state().locals.push((var.name.clone(), Location::Local(offset)));
+
+                if let Some(initial) = var.initial.first() {
+                    self.emit(Instruction::PushLocalAddress(offset));
+                    self.compile_expr(initial);
+                    self.emit(Instruction::Store);
+                    self.emit(Instruction::Pop);
+                }
+            },
+
+            VariableSize::Vector(size) => {
+                let size = match size {
+                    Some(Expr { kind: ExprKind::IntLit(size, _), .. }) => *size as usize,
+                    _ => var.initial.len()
+                };
+
+                let storage = self.allocate(size.max(var.initial.len()));
+                let offset = self.allocate(1);
+                self.state().locals.push((var.name.clone(), Location::Local(offset)));
+                self.state().vectors.push(var.name.clone());
+
+                self.emit(Instruction::PushLocalAddress(offset));
+                self.emit(Instruction::PushLocalAddress(storage));
+                self.emit(Instruction::Store);
+                self.emit(Instruction::Pop);
+
+                for (i, initial) in var.initial.iter().enumerate() {
+                    self.emit(Instruction::PushLocalAddress(storage + (i * self.word_size) as i32));
+                    self.compile_expr(initial);
+                    self.emit(Instruction::Store);
+                    self.emit(Instruction::Pop);
+                }
+            }
+        }
+    }
+
+    // The string is padded to whole words in the data section and copied word by
+    // word into the local storage.
+    fn compile_string_buffer(&mut self, name: &str, value: &str, kind: StringKind) {
+        let mut bytes = String::from(value);
+
+        if kind == StringKind::NulTerminated {
+            bytes.push('\0');
+        }
+
+        let words = bytes.len().div_ceil(self.word_size);
+
+        while bytes.len() < words * self.word_size {
+            bytes.push('\0');
+        }
+
+        let label = self.add_string(&bytes, StringKind::Raw);
+
+        let storage = self.allocate(words);
+        let offset = self.allocate(1);
+        self.state().locals.push((String::from(name), Location::Local(offset)));
+        self.state().vectors.push(String::from(name));
+
+        if self.packed_chars {
+            self.state().byte_arrays.push(offset);
+        }
+
+        self.emit(Instruction::PushLocalAddress(offset));
+        self.emit(Instruction::PushLocalAddress(storage));
+        self.emit(Instruction::Store);
+        self.emit(Instruction::Pop);
+
+        for i in 0..words {
+            let word_offset = (i * self.word_size) as i32;
+
+            self.emit(Instruction::PushLocalAddress(storage + word_offset));
+            self.emit(Instruction::PushAddress(label.clone()));
+            self.emit(Instruction::Push(word_offset));
+            self.emit(Instruction::Binary(BinaryOp::Add));
+            self.emit(Instruction::Load);
+            self.emit(Instruction::Store);
+            self.emit(Instruction::Pop);
+        }
+    }
+
+    fn compile_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::IntLit(value, _) => self.emit(Instruction::Push(*value)),
+
+            ExprKind::StringLit(value, kind) => {
+                let label = self.add_string(value, *kind);
+                self.emit(Instruction::PushAddress(label));
+            },
+
+            ExprKind::Var(_) | ExprKind::Index(..) => {
+                self.compile_lvalue(expr);
+                self.emit_load(expr);
+            },
+
+            ExprKind::UnaryOp(op, fixity, operand) => self.compile_unary(*op, *fixity, operand),
+
+            ExprKind::BinOp(left, op, right) => self.compile_binary(left, *op, right),
+
+            // The target address is computed once, before the value. `Store` leaves
+            // the value on the stack, so `a = b = c` stores it into `b` and then `a`.
+            ExprKind::Assign(target, value) => {
+                self.compile_lvalue(target);
+                self.compile_expr(value);
+                self.emit_store(target);
+            },
+
+            ExprKind::Ternary(cond, then_arm, else_arm) => {
+                let else_label = self.new_label();
+                let end_label = self.new_label();
+
+                self.compile_expr(cond);
+                self.emit(Instruction::JumpIfZero(else_label));
+                self.compile_expr(then_arm);
+                self.emit(Instruction::Jump(end_label));
+                self.emit(Instruction::Label(else_label));
+                self.compile_expr(else_arm);
+                self.emit(Instruction::Label(end_label));
+            },
+
+            ExprKind::Call(callee, args) => {
+                for arg in args.iter().rev() {
+                    self.compile_expr(arg);
+                }
+
+                match self.direct_callee(callee) {
+                    Some(name) => self.emit(Instruction::CallDirect(name, args.len())),
+
+                    None => {
+                        self.compile_expr(callee);
+                        self.emit(Instruction::Call(args.len()));
+                    }
+                }
+            }
+        }
+    }
+
+    // Globals are called by name, everything else through the computed address.
+    fn direct_callee(&mut self, callee: &Expr) -> Option<String> {
+        match &callee.kind {
+            ExprKind::Var(name) => match self.lookup(name) {
+                Location::Global(name) => Some(name),
+                Location::Local(_) => None
+            },
+
+            _ => None
+        }
+    }
+
+    fn compile_unary(&mut self, op: TokenType, fixity: Fixity, operand: &Expr) {
+        match op {
+            TokenType::Minus => self.compile_unary_op(operand, UnaryOp::Negate),
+            TokenType::Tilda => self.compile_unary_op(operand, UnaryOp::Complement),
+            TokenType::Bang => self.compile_unary_op(operand, UnaryOp::Not),
+            TokenType::Plus => self.compile_expr(operand),
+
+            TokenType::Star => {
+                self.compile_expr(operand);
+                self.emit(Instruction::Load);
+            },
+
+            TokenType::Ampersand => self.compile_lvalue(operand),
+
+            TokenType::PlusPlus | TokenType::MinusMinus => {
+                let (op, undo) = if op == TokenType::PlusPlus {
+                    (BinaryOp::Add, BinaryOp::Sub)
+                } else {
+                    (BinaryOp::Sub, BinaryOp::Add)
+                };
+
+                self.compile_lvalue(operand);
+                self.emit(Instruction::Dup);
+                self.emit_load(operand);
+                self.emit(Instruction::Push(1));
+                self.emit(Instruction::Binary(op));
+                self.emit_store(operand);
+
+                // The store leaves the updated value, undo the update on it
+                // to get the old one.
+                if fixity == Fixity::Postfix {
+                    self.emit(Instruction::Push(1));
+                    self.emit(Instruction::Binary(undo));
+                }
+            },
+
+            _ => unreachable!("parser produces only unary operators")
+        }
+    }
+
+    fn compile_unary_op(&mut self, operand: &Expr, op: UnaryOp) {
+        self.compile_expr(operand);
+        self.emit(Instruction::Unary(op));
+    }
+
+    fn compile_binary(&mut self, left: &Expr, op: TokenType, right: &Expr) {
+        let op = match op {
+            // Left to right, the value of the left operand is discarded.
+            TokenType::Comma => {
+                self.compile_expr(left);
+                self.emit(Instruction::Pop);
+                self.compile_expr(right);
+                return;
+            },
+
+            TokenType::AmpersandAmpersand | TokenType::BarBar => {
+                self.compile_logical(left, op, right);
+                return;
+            },
+
+            TokenType::Plus => BinaryOp::Add,
+            TokenType::Minus => BinaryOp::Sub,
+            TokenType::Star => BinaryOp::Mul,
+            TokenType::Slash => BinaryOp::Div,
+            TokenType::Percent => BinaryOp::Mod,
+            TokenType::Ampersand => BinaryOp::And,
+            TokenType::Bar => BinaryOp::Or,
+            TokenType::UpArrow => BinaryOp::Xor,
+            TokenType::LessLess => BinaryOp::ShiftLeft,
+            TokenType::GreaterGreater => BinaryOp::ShiftRight,
+            TokenType::GreaterGreaterGreater => BinaryOp::ShiftRightLogical,
+            TokenType::EqualEqual => BinaryOp::Equal,
+            TokenType::BangEqual => BinaryOp::NotEqual,
+            TokenType::Less => BinaryOp::Less,
+            TokenType::LessEqual => BinaryOp::LessEqual,
+            TokenType::Greater => BinaryOp::Greater,
+            TokenType::GreaterEqual => BinaryOp::GreaterEqual,
+            _ => unreachable!("parser produces only binary operators")
+        };
+
+        self.compile_expr(left);
+        self.compile_expr(right);
+        self.emit(Instruction::Binary(op));
+    }
+
+    // The right operand is evaluated only when the left one doesn't decide the result.
+    fn compile_logical(&mut self, left: &Expr, op: TokenType, right: &Expr) {
+        let short_label = self.new_label();
+        let end_label = self.new_label();
+
+        let (jump, short_value): (fn(usize) -> Instruction, i32) = if op == TokenType::AmpersandAmpersand {
+            (Instruction::JumpIfZero, 0)
+        } else {
+            (Instruction::JumpIfNotZero, 1)
+        };
+
+        self.compile_expr(left);
+        self.emit(jump(short_label));
+        self.compile_expr(right);
+        self.emit(jump(short_label));
+        self.emit(Instruction::Push(1 - short_value));
+        self.emit(Instruction::Jump(end_label));
+        self.emit(Instruction::Label(short_label));
+        self.emit(Instruction::Push(short_value));
+        self.emit(Instruction::Label(end_label));
+    }
+
+    // Pushes the address the expression designates.
+    fn compile_lvalue(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Var(name) => match self.lookup(name) {
+                Location::Local(offset) => self.emit(Instruction::PushLocalAddress(offset)),
+                Location::Global(name) => self.emit(Instruction::PushAddress(name))
+            },
+
+            ExprKind::UnaryOp(TokenType::Star, Fixity::Prefix, operand) => self.compile_expr(operand),
+
+            ExprKind::Index(base, index) => {
+                let vectors = &self.state().vectors;
+                let (base, index) = index_operands(base, index, |name| vectors.iter().any(|vector| vector == name));
+
+                // The offset wraps like any other arithmetic, the validator warns
+                // about constant indices where it does.
+                let stride = if self.is_byte_array(base) { 1 } else { self.word_size };
+
+                self.compile_expr(base);
+                self.compile_expr(index);
+
+                if stride != 1 {
+                    self.emit(Instruction::Push(stride as i32));
+                    self.emit(Instruction::Binary(BinaryOp::Mul));
+                }
+
+                self.emit(Instruction::Binary(BinaryOp::Add));
+            },
+
+            _ => unreachable!("validator rejects non-lvalue operands")
+        }
+    }
+
+    // Elements of a packed string buffer are bytes, everything else is a word.
+    fn is_byte_element(&mut self, lvalue: &Expr) -> bool {
+        match &lvalue.kind {
+            ExprKind::Index(base, index) => {
+                let vectors = &self.state().vectors;
+                let (base, _) = index_operands(base, index, |name| vectors.iter().any(|vector| vector == name));
+                self.is_byte_array(base)
+            },
+
+            _ => false
+        }
+    }
+
+    fn is_byte_array(&mut self, expr: &Expr) -> bool {
+        match &expr.kind {
+            ExprKind::Var(name) => match self.lookup(name) {
+                Location::Local(offset) => self.state().byte_arrays.contains(&offset),
+                Location::Global(_) => false
+            },
+
+            _ => false
+        }
+    }
+
+    fn emit_load(&mut self, lvalue: &Expr) {
+        let instruction = if self.is_byte_element(lvalue) { Instruction::LoadByte } else { Instruction::Load };
+        self.emit(instruction);
+    }
+
+    fn emit_store(&mut self, lvalue: &Expr) {
+        let instruction = if self.is_byte_element(lvalue) { Instruction::StoreByte } else { Instruction::Store };
+        self.emit(instruction);
+    }
+
+    fn lookup(&mut self, name: &str) -> Location {
+        self.state().locals.iter()
+            .rev()
+            .find(|(local, _)| local == name)
+            .map_or_else(|| Location::Global(String::from(name)), |(_, location)| location.clone())
+    }
+
+    // Returns the frame offset of the lowest allocated word.
+    fn allocate(&mut self, words: usize) -> i32 {
+        let word_size = self.word_size;
+        let state = self.state();
+        state.frame_size += words * word_size;
+        -(state.frame_size as i32)
+    }
+
+    fn new_label(&mut self) -> usize {
+        self.label_count += 1;
+        self.label_count
+    }
+
+    fn mark_line(&mut self, pos: &FilePosition) {
+        if self.state().line != pos.line {
+            self.state().line = pos.line;
+            self.emit(Instruction::Line(pos.clone()));
+        }
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.state().code.push(instruction);
     }
 
-    pub fn compile_one_decl(&mut self, _decl: &Decl) {
-        
+    fn state(&mut self) -> &mut FunctionState {
+        self.function.as_mut().expect("statements are compiled only inside functions")
     }
 
-    pub fn get_code(&self) -> &String {
-        &self.code
+    // Continue and break labels of the loop `levels` out, 1 is the innermost.
+    fn enclosing_loop(&mut self, levels: usize) -> (usize, usize) {
+        let loops = &self.state().loops;
+        let index = loops.len().checked_sub(levels).expect("validator rejects exiting more loops than there are");
+        loops[index]
     }
 }
@@ -0,0 +1,95 @@
+// Counts of the declarations, statements and expressions of a program, after
+// constant folding.
+
+use crate::ast::*;
+use std::fmt;
+
+#[derive(Default)]
+pub struct Stats {
+    functions: usize,
+    globals: usize,
+    // In the order of the first occurrence.
+    stmts: Vec<(&'static str, usize)>,
+    exprs: Vec<(&'static str, usize)>,
+    // Statement nesting, a function body is at depth 1.
+    depth: usize,
+    max_depth: usize
+}
+
+impl Visitor for Stats {
+    fn visit_decl(&mut self, decl: &Decl) {
+        match decl.kind {
+            DeclKind::Function { .. } => self.functions += 1,
+            DeclKind::External(_) => self.globals += 1
+        }
+
+        walk_decl(self, decl);
+    }
+
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        self.depth += 1;
+        self.max_depth = self.max_depth.max(self.depth);
+
+        let kind = match stmt.kind {
+            StmtKind::Block(_) => "block",
+            StmtKind::Expr(_) => "expression",
+            StmtKind::Auto(_) => "auto",
+            StmtKind::Extern(_) => "extern",
+            StmtKind::If(..) => "if",
+            StmtKind::While(..) => "while",
+            StmtKind::DoWhile(..) => "do",
+            StmtKind::Return(_) => "return",
+            StmtKind::Asm(_) => "asm",
+            StmtKind::Break(_) => "break",
+            StmtKind::Continue(_) => "continue",
+            StmtKind::Empty => "empty"
+        };
+
+        count(&mut self.stmts, kind);
+        walk_stmt(self, stmt);
+
+        self.depth -= 1;
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        let kind = match expr.kind {
+            ExprKind::IntLit(..) => "integer",
+            ExprKind::StringLit(..) => "string",
+            ExprKind::Var(_) => "variable",
+            ExprKind::UnaryOp(..) => "unary",
+            ExprKind::BinOp(..) => "binary",
+            ExprKind::Assign(..) => "assignment",
+            ExprKind::Ternary(..) => "ternary",
+            ExprKind::Call(..) => "call",
+            ExprKind::Index(..) => "index"
+        };
+
+        count(&mut self.exprs, kind);
+        walk_expr(self, expr);
+    }
+}
+
+fn count(counts: &mut Vec<(&'static str, usize)>, kind: &'static str) {
+    match counts.iter_mut().find(|(name, _)| *name == kind) {
+        Some((_, count)) => *count += 1,
+        None => counts.push((kind, 1))
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "functions\t{}", self.functions)?;
+        writeln!(f, "globals\t{}", self.globals)?;
+        writeln!(f, "max depth\t{}", self.max_depth)?;
+
+        for (kind, count) in &self.stmts {
+            writeln!(f, "stmt {}\t{}", kind, count)?;
+        }
+
+        for (kind, count) in &self.exprs {
+            writeln!(f, "expr {}\t{}", kind, count)?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,4 +1,8 @@
-use crate::file::FilePosition;
+use crate::file::{File, FilePosition};
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Range;
+use std::rc::Rc;
 
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum TokenType {
@@ -11,6 +15,7 @@ pub enum TokenType {
     KeywordBreak,
     KeywordContinue,
     KeywordExtern,
+    KeywordAsm,
 
     LeftParen,
     RightParen,
@@ -51,20 +56,156 @@ pub enum TokenType {
     UpArrow,
 
     GreaterGreater,
+    GreaterGreaterGreater,
     LessLess,
 
     Identifier,
     IntLiteral,
     CharLiteral,
     StringLiteral,
+    ByteStringLiteral,
+
+    Directive,
 
     EndOfFile,
     Error,
 }
 
+impl TokenType {
+    // Operators that only appear between two operands.
+    pub fn is_binary_only(self) -> bool {
+        matches!(self,
+            TokenType::Slash | TokenType::Percent | TokenType::Equal |
+            TokenType::EqualEqual | TokenType::BangEqual | TokenType::Greater | TokenType::Less |
+            TokenType::GreaterEqual | TokenType::LessEqual | TokenType::Bar | TokenType::BarBar |
+            TokenType::AmpersandAmpersand | TokenType::UpArrow | TokenType::GreaterGreater |
+            TokenType::GreaterGreaterGreater | TokenType::LessLess | TokenType::QuestionMark)
+    }
+}
+
+// Fixed tokens are shown quoted as they are written, the others by what they are.
+impl fmt::Display for TokenType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            TokenType::KeywordReturn => "return",
+            TokenType::KeywordIf => "if",
+            TokenType::KeywordElse => "else",
+            TokenType::KeywordAuto => "auto",
+            TokenType::KeywordWhile => "while",
+            TokenType::KeywordDo => "do",
+            TokenType::KeywordBreak => "break",
+            TokenType::KeywordContinue => "continue",
+            TokenType::KeywordExtern => "extern",
+            TokenType::KeywordAsm => "asm",
+
+            TokenType::LeftParen => "(",
+            TokenType::RightParen => ")",
+            TokenType::LeftBracket => "{",
+            TokenType::RightBracket => "}",
+            TokenType::LeftBrace => "[",
+            TokenType::RightBrace => "]",
+
+            TokenType::Semicolon => ";",
+            TokenType::Colon => ":",
+            TokenType::QuestionMark => "?",
+            TokenType::Comma => ",",
+
+            TokenType::Plus => "+",
+            TokenType::PlusPlus => "++",
+            TokenType::Minus => "-",
+            TokenType::MinusMinus => "--",
+            TokenType::Star => "*",
+            TokenType::Slash => "/",
+            TokenType::Percent => "%",
+
+            TokenType::Bang => "!",
+            TokenType::Tilda => "~",
+
+            TokenType::Equal => "=",
+
+            TokenType::EqualEqual => "==",
+            TokenType::BangEqual => "!=",
+            TokenType::Greater => ">",
+            TokenType::Less => "<",
+            TokenType::GreaterEqual => ">=",
+            TokenType::LessEqual => "<=",
+
+            TokenType::Bar => "|",
+            TokenType::BarBar => "||",
+            TokenType::Ampersand => "&",
+            TokenType::AmpersandAmpersand => "&&",
+            TokenType::UpArrow => "^",
+
+            TokenType::GreaterGreater => ">>",
+            TokenType::GreaterGreaterGreater => ">>>",
+            TokenType::LessLess => "<<",
+
+            TokenType::Identifier => return write!(f, "identifier"),
+            TokenType::IntLiteral => return write!(f, "integer literal"),
+            TokenType::CharLiteral => return write!(f, "character literal"),
+            TokenType::StringLiteral => return write!(f, "string literal"),
+            TokenType::ByteStringLiteral => return write!(f, "byte string literal"),
+            TokenType::Directive => return write!(f, "preprocessor directive"),
+            TokenType::EndOfFile => return write!(f, "end of file"),
+            TokenType::Error => return write!(f, "invalid token")
+        };
+
+        write!(f, "'{}'", text)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Token {
     pub kind: TokenType,
     pub pos: FilePosition,
-    pub data: String
+    text: TokenText
+}
+
+// The text is separate from the position, macro expansions keep the text of
+// the macro body but take the position of the use.
+#[derive(Debug, Clone)]
+enum TokenText {
+    Span(Rc<File>, Range<usize>),
+    // Error tokens carry their message instead of source text.
+    Message(Rc<str>),
+    // The end of file may be past the last byte.
+    Empty
+}
+
+impl Token {
+    // A token for the source text between `pos` and `end`.
+    pub fn new(kind: TokenType, pos: FilePosition, end: usize) -> Token {
+        let span = pos.offset..end;
+
+        Token {
+            kind,
+            text: TokenText::Span(pos.file.clone(), span),
+            pos
+        }
+    }
+
+    pub fn end_of_file(pos: FilePosition) -> Token {
+        Token {
+            kind: TokenType::EndOfFile,
+            pos,
+            text: TokenText::Empty
+        }
+    }
+
+    pub fn error(pos: FilePosition, msg: &str) -> Token {
+        Token {
+            kind: TokenType::Error,
+            pos,
+            text: TokenText::Message(Rc::from(msg))
+        }
+    }
+
+    // The source text of the token, or the message of an error token.
+    pub fn text(&self) -> Cow<'_, str> {
+        match &self.text {
+            TokenText::Span(file, span) => String::from_utf8_lossy(&file.data[span.clone()]),
+            TokenText::Message(msg) => Cow::Borrowed(msg),
+            TokenText::Empty => Cow::Borrowed("")
+        }
+    }
 }
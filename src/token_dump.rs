@@ -0,0 +1,71 @@
+use crate::{preprocessor::Preprocessor, token::*};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DumpFormat {
+    Plain,
+    Json
+}
+
+pub fn dump_tokens(preprocessor: &mut Preprocessor, format: DumpFormat) {
+    let mut first = true;
+
+    if format == DumpFormat::Json {
+        println!("[");
+    }
+
+    loop {
+        let token = preprocessor.next_token();
+
+        match format {
+            DumpFormat::Plain => {
+                println!("{}:{}: {:?} '{}'", token.pos.file.path, token.pos.line, token.kind, token.text());
+            },
+
+            DumpFormat::Json => {
+                if !first {
+                    println!(",");
+                }
+
+                // Error tokens carry a message instead of source text, so they span nothing.
+                let end = if token.kind == TokenType::Error {
+                    token.pos.offset
+                } else {
+                    token.pos.offset + token.text().len()
+                };
+
+                print!("  {{\"kind\": \"{:?}\", \"file\": {}, \"line\": {}, \"start\": {}, \"end\": {}, \"text\": {}}}",
+                       token.kind, json_string(&token.pos.file.path), token.pos.line,
+                       token.pos.offset, end, json_string(&token.text()));
+            }
+        }
+
+        first = false;
+
+        if token.kind == TokenType::EndOfFile {
+            break;
+        }
+    }
+
+    if format == DumpFormat::Json {
+        println!("\n]");
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut res = String::from("\"");
+
+    for ch in text.chars() {
+        match ch {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => res.push(ch)
+        }
+    }
+
+    res.push('"');
+    res
+}
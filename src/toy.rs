@@ -0,0 +1,468 @@
+// Assembles a program into a flat binary for a toy stack machine, for people
+// writing emulators. It is not a real ISA and has nothing to do with x86.
+//
+// The image is loaded at address 0. It starts with the magic `BTOY` and the
+// address of the entry function, followed by the code of all functions and
+// then the data. Words are 32-bit little-endian, immediates too.
+//
+// The machine has a stack pointer growing down, a frame pointer and a result
+// register. Execution begins with a call of the entry function with no
+// arguments, its result is the exit code.
+//
+//   01 imm   PUSH       push imm
+//   02 off   LEA        push fp + off
+//   03       DUP
+//   04       POP
+//   05       LOAD       pop address, push the word there
+//   06       STORE      pop value, pop address, store, push value
+//   07       LOADB      like LOAD for a byte, zero-extended
+//   08       STOREB     like STORE for a byte, pushes the stored byte zero-extended
+//   10..12   NEG, NOT, LNOT
+//   20..30   binary: pop right, pop left, push the result. ADD SUB MUL DIV MOD
+//            AND OR XOR SHL SAR SHR EQ NE LT LE GT GE, comparisons push 0 or 1
+//   40 addr  JMP
+//   41 addr  JZ         pop, jump if zero
+//   42 addr  JNZ        pop, jump if not zero
+//   50       CALL       pop callee address, push return address, jump
+//   51 addr  CALLD      push return address, jump
+//   52 n     RESULT     drop n words of arguments, push the result register
+//   53 size  ENTER      push fp, fp = sp, sp -= size
+//   54       RET        pop into the result register, sp = fp, pop fp, pop pc
+//
+// Parameters are at fp + 8 and up, locals below fp.
+
+use crate::program::*;
+use std::collections::HashMap;
+
+const MAGIC: &[u8] = b"BTOY";
+const HEADER_SIZE: usize = 8;
+
+enum Fixup {
+    Label(usize),
+    Symbol(String)
+}
+
+struct Assembler {
+    out: Vec<u8>,
+    labels: HashMap<usize, u32>,
+    symbols: HashMap<String, u32>,
+    fixups: Vec<(usize, Fixup)>
+}
+
+pub fn assemble(program: &Program, entry: &str) -> Result<Vec<u8>, String> {
+    let mut asm = Assembler {
+        out: Vec::from(MAGIC),
+        labels: HashMap::new(),
+        symbols: HashMap::new(),
+        fixups: Vec::new()
+    };
+
+    asm.out.resize(HEADER_SIZE, 0);
+    asm.fixups.push((MAGIC.len(), Fixup::Symbol(String::from(entry))));
+
+    for function in &program.functions {
+        asm.define(&function.name);
+        asm.op(0x53);
+        asm.word(function.frame_size as u32);
+
+        for instruction in &function.code {
+            asm.instruction(instruction)?;
+        }
+    }
+
+    for data in &program.data {
+        asm.define(&data.label);
+
+        for value in &data.values {
+            match value {
+                DataValue::Word(value) => asm.word(*value as u32),
+                DataValue::Address(label) => asm.fixup(Fixup::Symbol(label.clone())),
+                DataValue::Bytes(bytes) => asm.out.extend_from_slice(bytes)
+            }
+        }
+    }
+
+    asm.resolve()?;
+    Ok(asm.out)
+}
+
+impl Assembler {
+    fn instruction(&mut self, instruction: &Instruction) -> Result<(), String> {
+        match instruction {
+            Instruction::Line(_) => {},
+
+            Instruction::Push(value) => {
+                self.op(0x01);
+                self.word(*value as u32);
+            },
+
+            Instruction::PushAddress(label) => {
+                self.op(0x01);
+                self.fixup(Fixup::Symbol(label.clone()));
+            },
+
+            Instruction::PushLocalAddress(offset) => {
+                self.op(0x02);
+                self.word(*offset as u32);
+            },
+
+            Instruction::Dup => self.op(0x03),
+            Instruction::Pop => self.op(0x04),
+            Instruction::Load => self.op(0x05),
+            Instruction::Store => self.op(0x06),
+            Instruction::LoadByte => self.op(0x07),
+            Instruction::StoreByte => self.op(0x08),
+
+            Instruction::Unary(op) => self.op(0x10 + *op as u8),
+            Instruction::Binary(op) => self.op(0x20 + *op as u8),
+
+            Instruction::Label(label) => {
+                self.labels.insert(*label, self.out.len() as u32);
+            },
+
+            Instruction::Jump(label) => self.jump(0x40, *label),
+            Instruction::JumpIfZero(label) => self.jump(0x41, *label),
+            Instruction::JumpIfNotZero(label) => self.jump(0x42, *label),
+
+            Instruction::Call(args) => {
+                self.op(0x50);
+                self.op(0x52);
+                self.word(*args as u32);
+            },
+
+            Instruction::CallDirect(name, args) => {
+                self.op(0x51);
+                self.fixup(Fixup::Symbol(name.clone()));
+                self.op(0x52);
+                self.word(*args as u32);
+            },
+
+            Instruction::Return => self.op(0x54),
+
+            Instruction::Asm(_) => return Err(String::from("inline assembly can't be assembled for the toy machine"))
+        }
+
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str) {
+        self.symbols.insert(String::from(name), self.out.len() as u32);
+    }
+
+    fn op(&mut self, op: u8) {
+        self.out.push(op);
+    }
+
+    fn word(&mut self, word: u32) {
+        self.out.extend_from_slice(&word.to_le_bytes());
+    }
+
+    fn jump(&mut self, op: u8, label: usize) {
+        self.op(op);
+        self.fixup(Fixup::Label(label));
+    }
+
+    // Leaves room for an address known only at the end.
+    fn fixup(&mut self, fixup: Fixup) {
+        self.fixups.push((self.out.len(), fixup));
+        self.word(0);
+    }
+
+    fn resolve(&mut self) -> Result<(), String> {
+        for (offset, fixup) in &self.fixups {
+            let address = match fixup {
+                Fixup::Label(label) => *self.labels.get(label).expect("compiler defines every label it jumps to"),
+                Fixup::Symbol(name) => match self.symbols.get(name) {
+                    Some(address) => *address,
+                    None => return Err(format!("undefined symbol '{}'", name))
+                }
+            };
+
+            self.out[*offset..*offset + 4].copy_from_slice(&address.to_le_bytes());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{file::File, parser::{Dialect, Parser}, preprocessor::Preprocessor, scanner::Scanner, simple_compiler::Compiler};
+    use std::rc::Rc;
+
+    const STACK_SIZE: usize = 1 << 16;
+    // Pushed as the return address of the entry function, returning there halts.
+    const HALT: u32 = u32::MAX;
+
+    // A reference implementation of the machine described at the top of the file.
+    struct Machine {
+        memory: Vec<u8>,
+        pc: u32,
+        sp: u32,
+        fp: u32,
+        result: u32
+    }
+
+    impl Machine {
+        fn load(image: &[u8]) -> Machine {
+            let mut memory = image.to_vec();
+            memory.resize(image.len() + STACK_SIZE, 0);
+
+            let mut machine = Machine { sp: memory.len() as u32, memory, pc: 0, fp: 0, result: 0 };
+            machine.pc = machine.read(MAGIC.len() as u32);
+            machine.push(HALT);
+            machine
+        }
+
+        fn read(&self, address: u32) -> u32 {
+            let address = address as usize;
+            u32::from_le_bytes(self.memory[address..address + 4].try_into().unwrap())
+        }
+
+        fn write(&mut self, address: u32, value: u32) {
+            let address = address as usize;
+            self.memory[address..address + 4].copy_from_slice(&value.to_le_bytes());
+        }
+
+        fn push(&mut self, value: u32) {
+            self.sp -= 4;
+            self.write(self.sp, value);
+        }
+
+        fn pop(&mut self) -> u32 {
+            let value = self.read(self.sp);
+            self.sp += 4;
+            value
+        }
+
+        fn fetch_op(&mut self) -> u8 {
+            self.pc += 1;
+            self.memory[self.pc as usize - 1]
+        }
+
+        fn fetch_word(&mut self) -> u32 {
+            self.pc += 4;
+            self.read(self.pc - 4)
+        }
+
+        fn run(&mut self) -> Result<i32, String> {
+            while self.pc != HALT {
+                let op = self.fetch_op();
+
+                match op {
+                    0x01 => {
+                        let value = self.fetch_word();
+                        self.push(value);
+                    },
+                    0x02 => {
+                        let address = self.fp.wrapping_add(self.fetch_word());
+                        self.push(address);
+                    },
+                    0x03 => {
+                        let value = self.read(self.sp);
+                        self.push(value);
+                    },
+                    0x04 => {
+                        self.pop();
+                    },
+                    0x05 => {
+                        let address = self.pop();
+                        let value = self.read(address);
+                        self.push(value);
+                    },
+                    0x06 => {
+                        let value = self.pop();
+                        let address = self.pop();
+                        self.write(address, value);
+                        self.push(value);
+                    },
+                    0x07 => {
+                        let address = self.pop();
+                        let value = self.memory[address as usize];
+                        self.push(u32::from(value));
+                    },
+                    0x08 => {
+                        let value = self.pop() as u8;
+                        let address = self.pop();
+                        self.memory[address as usize] = value;
+                        self.push(u32::from(value));
+                    },
+
+                    0x10 => {
+                        let value = self.pop();
+                        self.push(value.wrapping_neg());
+                    },
+                    0x11 => {
+                        let value = self.pop();
+                        self.push(!value);
+                    },
+                    0x12 => {
+                        let value = self.pop();
+                        self.push(u32::from(value == 0));
+                    },
+
+                    0x20..=0x30 => {
+                        let right = self.pop() as i32;
+                        let left = self.pop() as i32;
+                        let value = binary(op, left, right)?;
+                        self.push(value as u32);
+                    },
+
+                    0x40 => self.pc = self.fetch_word(),
+                    0x41 | 0x42 => {
+                        let target = self.fetch_word();
+                        if (self.pop() == 0) == (op == 0x41) {
+                            self.pc = target;
+                        }
+                    },
+
+                    0x50 => {
+                        let callee = self.pop();
+                        self.push(self.pc);
+                        self.pc = callee;
+                    },
+                    0x51 => {
+                        let callee = self.fetch_word();
+                        self.push(self.pc);
+                        self.pc = callee;
+                    },
+                    0x52 => {
+                        self.sp += 4 * self.fetch_word();
+                        self.push(self.result);
+                    },
+                    0x53 => {
+                        let size = self.fetch_word();
+                        self.push(self.fp);
+                        self.fp = self.sp;
+                        self.sp -= size;
+                    },
+                    0x54 => {
+                        self.result = self.pop();
+                        self.sp = self.fp;
+                        self.fp = self.pop();
+                        self.pc = self.pop();
+                    },
+
+                    _ => return Err(format!("invalid opcode {:#04x} at {}", op, self.pc - 1))
+                }
+            }
+
+            Ok(self.result as i32)
+        }
+    }
+
+    fn binary(op: u8, left: i32, right: i32) -> Result<i32, String> {
+        if matches!(op, 0x23 | 0x24) && right == 0 {
+            return Err(String::from("division by zero"));
+        }
+
+        let value = match op {
+            0x20 => left.wrapping_add(right),
+            0x21 => left.wrapping_sub(right),
+            0x22 => left.wrapping_mul(right),
+            0x23 => left.wrapping_div(right),
+            0x24 => left.wrapping_rem(right),
+            0x25 => left & right,
+            0x26 => left | right,
+            0x27 => left ^ right,
+            0x28 => left.wrapping_shl(right as u32),
+            0x29 => left.wrapping_shr(right as u32),
+            0x2a => (left as u32).wrapping_shr(right as u32) as i32,
+            0x2b => i32::from(left == right),
+            0x2c => i32::from(left != right),
+            0x2d => i32::from(left < right),
+            0x2e => i32::from(left <= right),
+            0x2f => i32::from(left > right),
+            _ => i32::from(left >= right)
+        };
+
+        Ok(value)
+    }
+
+    fn compile(source: &str) -> Vec<u8> {
+        let file = Rc::new(File {
+            path: String::from("test.b"),
+            data: source.as_bytes().to_vec()
+        });
+
+        let mut preprocessor = Preprocessor::new(Scanner::new(file), Vec::new());
+        let mut parser = Parser::new(&mut preprocessor, Dialect::default());
+        let mut compiler = Compiler::new(4, false);
+
+        while !parser.is_at_end() {
+            compiler.compile_one_decl(&parser.parse_one_decl().expect("test programs parse"));
+        }
+
+        compiler.resolve_initializers();
+        compiler.get_binary("main").unwrap()
+    }
+
+    #[test]
+    fn trivial_function_assembles_to_known_bytes() {
+        let mut program = Program::new(4);
+        program.functions.push(Function {
+            name: String::from("main"),
+            frame_size: 0,
+            register_params: 0,
+            code: vec![Instruction::Push(42), Instruction::Return]
+        });
+        program.data.push(Data { label: String::from("x"), global: true, values: vec![DataValue::Address(String::from("main"))] });
+
+        let expected = [
+            b'B', b'T', b'O', b'Y', 8, 0, 0, 0,
+            0x53, 0, 0, 0, 0,
+            0x01, 42, 0, 0, 0,
+            0x54,
+            8, 0, 0, 0
+        ];
+
+        assert_eq!(assemble(&program, "main").unwrap(), expected);
+        assert_eq!(Machine::load(&expected).run(), Ok(42));
+    }
+
+    #[test]
+    fn compiled_programs_run_on_the_machine() {
+        let source = "
+v[3] 1, 2, 3;
+s \"ab\";
+sum(a, n) {
+    auto i 0;
+    auto total 0;
+    while (i < n)
+        total = total + a[i++];
+    return total;
+}
+main() {
+    extern v;
+    extern s;
+    extern sum;
+    extern byte;
+    auto f;
+    f = &sum;
+    if (-7 / 2 != -3 | -7 % 2 != -1 | (-8 >> 1) != -4 | !5 | ~0 != -1)
+        return 1;
+    return f(v, 3) * 10 + (byte(s, 1) == 'b');
+}
+byte(s, i) {
+    return ((s[i / 4] >> (i % 4) * 8) & 0377);
+}
+";
+
+        assert_eq!(Machine::load(&compile(source)).run(), Ok(61));
+    }
+
+    #[test]
+    fn division_by_zero_stops_the_machine() {
+        let image = compile("main() {\n    auto z 0;\n    return 1 / z;\n}\n");
+        assert_eq!(Machine::load(&image).run(), Err(String::from("division by zero")));
+    }
+
+    #[test]
+    fn undefined_symbols_are_errors() {
+        let mut program = Program::new(4);
+        program.data.push(Data { label: String::from("x"), global: true, values: vec![DataValue::Word(1)] });
+
+        assert_eq!(assemble(&program, "main"), Err(String::from("undefined symbol 'main'")));
+    }
+}
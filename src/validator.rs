@@ -1,59 +1,268 @@
-use crate::{ast::*, error_reporter::report_error, file::FilePosition};
+use std::collections::{HashMap, HashSet};
+
+use crate::{ast::*, error_reporter::{report, Diagnostic, ErrorKind, Severity, WarningKind}, file::FilePosition, folder::{is_literal_builtin, LEN_BUILTIN}, token::TokenType};
+
+#[derive(Clone)]
+pub struct Warnings {
+    pub dangling_else: bool,
+    pub identical_branches: bool,
+    pub unused_ternary: bool,
+    pub write_strings: bool,
+    // Functions with more parameters are reported.
+    pub max_params: Option<usize>,
+    // Off when the missing definitions come from other objects at link time.
+    pub undefined_extern: bool,
+    // Filtered out by the reporter, the checks still run. `-w` drops them all.
+    pub quiet: bool,
+    pub disabled: Vec<WarningKind>
+}
+
+impl Default for Warnings {
+    fn default() -> Self {
+        Warnings {
+            dangling_else: false,
+            identical_branches: false,
+            unused_ternary: true,
+            write_strings: true,
+            max_params: None,
+            undefined_extern: true,
+            quiet: false,
+            disabled: Vec::new()
+        }
+    }
+}
+
+pub enum GlobalKind {
+    Function { arity: usize },
+    Data { size: usize },
+    Vector { size: usize }
+}
+
+pub struct Global {
+    pub name: String,
+    pub kind: GlobalKind,
+    pub pos: FilePosition
+}
+
+// Where a name used in an expression was declared.
+enum Binding {
+    // Position of the `auto` or of the function for a parameter.
+    Local(FilePosition),
+    // Found by name when asked, the global may be defined after the use.
+    Global(String)
+}
+
+struct Reference {
+    pos: FilePosition,
+    len: usize,
+    binding: Binding
+}
+
+// A global initialized with the value of another global.
+struct InitializerRef {
+    owner: String,
+    index: usize,
+    target: String,
+    pos: FilePosition
+}
 
 pub struct Validator {
-    had_error: bool,
-    global_data: Vec<String>,
-    local_data: Vec<String>,
+    warnings: Warnings,
+    // In bytes, shifts by as many bits or more are out of range.
+    word_size: usize,
+    diagnostics: Vec<Diagnostic>,
+    global_data: Vec<Global>,
+    // Index in `global_data` by name, huge programs have many globals.
+    global_index: HashMap<String, usize>,
+    // Globals initialized with a string literal. The global can be assigned,
+    // the literal it points to is read-only.
+    string_globals: HashSet<String>,
+    // Locals of the current function and where they are declared.
+    local_data: Vec<(String, FilePosition)>,
+    // The locals of the current function that are `extern`s.
+    local_externs: Vec<String>,
+    // Sizes of the vectors visible in the current function.
+    vector_sizes: Vec<(String, usize)>,
+    initializer_refs: Vec<InitializerRef>,
+    // First `extern` of every name, checked against the globals at the end.
+    externs: Vec<(String, FilePosition)>,
+    extern_names: HashSet<String>,
+    // Caller and callee of every call by name, in source order.
+    calls: Vec<(String, String)>,
+    // Every name used in an expression, for tools.
+    references: Vec<Reference>,
     loop_count: usize,
+    // Name of the function being validated.
+    function: Option<String>,
+    // Whether the first return of the current function had a value.
+    returns_value: Option<bool>,
+    mixed_returns: bool
 }
 
 impl Validator {
-    pub fn new() -> Self {
+    pub fn new(warnings: Warnings, word_size: usize) -> Self {
         Validator {
-            had_error: false,
+            warnings,
+            word_size,
+            diagnostics: Vec::new(),
             global_data: Vec::new(),
+            global_index: HashMap::new(),
+            string_globals: HashSet::new(),
             local_data: Vec::new(),
-            loop_count: 0
+            local_externs: Vec::new(),
+            vector_sizes: Vec::new(),
+            initializer_refs: Vec::new(),
+            externs: Vec::new(),
+            extern_names: HashSet::new(),
+            calls: Vec::new(),
+            references: Vec::new(),
+            loop_count: 0,
+            function: None,
+            returns_value: None,
+            mixed_returns: false
         }
     }
 
+    // Reports the diagnostics, returns false if there were errors.
     pub fn validate_one_decl(&mut self, decl: &Decl) -> bool {
+        self.validate_decl(decl);
+        self.report_diagnostics()
+    }
+
+    // Checks what needs all the declarations, called after the last one.
+    pub fn finish(&mut self) -> bool {
+        self.check_initializers();
+        self.check_externs();
+        self.report_diagnostics()
+    }
+
+    // Declarations are validated in order, later ones see the globals of the
+    // earlier ones. For library users, the compiler validates one by one.
+    pub fn validate(&mut self, decls: &[Decl]) -> Vec<Diagnostic> {
+        for decl in decls {
+            self.validate_decl(decl);
+        }
+
+        self.check_initializers();
+        self.check_externs();
+        std::mem::take(&mut self.diagnostics)
+    }
+
+    fn report_diagnostics(&mut self) -> bool {
+        let diagnostics = std::mem::take(&mut self.diagnostics);
+
+        for diagnostic in &diagnostics {
+            report(diagnostic);
+        }
+
+        !diagnostics.iter().any(|diagnostic| matches!(diagnostic.severity, Severity::Error(_)))
+    }
+
+    fn validate_decl(&mut self, decl: &Decl) {
         match &decl.kind {
             DeclKind::External(var) => {
-                self.add_global(&var.0, &decl.pos);
-                self.validate_iexpr(&var.1);
+                let size = self.validate_var(var, decl.span(), true);
+
+                let kind = match var.size {
+                    VariableSize::Scalar => GlobalKind::Data { size },
+                    VariableSize::Vector(_) => GlobalKind::Vector { size }
+                };
+
+                if let (VariableSize::Scalar, [Expr { kind: ExprKind::StringLit(..), .. }]) = (&var.size, var.initial.as_slice()) {
+                    self.string_globals.insert(var.name.clone());
+                }
+
+                self.add_global(&var.name, kind, decl.span());
             },
 
             DeclKind::Function { name, params, body } => {
-                self.add_global(&name, &decl.pos);
+                self.add_global(name, GlobalKind::Function { arity: params.len() }, decl.span());
+
+                if let Some(max) = self.warnings.max_params.filter(|&max| params.len() > max) {
+                    self.warning(WarningKind::TooManyParams, decl.span(), &format!("function '{}' has {} parameters, more than {}", name, params.len(), max));
+                }
+
+                self.function = Some(name.clone());
 
                 for param in params {
-                    self.add_local(param, &decl.pos);
+                    self.add_local(param, decl.span());
+                    self.check_shadowed_function(param, decl.span());
                 }
 
                 self.validate_stmt(body);
             }
         }
 
-        let old_error = self.had_error;
         self.clear();
-        old_error
+    }
+
+    pub fn globals(&self) -> &[Global] {
+        &self.global_data
+    }
+
+    // Calls of globals by name. The callee may be data holding a function address.
+    pub fn calls(&self) -> &[(String, String)] {
+        &self.calls
+    }
+
+    // Declaration of the name used at the byte offset of a file, for
+    // go-to-definition. Undefined names and undefined externs have none.
+    pub fn definition_at(&self, path: &str, offset: usize) -> Option<&FilePosition> {
+        let reference = self.references.iter().find(|reference| {
+            reference.pos.file.path == path && (reference.pos.offset..reference.pos.offset + reference.len).contains(&offset)
+        })?;
+
+        match &reference.binding {
+            Binding::Local(pos) => Some(pos),
+            Binding::Global(name) => self.global(name).map(|global| &global.pos)
+        }
     }
 
     fn validate_stmt(&mut self, stmt: &Stmt) {
         match &stmt.kind {
-            StmtKind::Auto(var) => {
-                // The scope of a variable begins with its declaration
-                // and ends with the end of the function where it was delared.
-                self.add_local(&var.0, &stmt.pos);
-                self.validate_iexpr(&var.1);
+            // The scope of a variable begins with its declaration
+            // and ends with the end of the function where it was delared.
+            StmtKind::Auto(vars) => for var in vars {
+                self.add_local(&var.name, stmt.span());
+                self.check_shadowed_function(&var.name, stmt.span());
+                let size = self.validate_var(var, stmt.span(), false);
+
+                match var.size {
+                    VariableSize::Scalar => if var.initial.len() > 1 {
+                        self.error(ErrorKind::TooManyInitializers, stmt.span(), "too many initializers for scalar local");
+                    },
+
+                    // The size of a string buffer depends on the word size.
+                    VariableSize::Vector(_) if var.string_buffer().is_some() => {},
+                    VariableSize::Vector(_) => self.vector_sizes.push((var.name.clone(), size))
+                }
             },
 
             StmtKind::Extern(name) => {
-                self.add_local(name, &stmt.pos)
+                self.add_local(name, stmt.span());
+                self.local_externs.push(name.clone());
+
+                if self.extern_names.insert(name.clone()) {
+                    self.externs.push((name.clone(), stmt.span().clone()));
+                }
+
+                let size = self.global(name).and_then(|global| match global.kind {
+                    GlobalKind::Vector { size } => Some(size),
+                    _ => None
+                });
+
+                if let Some(size) = size {
+                    self.vector_sizes.push((name.clone(), size));
+                }
             },
 
             StmtKind::Expr(expr) => {
+                if let ExprKind::Ternary(_, then_arm, else_arm) = &expr.kind {
+                    if self.warnings.unused_ternary && !has_side_effects(then_arm) && !has_side_effects(else_arm) {
+                        self.warning(WarningKind::Unused, expr.span(), "result of conditional expression is unused");
+                    }
+                }
+
                 self.validate_expr(expr)
             }
 
@@ -63,21 +272,31 @@ impl Validator {
                 }
             },
 
-            StmtKind::Break => {
+            StmtKind::Break(levels) => {
                 if self.loop_count == 0 {
-                    self.error(&stmt.pos, "break statement appeared outside of loop");
+                    self.error(ErrorKind::LoopControl, stmt.span(), "break statement appeared outside of loop");
+                } else if *levels > self.loop_count {
+                    self.error(ErrorKind::LoopControl, stmt.span(), &format!("break {} exits more loops than the {} enclosing it", levels, self.loop_count));
                 }
             },
 
-            StmtKind::Continue => {
+            StmtKind::Continue(levels) => {
                 if self.loop_count == 0 {
-                    self.error(&stmt.pos, "continue statement appeared outside of loop");
+                    self.error(ErrorKind::LoopControl, stmt.span(), "continue statement appeared outside of loop");
+                } else if *levels > self.loop_count {
+                    self.error(ErrorKind::LoopControl, stmt.span(), &format!("continue {} exits more loops than the {} enclosing it", levels, self.loop_count));
                 }
             },
 
             StmtKind::While(cond, body) => {
                 self.validate_expr(cond);
 
+                // `while (1)` is an intentional infinite loop, but the body
+                // of `while (0)` never runs.
+                if let ExprKind::IntLit(0, _) = cond.kind {
+                    self.warning(WarningKind::ConstantCondition, cond.span(), "loop condition is always false");
+                }
+
                 self.loop_count += 1;
 
                 if let Some(body) = body {
@@ -98,6 +317,18 @@ impl Validator {
             StmtKind::If(cond, then_arm, else_arm) => {
                 self.validate_expr(cond);
 
+                if self.warnings.dangling_else && else_arm.is_none() {
+                    if let StmtKind::If(_, _, Some(_)) = then_arm.kind {
+                        self.warning(WarningKind::DanglingElse, then_arm.span(), "add braces around nested 'if' to make the 'else' binding explicit");
+                    }
+                }
+
+                if let Some(else_arm) = else_arm {
+                    if self.warnings.identical_branches && then_arm.same_shape(else_arm) {
+                        self.warning(WarningKind::IdenticalBranches, else_arm.span(), "both branches of 'if' are identical");
+                    }
+                }
+
                 self.validate_stmt(then_arm);
 
                 if let Some(else_arm) = else_arm {
@@ -109,26 +340,81 @@ impl Validator {
                 if let Some(expr) = expr {
                     self.validate_expr(expr);
                 }
-            }
+
+                // There is no void in B, but `return;` next to `return x;` is
+                // likely a mistake.
+                match self.returns_value {
+                    None => self.returns_value = Some(expr.is_some()),
+
+                    Some(returns_value) if returns_value != expr.is_some() && !self.mixed_returns => {
+                        self.mixed_returns = true;
+                        self.warning(WarningKind::MixedReturns, stmt.span(), "function mixes 'return' with and without a value");
+                    },
+
+                    Some(_) => {}
+                }
+            },
+
+            StmtKind::Asm(_) | StmtKind::Empty => {}
         }
     }
 
     fn validate_expr(&mut self, expr: &Expr) {
         match &expr.kind {
-            ExprKind::IntLit(_) => {},
-            ExprKind::StringLit(_) => {},
+            ExprKind::IntLit(..) => {},
+            ExprKind::StringLit(..) => {},
+
+            // Left unfolded only as an lvalue, which is already reported.
+            ExprKind::Var(name) if is_literal_builtin(name) => {},
 
             ExprKind::Var(name) => {
-                if !self.local_data.contains(name) {
-                    self.error(&expr.pos, &format!("undefinded reference to '{}'", name));
+                let binding = if self.local_externs.contains(name) {
+                    Binding::Global(name.clone())
+                } else if let Some(pos) = self.local(name) {
+                    Binding::Local(pos.clone())
+                } else {
+                    self.error(ErrorKind::UndefinedReference, expr.span(), &format!("undefinded reference to '{}'", name));
+                    return;
+                };
+
+                self.references.push(Reference { pos: expr.span().clone(), len: name.len(), binding });
+            },
+
+            ExprKind::UnaryOp(op, _, operand) => {
+                if matches!(op, TokenType::Ampersand | TokenType::PlusPlus | TokenType::MinusMinus) {
+                    self.check_lvalue(operand);
                 }
+
+                if matches!(op, TokenType::PlusPlus | TokenType::MinusMinus) {
+                    self.check_read_only(operand);
+                }
+
+                self.validate_expr(operand)
             },
 
-            ExprKind::UnaryOp(_, _, expr) => {
-                self.validate_expr(expr)
+            ExprKind::Assign(target, value) => {
+                self.check_lvalue(target);
+                self.check_read_only(target);
+                self.validate_expr(target);
+                self.validate_expr(value);
+            },
+
+            ExprKind::Index(base, index) => {
+                self.check_constant_index(expr.span(), base, index);
+                self.check_index_offset(base, index);
+                self.validate_expr(base);
+                self.validate_expr(index);
             },
 
-            ExprKind::BinOp(left, _, right) => {
+            ExprKind::BinOp(left, op, right) => {
+                if matches!(op, TokenType::LessLess | TokenType::GreaterGreater | TokenType::GreaterGreaterGreater) {
+                    if let ExprKind::IntLit(amount, _) = right.kind {
+                        if !(0..(self.word_size * 8) as i32).contains(&amount) {
+                            self.warning(WarningKind::ShiftCount, right.span(), "shift amount out of range");
+                        }
+                    }
+                }
+
                 self.validate_expr(left);
                 self.validate_expr(right);
             },
@@ -137,50 +423,413 @@ impl Validator {
                 self.validate_expr(cond);
                 self.validate_expr(then_arm);
                 self.validate_expr(else_arm);
+            },
+
+            ExprKind::Call(callee, args) => {
+                match &callee.kind {
+                    // Well-formed builtin calls are already folded to literals.
+                    ExprKind::Var(name) if name == LEN_BUILTIN => {
+                        self.error(ErrorKind::BuiltinArgument, expr.span(), &format!("'{}' expects a single string literal argument", name));
+                    },
+
+                    ExprKind::Var(name) => {
+                        if let (Some(function), true) = (&self.function, self.local_externs.contains(name)) {
+                            self.calls.push((function.clone(), name.clone()));
+                        }
+
+                        self.validate_expr(callee)
+                    },
+
+                    _ => self.validate_expr(callee)
+                }
+
+                for arg in args {
+                    self.validate_expr(arg);
+                }
+            }
+        }
+    }
+
+    fn check_lvalue(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Var(name) if is_literal_builtin(name) => {
+                self.error(ErrorKind::NotLvalue, expr.span(), &format!("'{}' is not an lvalue", name));
+            },
+
+            ExprKind::Var(_) | ExprKind::Index(..) | ExprKind::UnaryOp(TokenType::Star, Fixity::Prefix, _) => {},
+            _ => self.error(ErrorKind::NotLvalue, expr.span(), "expression is not an lvalue")
+        }
+    }
+
+    // Writes through a string literal, or through a global that still points
+    // to the one it was initialized with as far as we know.
+    fn check_read_only(&mut self, target: &Expr) {
+        let base = match &target.kind {
+            ExprKind::Index(base, _) | ExprKind::UnaryOp(TokenType::Star, Fixity::Prefix, base) => base,
+            _ => return
+        };
+
+        if !self.warnings.write_strings {
+            return;
+        }
+
+        match &base.kind {
+            ExprKind::StringLit(..) => self.warning(WarningKind::WriteStrings, target.span(), "writing into a string literal"),
+
+            ExprKind::Var(name) if self.local_externs.contains(name) && self.string_globals.contains(name) => {
+                self.warning(WarningKind::WriteStrings, target.span(), &format!("writing into the string literal of '{}'", name));
+            },
+
+            _ => {}
+        }
+    }
+
+    // A constant index is scaled by the word size at run time, which wraps if
+    // the offset doesn't fit in a word. `2[v]` has the index on the left.
+    fn check_index_offset(&mut self, base: &Expr, index: &Expr) {
+        let index = if let ExprKind::IntLit(..) = base.kind { base } else { index };
+
+        let ExprKind::IntLit(value, _) = index.kind else {
+            return;
+        };
+
+        let bits = self.word_size * 8;
+        let offset = value as i128 * self.word_size as i128;
+
+        if offset < -(1 << (bits - 1)) || offset >= 1 << (bits - 1) {
+            self.warning(WarningKind::IndexOverflow, index.span(), &format!("offset of index {} overflows a {}-bit word", value, bits));
+        }
+    }
+
+    fn check_constant_index(&mut self, pos: &FilePosition, base: &Expr, index: &Expr) {
+        let (base, index) = index_operands(base, index, |name| self.vector_sizes.iter().any(|(vector, _)| vector == name));
+
+        if let (ExprKind::Var(name), ExprKind::IntLit(index, _)) = (&base.kind, &index.kind) {
+            let size = self.vector_sizes.iter()
+                .find(|(vector, _)| vector == name)
+                .map(|(_, size)| *size);
+
+            if let Some(size) = size {
+                if *index < 0 || *index as usize >= size {
+                    self.warning(WarningKind::ArrayBounds, pos, &format!("array index out of bounds for '{}' of size {}", name, size));
+                }
             }
         }
     }
 
-    fn validate_iexpr(&mut self, expr: &Option<Expr>) {
-        match expr {
-            None => {},
-            Some(e) => match e.kind {
-                ExprKind::IntLit(_) => {},
-                ExprKind::StringLit(_) => {},
+    // Returns the number of words the variable occupies.
+    fn validate_var(&mut self, var: &Variable, pos: &FilePosition, global: bool) -> usize {
+        // Locals are initialized at run time, with any expression.
+        for (i, expr) in var.initial.iter().enumerate() {
+            if global {
+                self.validate_global_iexpr(&var.name, i, expr);
+            } else {
+                self.validate_expr(expr);
+            }
+        }
+
+        match &var.size {
+            VariableSize::Scalar => var.initial.len().max(1),
+
+            VariableSize::Vector(None) => {
+                if var.initial.is_empty() {
+                    self.error(ErrorKind::ArraySize, pos, "array without size must have initializers");
+                }
+
+                var.initial.len()
+            },
+
+            VariableSize::Vector(Some(size)) => match size.kind {
+                ExprKind::IntLit(size, _) if size >= 0 => {
+                    if var.initial.len() > size as usize {
+                        self.error(ErrorKind::TooManyInitializers, pos, "too many initializers for array");
+                    }
+
+                    size as usize
+                },
 
                 _ => {
-                    self.error(&e.pos, "only integer or string literals are allowed to be iexpr");
+                    self.error(ErrorKind::ArraySize, size.span(), "array size must be a non-negative integer literal");
+                    0
                 }
             }
         }
     }
 
-    fn add_global(&mut self, name: &String, err_pos: &FilePosition) {
+    // Initializers are folded before validation, so `1 << 4` is a literal here.
+    fn validate_iexpr(&mut self, expr: &Expr) {
+        match expr.kind {
+            ExprKind::IntLit(..) => {},
+            ExprKind::StringLit(..) => {},
+
+            _ => {
+                self.error(ErrorKind::NonConstantInitializer, expr.span(), "initializer is not a constant");
+            }
+        }
+    }
+
+    // Globals may also be initialized with the address or the value of another
+    // global, values are checked once all globals are known.
+    fn validate_global_iexpr(&mut self, owner: &str, index: usize, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::UnaryOp(TokenType::Ampersand, Fixity::Prefix, operand) if matches!(operand.kind, ExprKind::Var(_)) => {},
+
+            ExprKind::Var(target) => self.initializer_refs.push(InitializerRef {
+                owner: String::from(owner),
+                index,
+                target: target.clone(),
+                pos: expr.span().clone()
+            }),
+
+            _ => self.validate_iexpr(expr)
+        }
+    }
+
+    // A value reference takes the first word of the target, which may itself be
+    // a reference. Addresses don't depend on values, so only these chains can
+    // form a cycle.
+    fn check_initializers(&mut self) {
+        let refs = std::mem::take(&mut self.initializer_refs);
+
+        let first_ref = |name: &str| refs.iter()
+            .find(|r| r.owner == name && r.index == 0)
+            .map(|r| r.target.as_str());
+
+        for r in &refs {
+            match self.global(&r.target).map(|global| &global.kind) {
+                None => self.error(ErrorKind::UndefinedReference, &r.pos, &format!("undefinded reference to '{}'", r.target)),
+
+                Some(GlobalKind::Function { .. }) => {
+                    self.error(ErrorKind::NonConstantInitializer, &r.pos, &format!("initializer of '{}' uses the value of function '{}'", r.owner, r.target));
+                },
+
+                Some(_) => {
+                    let mut seen = Vec::new();
+                    let mut current = Some(r.target.as_str());
+
+                    while let Some(name) = current {
+                        if r.index == 0 && name == r.owner {
+                            self.error(ErrorKind::InitializerCycle, &r.pos, &format!("initializer of '{}' depends on its own value", r.owner));
+                            break;
+                        }
+
+                        if seen.contains(&name) {
+                            break;
+                        }
+
+                        seen.push(name);
+                        current = first_ref(name);
+                    }
+                }
+            }
+        }
+
+        self.initializer_refs = refs;
+    }
+
+    // Inside `f(f)` the name refers to the parameter, so the function can't be
+    // called recursively.
+    fn check_shadowed_function(&mut self, name: &str, pos: &FilePosition) {
+        if self.function.as_deref() == Some(name) {
+            self.warning(WarningKind::Shadow, pos, &format!("'{}' shadows the enclosing function", name));
+        }
+    }
+
+    // Runs once all globals are known, since an `extern` may precede the
+    // definition. Names linked from outside the program, like library
+    // functions, are what -Wno-undefined-extern is for.
+    fn check_externs(&mut self) {
+        if !self.warnings.undefined_extern {
+            return;
+        }
+
+        let externs = std::mem::take(&mut self.externs);
+
+        for (name, pos) in &externs {
+            if self.global(name).is_none() {
+                self.warning(WarningKind::UndefinedExtern, pos, &format!("'{}' is declared but never defined", name));
+            }
+        }
+
+        self.externs = externs;
+    }
+
+    fn add_global(&mut self, name: &String, kind: GlobalKind, err_pos: &FilePosition) {
         // Rust doesn't allow me to create function safe_push with mutable vector reference
         // to remove code duplication, because there will be two mutable references:
         // first - in add_global parameter, second - in safe_push parameter.
-        if self.global_data.contains(&name) {
-            self.error(err_pos, &format!("redefinition of global '{}'", name));
+        if self.global(name).is_some() {
+            self.error(ErrorKind::Redefinition, err_pos, &format!("redefinition of global '{}'", name));
         } else {
-            self.global_data.push(name.clone())
+            self.global_index.insert(name.clone(), self.global_data.len());
+            self.global_data.push(Global {
+                name: name.clone(),
+                kind,
+                pos: err_pos.clone()
+            })
         }
     }
 
+    fn global(&self, name: &str) -> Option<&Global> {
+        self.global_index.get(name).map(|&index| &self.global_data[index])
+    }
+
+    fn local(&self, name: &str) -> Option<&FilePosition> {
+        self.local_data.iter().find(|(local, _)| local == name).map(|(_, pos)| pos)
+    }
+
     fn add_local(&mut self, name: &String, err_pos: &FilePosition) {
-        if self.local_data.contains(&name) {
-            self.error(err_pos, &format!("redefinition of local '{}'", name));
+        if self.local(name).is_some() {
+            self.error(ErrorKind::Redefinition, err_pos, &format!("redefinition of local '{}'", name));
         } else {
-            self.local_data.push(name.clone())
+            self.local_data.push((name.clone(), err_pos.clone()))
         }
     }
 
     fn clear(&mut self) {
-        self.had_error = false;
-        self.local_data.clear()
+        self.function = None;
+        self.returns_value = None;
+        self.mixed_returns = false;
+        self.local_data.clear();
+        self.local_externs.clear();
+        self.vector_sizes.clear()
     }
 
-    fn error(&mut self, pos: &FilePosition, msg: &str) {
-        self.had_error = true;
-        report_error(pos, msg);
+    fn error(&mut self, kind: ErrorKind, pos: &FilePosition, msg: &str) {
+        self.diagnose(Severity::Error(kind), pos, msg);
+    }
+
+    fn warning(&mut self, kind: WarningKind, pos: &FilePosition, msg: &str) {
+        self.diagnose(Severity::Warning(kind), pos, msg);
+    }
+
+    fn diagnose(&mut self, severity: Severity, pos: &FilePosition, msg: &str) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            pos: pos.clone(),
+            msg: String::from(msg)
+        });
+    }
+}
+
+struct SideEffects(bool);
+
+impl Visitor for SideEffects {
+    fn visit_expr(&mut self, expr: &Expr) {
+        match expr.kind {
+            ExprKind::Assign(..) | ExprKind::Call(..) |
+            ExprKind::UnaryOp(TokenType::PlusPlus | TokenType::MinusMinus, ..) => self.0 = true,
+            _ => walk_expr(self, expr)
+        }
+    }
+}
+
+fn has_side_effects(expr: &Expr) -> bool {
+    let mut finder = SideEffects(false);
+    finder.visit_expr(expr);
+    finder.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{file::File, parser::{Dialect, Parser}, preprocessor::Preprocessor, scanner::Scanner};
+    use std::rc::Rc;
+
+    fn parse(source: &str) -> Vec<Decl> {
+        let file = Rc::new(File {
+            path: String::from("test.b"),
+            data: source.as_bytes().to_vec()
+        });
+
+        let mut preprocessor = Preprocessor::new(Scanner::new(file), Vec::new());
+        let mut parser = Parser::new(&mut preprocessor, Dialect::default());
+        let mut decls = Vec::new();
+
+        while !parser.is_at_end() {
+            decls.push(parser.parse_one_decl().expect("test programs parse"));
+        }
+
+        decls
+    }
+
+    #[test]
+    fn validate_returns_diagnostics() {
+        let decls = parse("main() {\n    while (0)\n        return x;\n}\n");
+        let diagnostics = Validator::new(Warnings::default(), 4).validate(&decls);
+
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics[0].severity == Severity::Warning(WarningKind::ConstantCondition) && diagnostics[0].pos.line == 2);
+        assert!(diagnostics[1].severity == Severity::Error(ErrorKind::UndefinedReference) && diagnostics[1].pos.line == 3);
+        assert!(diagnostics[1].msg.contains("'x'"), "{}", diagnostics[1].msg);
+    }
+
+    #[test]
+    fn later_declarations_see_earlier_globals() {
+        let decls = parse("x 1;\nmain() {\n    extern x;\n    return x;\n}\n");
+
+        assert!(Validator::new(Warnings::default(), 4).validate(&decls).is_empty());
+    }
+
+    #[test]
+    fn mixed_returns_warn_once_per_function() {
+        let decls = parse("f(x) {\n    if (x)\n        return;\n    if (x)\n        return 1;\n    return;\n}\ng(x) {\n    if (x)\n        return 1;\n    return 2;\n}\n");
+        let diagnostics = Validator::new(Warnings::default(), 4).validate(&decls);
+
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].severity == Severity::Warning(WarningKind::MixedReturns) && diagnostics[0].pos.line == 5);
+        assert_eq!(diagnostics[0].msg, "function mixes 'return' with and without a value");
+    }
+
+    #[test]
+    fn parameter_and_local_shadowing_the_function_warn() {
+        let decls = parse("f(f) {\n    return f;\n}\ng(x) {\n    auto g;\n    g = x;\n    return g;\n}\nh(x) {\n    return x;\n}\n");
+        let diagnostics = Validator::new(Warnings::default(), 4).validate(&decls);
+
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics[0].severity == Severity::Warning(WarningKind::Shadow) && diagnostics[0].pos.line == 1);
+        assert_eq!(diagnostics[0].msg, "'f' shadows the enclosing function");
+        assert!(diagnostics[1].severity == Severity::Warning(WarningKind::Shadow) && diagnostics[1].pos.line == 5);
+        assert_eq!(diagnostics[1].msg, "'g' shadows the enclosing function");
+    }
+
+    #[test]
+    fn extern_must_name_a_global_defined_anywhere() {
+        let decls = parse("main() {\n    extern before;\n    extern after;\n    extern missing;\n    return before + after;\n}\nbefore 1;\nafter 2;\n");
+        let diagnostics = Validator::new(Warnings::default(), 4).validate(&decls);
+
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].severity == Severity::Warning(WarningKind::UndefinedExtern) && diagnostics[0].pos.line == 4);
+        assert_eq!(diagnostics[0].msg, "'missing' is declared but never defined");
+
+        let warnings = Warnings { undefined_extern: false, ..Warnings::default() };
+        assert!(Validator::new(warnings, 4).validate(&decls).is_empty());
+    }
+
+    #[test]
+    fn unused_ternary_without_side_effects_warns() {
+        let decls = parse("f(x) {\n    x ? 1 : 2;\n    x ? x(1) : 2;\n    x ? 1 : (x = 2);\n    x ? x++ : 0;\n    return x ? 1 : 2;\n}\n");
+        let diagnostics = Validator::new(Warnings::default(), 4).validate(&decls);
+
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].severity == Severity::Warning(WarningKind::Unused) && diagnostics[0].pos.line == 2);
+        assert_eq!(diagnostics[0].msg, "result of conditional expression is unused");
+
+        let warnings = Warnings { unused_ternary: false, ..Warnings::default() };
+        assert!(Validator::new(warnings, 4).validate(&decls).is_empty());
+    }
+
+    #[test]
+    fn writes_into_string_literals_warn() {
+        let decls = parse("s \"abc\";\nf() {\n    extern s;\n    auto t;\n    t = \"xy\";\n    s[0] = 'x';\n    *s = 'y';\n    \"lit\"[1] = 'z';\n    t[0] = 'w';\n    s = t;\n    return s;\n}\n");
+        let diagnostics = Validator::new(Warnings::default(), 4).validate(&decls);
+
+        let lines: Vec<usize> = diagnostics.iter().map(|d| d.pos.line).collect();
+        assert_eq!(lines, [6, 7, 8]);
+        assert_eq!(diagnostics[0].msg, "writing into the string literal of 's'");
+        assert_eq!(diagnostics[2].msg, "writing into a string literal");
+
+        let warnings = Warnings { write_strings: false, ..Warnings::default() };
+        assert!(Validator::new(warnings, 4).validate(&decls).is_empty());
     }
 }
@@ -0,0 +1,231 @@
+// Uses the crate as a library, the way editors, linters and fuzzers do.
+
+use blang::{ast::*, file::{File, FilePosition}, fuzz_parse, incremental::{self, Edit}, token::TokenType};
+use blang::{ErrorKind, Severity, Validator, Warnings};
+use std::rc::Rc;
+
+#[test]
+fn fuzz_parse_accepts_valid_program() {
+    assert!(fuzz_parse(b"x 1;\nmain() {\n    return x ? (1) : -x;\n}\n"));
+}
+
+#[test]
+fn deep_nesting_is_an_error() {
+    let parens = format!("main() {{ return {}1{}; }}", "(".repeat(100_000), ")".repeat(100_000));
+    assert!(!fuzz_parse(parens.as_bytes()));
+
+    let blocks = format!("main() {}{}", "{".repeat(100_000), "}".repeat(100_000));
+    assert!(!fuzz_parse(blocks.as_bytes()));
+
+    let unary = format!("main() {{ return {}x; }}", "-".repeat(100_000));
+    assert!(!fuzz_parse(unary.as_bytes()));
+}
+
+#[test]
+fn fuzz_parse_survives_arbitrary_bytes() {
+    // A fixed linear congruential generator, so failures reproduce.
+    let mut state: u32 = 1;
+    let mut next = || {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        (state >> 16) as u8
+    };
+
+    let alphabet = b"(){}[];,=+-*/&|!?:<>\"'\\#bx0 \n";
+
+    for _ in 0..500 {
+        let len = next() as usize;
+        let data: Vec<u8> = (0..len).map(|_| alphabet[next() as usize % alphabet.len()]).collect();
+        fuzz_parse(&data);
+
+        let raw: Vec<u8> = (0..len).map(|_| next()).collect();
+        fuzz_parse(&raw);
+    }
+}
+
+fn expr(kind: ExprKind) -> Expr {
+    let pos = FilePosition {
+        file: Rc::new(File { path: String::from("test.b"), data: Vec::new() }),
+        line: 1,
+        offset: 0
+    };
+
+    Expr { pos, kind }
+}
+
+fn var(name: &str) -> Expr {
+    expr(ExprKind::Var(String::from(name)))
+}
+
+// Collects the variables, and stops at calls.
+#[derive(Default)]
+struct Names(Vec<String>);
+
+impl Visitor for Names {
+    fn visit_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Var(name) => self.0.push(name.clone()),
+            ExprKind::Call(..) => {},
+            _ => walk_expr(self, expr)
+        }
+    }
+}
+
+#[test]
+fn visitor_walks_in_source_order() {
+    // `f() { x = y + g(z); return w; }`
+    let value = ExprKind::BinOp(Box::new(var("y")), TokenType::Plus, Box::new(expr(ExprKind::Call(Box::new(var("g")), vec![var("z")]))));
+    let assign = expr(ExprKind::Assign(Box::new(var("x")), Box::new(expr(value))));
+    let stmts = vec![
+        Stmt { pos: assign.pos.clone(), kind: StmtKind::Expr(assign) },
+        Stmt { pos: var("w").pos, kind: StmtKind::Return(Some(var("w"))) }
+    ];
+
+    let decl = Decl {
+        pos: var("f").pos,
+        kind: DeclKind::Function {
+            name: String::from("f"),
+            params: Vec::new(),
+            body: Stmt { pos: var("f").pos, kind: StmtKind::Block(stmts) }
+        }
+    };
+
+    let mut names = Names::default();
+    walk(&mut names, &[decl]);
+
+    assert_eq!(names.0, ["x", "y", "w"]);
+}
+
+fn file(source: &str) -> Rc<File> {
+    Rc::new(File {
+        path: String::from("test.b"),
+        data: source.as_bytes().to_vec()
+    })
+}
+
+fn name(decl: &Decl) -> &str {
+    match &decl.kind {
+        DeclKind::Function { name, .. } => name,
+        DeclKind::External(var) => &var.name
+    }
+}
+
+fn rename(decl: &mut Decl, to: &str) {
+    match &mut decl.kind {
+        DeclKind::Function { name, .. } => *name = String::from(to),
+        DeclKind::External(var) => var.name = String::from(to)
+    }
+}
+
+#[test]
+fn edit_reparses_only_the_touched_function() {
+    let source = "first() {\n    return 1;\n}\nsecond() {\n    return 2;\n}\nthird() {\n    return 3;\n}\n";
+    let old = file(source);
+    let mut decls = incremental::parse(&old);
+    assert_eq!(decls.len(), 3);
+
+    // A reparsed declaration would get its name back from the source.
+    rename(&mut decls[0], "kept_first");
+    rename(&mut decls[2], "kept_third");
+
+    let start = source.find("2;").unwrap();
+    let edit = Edit { range: start..start + 1, text: String::from("(20,\n 21)") };
+    let res = incremental::reparse(&old, decls, &edit);
+
+    assert_eq!(res.reparsed, 1..2);
+    assert_eq!(res.decls.iter().map(name).collect::<Vec<_>>(), ["kept_first", "second", "kept_third"]);
+
+    // The untouched declarations moved with the text after the edit.
+    let fresh = incremental::parse(&res.file);
+    for (moved, parsed) in res.decls.iter().zip(&fresh) {
+        assert_eq!((moved.pos.line, moved.pos.offset), (parsed.pos.line, parsed.pos.offset));
+        assert!(Rc::ptr_eq(&moved.pos.file, &res.file));
+    }
+}
+
+#[test]
+fn edit_swallowing_a_declaration_reparses_it() {
+    let source = "a 1;\nb 2;\nc 3;\n";
+    let old = file(source);
+    let mut decls = incremental::parse(&old);
+    rename(&mut decls[2], "kept_c");
+
+    // Without the semicolon `b` is where `a` expects one, both are lost like
+    // in a fresh parse.
+    let start = source.find(';').unwrap();
+    let res = incremental::reparse(&old, decls, &Edit { range: start..start + 1, text: String::new() });
+
+    assert_eq!(res.decls.iter().map(name).collect::<Vec<_>>(), ["kept_c"]);
+    assert_eq!(incremental::parse(&res.file).iter().map(name).collect::<Vec<_>>(), ["c"]);
+    assert!(res.reparsed.is_empty());
+}
+
+#[test]
+fn spans_start_where_the_node_is_written() {
+    let decls = incremental::parse(&file("x 1;\nmain() {\n    return x + 1;\n}\n"));
+    assert_eq!((decls[0].span().line, decls[0].span().offset), (1, 0));
+    assert_eq!(decls[1].span().line, 2);
+
+    let DeclKind::Function { body, .. } = &decls[1].kind else {
+        panic!("expected a function");
+    };
+
+    let StmtKind::Block(stmts) = &body.kind else {
+        panic!("expected a block");
+    };
+
+    let StmtKind::Return(Some(value)) = &stmts[0].kind else {
+        panic!("expected a return statement");
+    };
+
+    assert_eq!((stmts[0].span().line, stmts[0].span().offset), (3, 18));
+
+    let ExprKind::BinOp(left, _, right) = &value.kind else {
+        panic!("expected a binary operator");
+    };
+
+    assert_eq!((left.span().line, left.span().offset), (3, 25));
+    assert_eq!(right.span().offset, 29);
+}
+
+#[test]
+fn validate_returns_diagnostics() {
+    let decls = incremental::parse(&file("main() {\n    return x;\n}\n"));
+    let diagnostics = Validator::new(Warnings::default(), 8).validate(&decls);
+
+    assert_eq!(diagnostics.len(), 1);
+    assert!(diagnostics[0].severity == Severity::Error(ErrorKind::UndefinedReference));
+    assert_eq!(diagnostics[0].category(), "undefined-reference");
+    assert_eq!(diagnostics[0].pos.line, 2);
+    assert!(diagnostics[0].msg.contains("'x'"), "{}", diagnostics[0].msg);
+}
+
+#[test]
+fn definition_at_resolves_local() {
+    let source = "main() {\n    auto count 1;\n    return count;\n}\n";
+    let file = file(source);
+    let decls = incremental::parse(&file);
+
+    let mut validator = Validator::new(Warnings::default(), 8);
+    assert!(validator.validate(&decls).is_empty());
+
+    let usage = source.rfind("count").unwrap() + 2;
+    let definition = validator.definition_at("test.b", usage).unwrap();
+
+    assert_eq!(definition.line, 2);
+    assert_eq!(definition.offset, source.find("auto").unwrap());
+}
+
+#[test]
+fn definition_at_resolves_global() {
+    let source = "limit 10;\nmain() {\n    extern limit;\n    return limit;\n}\n";
+    let file = file(source);
+    let decls = incremental::parse(&file);
+
+    let mut validator = Validator::new(Warnings::default(), 8);
+    assert!(validator.validate(&decls).is_empty());
+
+    let definition = validator.definition_at("test.b", source.rfind("limit").unwrap()).unwrap();
+    assert_eq!(definition.line, 1);
+
+    assert!(validator.definition_at("test.b", source.find("main").unwrap()).is_none());
+}
@@ -0,0 +1,1556 @@
+// Runs the compiler on small programs and checks its exit code, output and
+// diagnostics.
+
+use std::{fs, path::PathBuf, process::Command};
+
+struct Run {
+    code: i32,
+    stdout: String,
+    stderr: String,
+    dir: PathBuf
+}
+
+// Every test gets its own directory, the tests run in parallel.
+fn scratch(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("blang-{}-{}", std::process::id(), test));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn run(dir: PathBuf, args: &[String]) -> Run {
+    let output = Command::new(env!("CARGO_BIN_EXE_blang")).args(args).output().unwrap();
+
+    Run {
+        code: output.status.code().unwrap(),
+        stdout: String::from_utf8(output.stdout).unwrap(),
+        stderr: String::from_utf8(output.stderr).unwrap(),
+        dir
+    }
+}
+
+// Compiles `source` with `args` before the input and output paths, the output
+// is `out` in the test's directory.
+fn compile(test: &str, source: &str, args: &[&str]) -> Run {
+    let dir = scratch(test);
+    let input = dir.join("in.b");
+    fs::write(&input, source).unwrap();
+
+    let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    args.push(input.display().to_string());
+    args.push(dir.join("out").display().to_string());
+
+    run(dir, &args)
+}
+
+// Translates `source` to C, builds it with the system C compiler and returns
+// the exit code of the program, `None` if there is no C compiler.
+fn run_through_c(test: &str, source: &str, args: &[&str]) -> Option<i32> {
+    let res = compile(test, source, &[&["--emit-c"], args].concat());
+    assert_eq!(res.code, 0, "{}", res.stderr);
+
+    let c_file = res.dir.join("out.c");
+    fs::rename(res.dir.join("out"), &c_file).unwrap();
+    let program = res.dir.join("program");
+
+    let status = Command::new("cc").arg(&c_file).arg("-o").arg(&program).status().ok()?;
+    assert!(status.success(), "cc failed on {}", c_file.display());
+
+    Command::new(&program).status().unwrap().code()
+}
+
+#[test]
+fn usage_error_exits_with_1() {
+    let res = run(scratch("usage"), &[]);
+
+    assert_eq!(res.code, 1);
+    assert!(res.stderr.contains("problem parsing arguments"), "{}", res.stderr);
+}
+
+#[test]
+fn missing_input_exits_with_1() {
+    let dir = scratch("missing");
+    let args = [dir.join("none.b").display().to_string(), dir.join("out").display().to_string()];
+
+    let res = run(dir, &args);
+    assert_eq!(res.code, 1);
+    assert!(!res.stderr.contains("compilation failed"), "{}", res.stderr);
+}
+
+#[test]
+fn compile_error_exits_with_2() {
+    let res = compile("compile_error", "main() { return x; }\n", &[]);
+
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("error: compilation failed with 1 error\n"), "{}", res.stderr);
+}
+
+#[test]
+fn compile_error_counts_every_error() {
+    let res = compile("error_count", "main() {\n    x = 1;\n    return y;\n}\n", &["--entry", "start"]);
+
+    assert_eq!(res.code, 2);
+    // The missing entry is an error about the whole program, it counts too.
+    assert!(res.stderr.contains("error: compilation failed with 3 errors\n"), "{}", res.stderr);
+}
+
+#[test]
+fn success_exits_with_0() {
+    let res = compile("success", "main() { return 0; }\n", &[]);
+
+    assert_eq!(res.code, 0, "{}", res.stderr);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+    assert!(res.dir.join("out").exists());
+}
+
+#[test]
+fn include_is_found_in_include_dir() {
+    let headers = scratch("include_dir").join("headers");
+    fs::create_dir_all(&headers).unwrap();
+    fs::write(headers.join("answer.b"), "answer 42;\n").unwrap();
+
+    // The header is read, so the global is defined twice.
+    let source = "#include \"answer.b\"\nanswer 1;\n";
+
+    let res = compile("include_dir", source, &["-I", &headers.display().to_string()]);
+    assert!(res.stderr.contains("in.b:2: error redefinition of global 'answer'"), "{}", res.stderr);
+    assert!(!res.stderr.contains("cannot find include"), "{}", res.stderr);
+
+    let res = compile("include_dir", source, &[]);
+    assert!(res.stderr.contains("cannot find include 'answer.b'"), "{}", res.stderr);
+}
+
+#[test]
+fn dangling_else_warns_without_braces() {
+    let source = "main(a, b) {\n    if (a)\n        if (b)\n            return 1;\n        else\n            return 2;\n    return 0;\n}\n";
+
+    let res = compile("dangling_else", source, &["-Wdangling-else"]);
+    assert!(res.stderr.contains("in.b:3: warning add braces around nested 'if'"), "{}", res.stderr);
+
+    let res = compile("dangling_else", source, &[]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+}
+
+#[test]
+fn dangling_else_accepts_braces() {
+    let source = "main(a, b) {\n    if (a) {\n        if (b)\n            return 1;\n        else\n            return 2;\n    }\n    return 0;\n}\n";
+    let res = compile("dangling_else_braces", source, &["-Wdangling-else"]);
+
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+}
+
+#[test]
+fn identical_branches_warn() {
+    let source = "main(a) {\n    if (a)\n        return a + 1;\n    else\n        return (a+1);\n}\n";
+
+    let res = compile("identical_branches", source, &["-Widentical-branches"]);
+    assert!(res.stderr.contains("in.b:5: warning both branches of 'if' are identical"), "{}", res.stderr);
+
+    let res = compile("identical_branches", source, &[]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+
+    let source = "main(a) {\n    if (a)\n        return a;\n    else\n        return -a;\n}\n";
+    let res = compile("different_branches", source, &["-Widentical-branches"]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+}
+
+#[test]
+fn unknown_escape_is_an_error() {
+    let res = compile("unknown_escape", "main() {\n    return '\\q';\n}\n", &[]);
+
+    assert!(res.stderr.contains("in.b:2: error unknown escape sequence '\\q'"), "{}", res.stderr);
+}
+
+#[test]
+fn unknown_escape_is_reported_on_its_line() {
+    let res = compile("unknown_escape_line", "main() {\n    return \"one\n\\ttwo\n\\q\";\n}\n", &[]);
+
+    assert!(res.stderr.contains("in.b:4: error unknown escape sequence '\\q'"), "{}", res.stderr);
+}
+
+#[test]
+fn minus_before_a_number_subtracts() {
+    let source = "low -2147483648;\nmain(x) {\n    return x-5;\n}\n";
+    let res = compile("minus_number", source, &[]);
+
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+}
+
+#[test]
+fn byte_string_has_no_nul() {
+    let res = compile("byte_string", "text \"hi\";\nraw b\"hi\";\n", &[]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+
+    let code = fs::read_to_string(res.dir.join("out")).unwrap();
+    assert!(code.contains(".Lstr0:\n\t.byte 104, 105, 0\n"), "{}", code);
+    assert!(code.contains(".Lstr1:\n\t.byte 104, 105\n"), "{}", code);
+    assert!(code.contains("text:\n\t.long .Lstr0\n"), "{}", code);
+    assert!(code.contains("raw:\n\t.long .Lstr1\n"), "{}", code);
+}
+
+#[test]
+fn list_globals_reports_kind_size_and_line() {
+    let source = "count 0;\nsum(a, b) {\n    return a;\n}\n";
+    let res = compile("list_globals", source, &["--list-globals"]);
+
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+
+    let input = res.dir.join("in.b").display().to_string();
+    let expected = format!("count\tdata\t1\t{0}:1\nsum\tfunction\t2\t{0}:2\n", input);
+    assert_eq!(res.stdout, expected);
+}
+
+#[test]
+fn comma_in_ternary_arm_is_an_error() {
+    let res = compile("ternary_comma", "main(a, b, c, d) {\n    return a ? b, c : d;\n}\n", &[]);
+
+    assert!(res.stderr.contains("in.b:2: error expected ':' in ternary expression"), "{}", res.stderr);
+}
+
+#[test]
+fn initializer_list_allows_trailing_comma() {
+    let res = compile("trailing_comma", "v[3] 1, 2, 3,;\n", &[]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+
+    let code = fs::read_to_string(res.dir.join("out")).unwrap();
+    assert!(code.contains(".Lvec_v:\n\t.long 1\n\t.long 2\n\t.long 3\n"), "{}", code);
+}
+
+#[test]
+fn initializer_list_rejects_empty_elements() {
+    let res = compile("doubled_comma", "v[3] 1,, 2;\nw[2] ,;\n", &[]);
+
+    assert!(res.stderr.contains("in.b:1: error expected expression"), "{}", res.stderr);
+    assert!(res.stderr.contains("in.b:2: error expected expression"), "{}", res.stderr);
+}
+
+// The value of `key` in a flat JSON object on one line, strings keep their
+// quotes and must not contain escaped ones.
+fn json_field<'a>(object: &'a str, key: &str) -> &'a str {
+    let start = object.find(&format!("\"{}\": ", key)).unwrap() + key.len() + 4;
+
+    let len = match object[start + 1..].find('"') {
+        Some(end) if object[start..].starts_with('"') => end + 2,
+        _ => object[start..].find([',', '}']).unwrap()
+    };
+
+    &object[start..start + len]
+}
+
+#[test]
+fn json_tokens_carry_byte_offsets() {
+    let source = "x 1;\nmain() {\n    return x;\n}\n";
+    let res = compile("json_tokens", source, &["--dump-tokens=json"]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+
+    let tokens: Vec<&str> = res.stdout.lines().filter(|line| line.starts_with("  {")).collect();
+    assert_eq!(tokens.len(), 12, "{}", res.stdout);
+
+    for token in &tokens {
+        let start: usize = json_field(token, "start").parse().unwrap();
+        let end: usize = json_field(token, "end").parse().unwrap();
+
+        assert_eq!(format!("\"{}\"", &source[start..end]), json_field(token, "text"), "{}", token);
+    }
+
+    assert_eq!(json_field(tokens[7], "kind"), "\"KeywordReturn\"");
+    assert_eq!(json_field(tokens[7], "line"), "3");
+    assert_eq!(json_field(tokens[7], "start"), "18");
+    assert_eq!(json_field(tokens[11], "kind"), "\"EndOfFile\"");
+}
+
+#[test]
+fn plain_tokens_are_one_per_line() {
+    let res = compile("plain_tokens", "x 1;\n", &["--dump-tokens"]);
+    let input = res.dir.join("in.b").display().to_string();
+
+    let expected = format!("{0}:1: Identifier 'x'\n{0}:1: IntLiteral '1'\n{0}:1: Semicolon ';'\n{0}:2: EndOfFile ''\n", input);
+    assert_eq!(res.stdout, expected);
+}
+
+#[test]
+fn len_builtin_folds_to_string_length() {
+    let res = compile("len_builtin", "size __len(\"abc\");\nsum 2 + 3 * 4;\n", &[]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+
+    let code = fs::read_to_string(res.dir.join("out")).unwrap();
+    assert!(code.contains("size:\n\t.long 3\n"), "{}", code);
+    assert!(code.contains("sum:\n\t.long 14\n"), "{}", code);
+}
+
+#[test]
+fn len_builtin_needs_string_literal() {
+    let res = compile("len_builtin_arg", "main(x) {\n    return __len(x);\n}\n", &[]);
+
+    assert!(res.stderr.contains("in.b:2: error '__len' expects a single string literal argument"), "{}", res.stderr);
+}
+
+// The caret's distance from the gutter's '|' in a reported snippet.
+fn caret_column(stderr: &str) -> usize {
+    let caret_line = stderr.lines().find(|line| line.trim_end().ends_with('^')).unwrap();
+    caret_line.find('^').unwrap() - caret_line.find('|').unwrap()
+}
+
+#[test]
+fn caret_ignores_gutter_width() {
+    let line = "main() { return y; }\n";
+
+    let res = compile("caret_line_9", &("\n".repeat(8) + line), &[]);
+    assert!(res.stderr.contains(" 9 | main() { return y; }\n   | "), "{}", res.stderr);
+    let column_9 = caret_column(&res.stderr);
+
+    let res = compile("caret_line_100", &("\n".repeat(99) + line), &[]);
+    assert!(res.stderr.contains(" 100 | main() { return y; }\n     | "), "{}", res.stderr);
+    let column_100 = caret_column(&res.stderr);
+
+    assert_eq!(column_9, column_100);
+    assert_eq!(column_9, 2 + line.find('y').unwrap());
+}
+
+#[test]
+fn caret_points_at_unknown_escape() {
+    let res = compile("caret_escape", "main() {\n    return \"ok\\q\";\n}\n", &[]);
+
+    let caret = format!(" 2 |     return \"ok\\q\";\n   | {}^\n", " ".repeat("    return \"ok".len()));
+    assert!(res.stderr.contains(&caret), "{}", res.stderr);
+}
+
+#[test]
+fn do_while_with_empty_body_compiles() {
+    let source = "main() {\n    auto c;\n    c = 3;\n    do ; while (c--);\n    do { if (c) break; continue; } while (c);\n    return c;\n}\n";
+    let res = compile("empty_do_while", source, &[]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+
+    // The empty body is only the condition, which jumps back to its own start.
+    let code = fs::read_to_string(res.dir.join("out")).unwrap();
+    assert!(code.contains(".L1:\n.L2:\n\tleal -4(%ebp), %eax\n"), "{}", code);
+    assert!(code.contains("\tjnz .L1\n"), "{}", code);
+}
+
+#[test]
+fn constant_index_out_of_bounds_warns() {
+    let source = "v[4];\nmain() {\n    extern v;\n    auto w[2];\n    v[3] = 1;\n    v[4] = 1;\n    w[-1] = 2;\n    return w[1];\n}\n";
+    let res = compile("index_bounds", source, &[]);
+
+    assert!(res.stderr.contains("in.b:6: warning array index out of bounds for 'v' of size 4."), "{}", res.stderr);
+    assert!(res.stderr.contains("in.b:7: warning array index out of bounds for 'w' of size 2."), "{}", res.stderr);
+    assert!(!res.stderr.contains("in.b:5:"), "{}", res.stderr);
+    assert!(!res.stderr.contains("in.b:8:"), "{}", res.stderr);
+}
+
+// Compiles `source` for x86-64, links it with the system's C compiler and
+// returns the exit code of running it.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn run_x86_64(test: &str, source: &str) -> i32 {
+    run_x86_64_with(test, source, &[])
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn run_x86_64_with(test: &str, source: &str, args: &[&str]) -> i32 {
+    let res = compile(test, source, &[&["--word-size", "8"], args].concat());
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+
+    let (asm, exe) = (res.dir.join("out.s"), res.dir.join("out.exe"));
+    fs::rename(res.dir.join("out"), &asm).unwrap();
+
+    // Globals are addressed absolutely.
+    let output = Command::new("cc").arg("-no-pie").arg("-o").arg(&exe).arg(&asm).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    Command::new(&exe).status().unwrap().code().unwrap()
+}
+
+// There is no `switch`, but `continue` nested in other statements must still
+// go to the innermost loop.
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn continue_iterates_the_innermost_loop() {
+    let source = "main() {
+    auto i;
+    auto j;
+    auto n;
+    i = 0;
+    n = 0;
+    while (i < 10) {
+        i++;
+        if (i & 1) {
+            continue;
+        }
+        j = 0;
+        while (j < 3) {
+            j++;
+            if (j == 2)
+                continue;
+            n = n + 100;
+        }
+        n = n + i;
+    }
+    return n - 1000;
+}
+";
+
+    assert_eq!(run_x86_64("continue_nested", source), 30);
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn auto_initializers_are_any_expression() {
+    let source = "f(n) {\n    auto x n * 2 + 1;\n    auto v[2] x, (x * 2);\n    return v[0] + v[1];\n}\nmain() {\n    extern f;\n    return f(3);\n}\n";
+    assert_eq!(run_x86_64("auto_initializers", source), 21);
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn folding_matches_64_bit_words() {
+    // Each side is folded on the left and computed at run time on the right.
+    let source = "main() {
+    auto a;
+    auto s;
+    a = 1;
+    s = 31;
+    if ((1 << 31) != (a << s))
+        return 1;
+    if (2147483647 + 1 != 2147483647 + a)
+        return 2;
+    if (-2147483648 - 1 != -2147483648 - a)
+        return 3;
+    if (65536 * 65536 != 65536 * (a << 16))
+        return 4;
+    return 0;
+}
+";
+
+    assert_eq!(run_x86_64("fold_64", source), 0);
+}
+
+#[test]
+fn assignment_needs_an_lvalue() {
+    let source = "main(a, b) {\n    auto v[2];\n    a = 1;\n    v[1] = 2;\n    *v = 3;\n    a + b = 4;\n    &a = 5;\n    3++;\n    return &(a + b);\n}\n";
+    let res = compile("lvalue", source, &[]);
+
+    assert!(res.stderr.contains("in.b:6: error expression is not an lvalue"), "{}", res.stderr);
+    assert!(res.stderr.contains("in.b:7: error expression is not an lvalue"), "{}", res.stderr);
+    assert!(res.stderr.contains("in.b:8: error expression is not an lvalue"), "{}", res.stderr);
+    assert!(res.stderr.contains("in.b:9: error expression is not an lvalue"), "{}", res.stderr);
+    assert!(!res.stderr.contains("in.b:3:") && !res.stderr.contains("in.b:4:") && !res.stderr.contains("in.b:5:"), "{}", res.stderr);
+}
+
+#[test]
+fn while_condition_folding_to_zero_warns() {
+    let source = "main(x) {\n    while (2 - 2)\n        x++;\n    while (1)\n        break;\n    while (x)\n        x--;\n    return x;\n}\n";
+    let res = compile("while_false", source, &[]);
+
+    assert!(res.stderr.contains("in.b:2: warning loop condition is always false"), "{}", res.stderr);
+    assert_eq!(res.stderr.matches("warning").count(), 1, "{}", res.stderr);
+}
+
+#[test]
+fn define_expands_in_source() {
+    let res = compile("define", "main() { return DEBUG + FLAG; }\n", &["-D", "DEBUG=7", "-DFLAG"]);
+    assert_eq!(res.code, 0, "{}", res.stderr);
+
+    // Without a value the macro is 1.
+    let code = fs::read_to_string(res.dir.join("out")).unwrap();
+    assert!(code.contains("\tpushl $8\n"), "{}", code);
+}
+
+#[test]
+fn define_rejects_invalid_names() {
+    for name in ["=1", "1X=2", "a-b"] {
+        let res = compile("define_invalid", "main() { return 0; }\n", &["-D", name]);
+
+        assert_eq!(res.code, 1, "-D {}", name);
+        assert!(res.stderr.contains("expected macro name after '-D'"), "{}", res.stderr);
+    }
+}
+
+#[test]
+fn listing_interleaves_source_and_assembly() {
+    let dir = scratch("listing");
+    fs::write(dir.join("helper.b"), "helper() {\n    return 1;\n}\n").unwrap();
+
+    let source = "#include \"helper.b\"\nmain() {\n    auto x;\n    x = 2;\n    return x;\n}\n";
+    let listing = dir.join("out.lst").display().to_string();
+    let res = compile("listing", source, &["-I", &dir.display().to_string(), "--listing", &listing]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+
+    let listing = fs::read_to_string(&listing).unwrap();
+    assert!(listing.starts_with(" 1 | #include \"helper.b\"\n 2 | main() {\n   | \t.globl main\n"), "{}", listing);
+    assert!(listing.contains(" 3 |     auto x;\n 4 |     x = 2;\n   | \tleal -4(%ebp), %eax\n"), "{}", listing);
+    assert!(listing.ends_with("\n 6 | }\n"), "{}", listing);
+
+    // Code from the included file is not listed.
+    assert!(!listing.contains("helper:"), "{}", listing);
+}
+
+#[test]
+fn empty_input_compiles_to_empty_output() {
+    for source in ["", "\n  \n", "\t\r\n"] {
+        let res = compile("empty_input", source, &[]);
+
+        assert_eq!(res.code, 0, "{}", res.stderr);
+        assert!(res.stderr.is_empty(), "{}", res.stderr);
+        assert_eq!(fs::read_to_string(res.dir.join("out")).unwrap(), "");
+    }
+}
+
+#[test]
+fn pedantic_rejects_extensions() {
+    let source = "raw b\"hi\";\nsize __len(\"abc\");\ntext \"hi\";\n";
+
+    let res = compile("pedantic", source, &["--pedantic"]);
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("in.b:1: error feature not allowed in pedantic mode: byte string literals"), "{}", res.stderr);
+    assert!(res.stderr.contains("in.b:2: error feature not allowed in pedantic mode: the '__len' builtin"), "{}", res.stderr);
+    assert!(!res.stderr.contains("in.b:3:"), "{}", res.stderr);
+
+    let res = compile("pedantic", source, &[]);
+    assert_eq!(res.code, 0, "{}", res.stderr);
+}
+
+#[test]
+fn pedantic_accepts_plain_b() {
+    let source = "text \"hi\";\nmain(a) {\n    extern text;\n    auto v[2];\n    v[0] = a ? 'x' : text[1];\n    return v[0];\n}\n";
+    let res = compile("pedantic_plain", source, &["--pedantic"]);
+
+    assert_eq!(res.code, 0, "{}", res.stderr);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn index_operands_commute() {
+    let source = "g[4];
+main() {
+    extern g;
+    auto a[3];
+    auto i;
+    i = 1;
+    a[2] = 7;
+    if (2[a] != 7)
+        return 1;
+    i[a] = 5;
+    if (a[1] != 5)
+        return 2;
+    (i + 1)[g] = 9;
+    if (g[2] != 9 | 2[g] != 9)
+        return 3;
+    return \"xyz\"[i] == i[\"xyz\"] ? 0 : 4;
+}
+";
+
+    assert_eq!(run_x86_64("index_commute", source), 0);
+}
+
+#[test]
+fn swapped_constant_index_is_bounds_checked() {
+    let source = "main() {\n    auto v[2];\n    1[v] = 1;\n    2[v] = 2;\n    return v[0];\n}\n";
+    let res = compile("swapped_bounds", source, &[]);
+
+    assert!(res.stderr.contains("in.b:4: warning array index out of bounds for 'v' of size 2."), "{}", res.stderr);
+    assert!(!res.stderr.contains("in.b:3:"), "{}", res.stderr);
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn chained_index_reads_nested_vectors() {
+    let source = "main() {\n    auto row[2];\n    auto m[1];\n    m[0] = row;\n    row[1] = 6;\n    m[0][1]++;\n    return m[0][1];\n}\n";
+
+    assert_eq!(run_x86_64("chained_index", source), 7);
+}
+
+#[test]
+fn globals_initialized_from_other_globals() {
+    let res = compile("global_refs", "a 5;\nb a;\nc &a;\nv[2] &c, b;\nd e;\ne 3;\n", &[]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+
+    // Values are copied, even from globals defined later, addresses are not.
+    let code = fs::read_to_string(res.dir.join("out")).unwrap();
+    assert!(code.contains("b:\n\t.long 5\n"), "{}", code);
+    assert!(code.contains("c:\n\t.long a\n"), "{}", code);
+    assert!(code.contains(".Lvec_v:\n\t.long c\n\t.long 5\n"), "{}", code);
+    assert!(code.contains("d:\n\t.long 3\n"), "{}", code);
+}
+
+#[test]
+fn global_value_cycles_are_errors() {
+    let source = "x y;\ny z;\nz x;\nf() {\n    return 0;\n}\ng f;\nh missing;\nself[2] 1, self;\n";
+    let res = compile("global_cycles", source, &[]);
+
+    assert!(res.stderr.contains("in.b:1: error initializer of 'x' depends on its own value"), "{}", res.stderr);
+    assert!(res.stderr.contains("in.b:7: error initializer of 'g' uses the value of function 'f'"), "{}", res.stderr);
+    assert!(res.stderr.contains("in.b:8: error undefinded reference to 'missing'"), "{}", res.stderr);
+
+    // The value of a vector is its address, so this is no cycle.
+    assert!(!res.stderr.contains("in.b:9:"), "{}", res.stderr);
+}
+
+#[test]
+fn asm_statement_is_copied_verbatim() {
+    let source = "main() {\n    asm(\"nop\");\n    asm(\"movl $1, %eax\\n\\tret\");\n    return 0;\n}\n";
+    let res = compile("asm", source, &[]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+
+    let code = fs::read_to_string(res.dir.join("out")).unwrap();
+    assert!(code.contains("\tnop\n\tmovl $1, %eax\n\tret\n"), "{}", code);
+
+    let res = compile("asm", source, &["--pedantic"]);
+    assert!(res.stderr.contains("in.b:2: error feature not allowed in pedantic mode: inline assembly"), "{}", res.stderr);
+}
+
+#[test]
+fn asm_needs_a_string_literal() {
+    let res = compile("asm_arg", "main() {\n    asm(x);\n}\n", &[]);
+
+    assert!(res.stderr.contains("in.b:2: error expected string literal in 'asm'"), "{}", res.stderr);
+}
+
+#[test]
+fn source_map_points_at_generated_lines() {
+    let dir = scratch("source_map");
+    let map = dir.join("out.map").display().to_string();
+    let res = compile("source_map", "x 1;\nmain() {\n    auto y;\n    return 7;\n}\n", &["--sourcemap", &map]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+
+    // An auto without initializer generates no code, so it shares the line of
+    // the next statement.
+    let input = res.dir.join("in.b").display().to_string();
+    assert_eq!(fs::read_to_string(&map).unwrap(), format!("2\t{0}:2\n7\t{0}:3\n7\t{0}:4\n", input));
+
+    let code = fs::read_to_string(res.dir.join("out")).unwrap();
+    let lines: Vec<&str> = code.lines().collect();
+    assert_eq!(lines[1], "\t.globl main");
+    assert_eq!(lines[6], "\tpushl $7");
+}
+
+#[test]
+fn emit_deps_lists_the_included_files() {
+    let dir = scratch("emit_deps");
+    fs::write(dir.join("helper.b"), "helper 1;\n").unwrap();
+    let deps = dir.join("out.d").display().to_string();
+
+    let res = compile("emit_deps", "#include \"helper.b\"\nmain() {\n    extern helper;\n    return helper;\n}\n", &["--emit-deps", &deps]);
+    assert_eq!(res.code, 0, "{}", res.stderr);
+
+    let out = res.dir.join("out").display().to_string();
+    let input = res.dir.join("in.b").display().to_string();
+    let helper = res.dir.join("helper.b").display().to_string();
+    assert_eq!(fs::read_to_string(&deps).unwrap(), format!("{}: \\\n  {} \\\n  {}\n\n{}:\n", out, input, helper, helper));
+}
+
+#[test]
+fn stats_count_the_folded_program() {
+    let source = "v[2] 1, 2;\nmain(x) {\n    if (x) {\n        x = x + 1;\n    }\n    return 2 * 3;\n}\n";
+    let res = compile("stats", source, &["--stats"]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+
+    // `2 * 3` is a single integer after folding.
+    let expected = "functions\t1\nglobals\t1\nmax depth\t4\nstmt block\t2\nstmt if\t1\nstmt expression\t1\nstmt return\t1\n\
+        expr integer\t5\nexpr variable\t3\nexpr assignment\t1\nexpr binary\t1\n";
+    assert_eq!(res.stdout, expected);
+    assert!(!res.dir.join("out").exists());
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn chained_assignment_evaluates_each_target_once() {
+    let source = "main() {
+    auto v[2];
+    auto w[2];
+    auto i;
+    i = 0;
+    v[i++] = w[i++] = 5;
+    if (i != 2)
+        return 1;
+    return v[0] == 5 & w[1] == 5 ? 0 : 2;
+}
+";
+
+    assert_eq!(run_x86_64("chained_assign", source), 0);
+}
+
+#[test]
+fn undefined_extern_warns_unless_silenced() {
+    let source = "main() {\n    extern printf;\n    extern later;\n    return later;\n}\nlater 1;\nf() {\n    extern printf;\n    return 0;\n}\n";
+    let res = compile("undefined_extern", source, &[]);
+
+    assert_eq!(res.code, 0, "{}", res.stderr);
+    assert!(res.stderr.contains("in.b:2: warning 'printf' is declared but never defined"), "{}", res.stderr);
+    assert_eq!(res.stderr.matches("warning").count(), 1, "{}", res.stderr);
+
+    let res = compile("undefined_extern", source, &["-Wno-undefined-extern"]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn auto_string_buffer_is_a_fresh_copy() {
+    // Every call gets the original bytes, whatever the previous one wrote.
+    let source = "first() {
+    auto s[] \"hello world\";
+    auto c;
+    c = s[0] & 255;
+    s[0] = 'x';
+    return c;
+}
+main() {
+    extern first;
+    auto s[] \"hello world\";
+    if ((s[1] & 255) != 'r' | (s[1] >> 24 & 255) != 0)
+        return 1;
+    if (1[s] != s[1])
+        return 2;
+    first();
+    return first() == 'h' ? 0 : 3;
+}
+";
+
+    assert_eq!(run_x86_64("string_buffer", source), 0);
+}
+
+#[test]
+fn color_is_only_used_when_asked() {
+    let source = "main() {\n    while (0)\n        return x;\n}\n";
+
+    // The tests' stderr is a pipe, so the default is no color.
+    for args in [&[][..], &["--no-color"], &["--color=never"], &["--color=auto"]] {
+        let res = compile("color", source, args);
+        assert!(res.stderr.contains("in.b:2: warning loop"), "{}", res.stderr);
+        assert!(!res.stderr.contains('\x1b'), "{}", res.stderr);
+    }
+
+    let res = compile("color", source, &["--color=always"]);
+    assert!(res.stderr.contains("in.b:2: \x1b[1;33mwarning\x1b[0m loop"), "{}", res.stderr);
+    assert!(res.stderr.contains("in.b:3: \x1b[1;31merror\x1b[0m undefinded"), "{}", res.stderr);
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn comma_evaluates_left_to_right() {
+    let source = "main() {\n    auto i;\n    auto j;\n    i = 1;\n    j = (i = i * 10, i = i + 2, i * 3);\n    return j;\n}\n";
+
+    assert_eq!(run_x86_64("comma_order", source), 36);
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn logical_operators_skip_the_right_operand() {
+    let source = "main() {
+    auto n;
+    n = 0;
+    if (0 && n++)
+        return 1;
+    if (!(1 || n++))
+        return 2;
+    if (n != 0)
+        return 3;
+    if (!(1 && ++n) | !(0 || n++))
+        return 4;
+    return n;
+}
+";
+
+    assert_eq!(run_x86_64("short_circuit", source), 2);
+}
+
+#[test]
+fn deep_nesting_is_reported() {
+    let source = format!("main() {{\n    return {}1{};\n}}\n", "(".repeat(100_000), ")".repeat(100_000));
+    let res = compile("deep_nesting", &source, &[]);
+
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("in.b:2: error nesting is too deep"), "{}", &res.stderr[..200]);
+}
+
+#[test]
+fn operator_chains_count_as_nesting() {
+    let chain = |len: usize| format!("main() {{\n    return 1{};\n}}\n", " + 1".repeat(len));
+
+    let res = compile("nesting_chain", &chain(100_000), &[]);
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("in.b:2: error nesting is too deep"), "{}", &res.stderr[..200]);
+
+    let res = compile("nesting_chain", &chain(20), &["--max-nesting", "10"]);
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("nesting is too deep"), "{}", res.stderr);
+
+    let res = compile("nesting_chain", &chain(2000), &["--max-nesting", "5000"]);
+    assert_eq!(res.code, 0, "{}", &res.stderr[..res.stderr.len().min(200)]);
+
+    let res = compile("nesting_chain", &chain(1), &["--max-nesting", "deep"]);
+    assert_eq!(res.code, 1);
+    assert!(res.stderr.contains("expected number after '--max-nesting'"), "{}", res.stderr);
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn return_value_is_the_last_of_a_comma_expression() {
+    let source = "main() {\n    auto a;\n    return a = 4, a + 1;\n}\n";
+
+    assert_eq!(run_x86_64("return_comma", source), 5);
+}
+
+#[test]
+fn entry_must_be_a_defined_function() {
+    let source = "start() {\n    return 0;\n}\ndata 1;\n";
+
+    let res = compile("entry", source, &["--entry", "start"]);
+    assert_eq!(res.code, 0, "{}", res.stderr);
+
+    for name in ["main", "data"] {
+        let res = compile("entry", source, &["--entry", name]);
+        assert_eq!(res.code, 2);
+        assert!(res.stderr.contains(&format!("error: entry function '{}' is not defined.", name)), "{}", res.stderr);
+    }
+
+    // Without the option there is no check, the program may be a library.
+    let res = compile("entry", source, &[]);
+    assert_eq!(res.code, 0, "{}", res.stderr);
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn logical_shift_fills_with_zeros() {
+    let source = "main() {
+    auto m;
+    m = -16;
+    if (m >> 2 != -4)
+        return 1;
+    if (m >>> 60 != 15)
+        return 2;
+    if (64 >>> 3 != 8 | (64 >>> 3) != (m * -4 >>> 3))
+        return 3;
+    return 0;
+}
+";
+
+    assert_eq!(run_x86_64("logical_shift", source), 0);
+}
+
+#[test]
+fn logical_shift_is_an_extension() {
+    let res = compile("logical_shift_pedantic", "main(x) {\n    return x >>> 1;\n}\n", &["--pedantic"]);
+
+    assert!(res.stderr.contains("in.b:2: error feature not allowed in pedantic mode: the '>>>' operator"), "{}", res.stderr);
+}
+
+#[test]
+fn shift_amount_range_follows_word_size() {
+    let source = "main(x) {\n    x = x << 31;\n    x = x >> 32;\n    x = x >>> 63;\n    return x << 64;\n}\n";
+
+    let res = compile("shift_range_4", source, &[]);
+    assert!(!res.stderr.contains("in.b:2:"), "{}", res.stderr);
+    assert!(res.stderr.contains("in.b:3: warning shift amount out of range"), "{}", res.stderr);
+    assert!(res.stderr.contains("in.b:4: warning shift amount out of range"), "{}", res.stderr);
+    assert!(res.stderr.contains("in.b:5: warning shift amount out of range"), "{}", res.stderr);
+
+    let res = compile("shift_range_8", source, &["--word-size", "8"]);
+    assert!(res.stderr.contains("in.b:5: warning shift amount out of range"), "{}", res.stderr);
+    assert_eq!(res.stderr.matches("warning").count(), 1, "{}", res.stderr);
+}
+
+#[test]
+fn negative_shift_amount_warns() {
+    let res = compile("shift_negative", "main(x) {\n    return x << -1;\n}\n", &["--word-size", "8"]);
+
+    assert!(res.stderr.contains("in.b:2: warning shift amount out of range"), "{}", res.stderr);
+}
+
+#[test]
+fn recovered_statement_error_fails_the_build() {
+    let source = "main() {\n    return 1 +;\n    return 2;\n}\n";
+    let res = compile("recovered_error", source, &[]);
+
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("in.b:2: error expected expression"), "{}", res.stderr);
+    assert!(!res.dir.join("out").exists());
+}
+
+#[test]
+fn braceless_function_needs_the_flag() {
+    let source = "main() return 0;\n";
+
+    let res = compile("braceless", source, &["--braceless-functions"]);
+    assert_eq!(res.code, 0, "{}", res.stderr);
+
+    let res = compile("braceless", source, &[]);
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("expected '{' before function body"), "{}", res.stderr);
+}
+
+#[test]
+fn c_output_does_not_depend_on_source_order() {
+    let source = "first &second;\nsecond 5;\n\
+        odd(n) { extern even; if (n == 0) return 0; return even(n - 1); }\n\
+        even(n) { extern odd; if (n == 0) return 1; return odd(n - 1); }\n\
+        main() { extern even; extern first; return even(10) + *first; }\n";
+
+    if let Some(code) = run_through_c("c_order", source, &[]) {
+        assert_eq!(code, 6);
+    }
+}
+
+#[test]
+fn c_output_checks_the_word_size() {
+    let source = "main() {\n    return 0;\n}\n";
+
+    let res = compile("c_word_size", source, &["--emit-c"]);
+    let code = fs::read_to_string(res.dir.join("out")).unwrap();
+    let host = std::mem::size_of::<usize>();
+    assert!(code.contains(&format!("_Static_assert(sizeof(intptr_t) == {0}, \"the program was compiled for {0}-byte words\");", host)), "{}", code);
+
+    let other = if host == 8 { "4" } else { "8" };
+    let res = compile("c_word_size", source, &["--emit-c", "--word-size", other]);
+    let code = fs::read_to_string(res.dir.join("out")).unwrap();
+    assert!(code.contains(&format!("sizeof(intptr_t) == {}", other)), "{}", code);
+
+    let c_file = res.dir.join("out.c");
+    fs::rename(res.dir.join("out"), &c_file).unwrap();
+
+    if let Ok(output) = Command::new("cc").arg("-c").arg(&c_file).arg("-o").arg(res.dir.join("out.o")).output() {
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains(&format!("compiled for {}-byte words", other)));
+    }
+}
+
+#[test]
+fn c_output_commutes_index_operands() {
+    let source = "g[4];\nmain() {\n    extern g;\n    auto a[3];\n    auto i;\n    i = 1;\n    i[a] = 5;\n    (i + 1)[g] = 9;\n    \
+        return a[1] + 2[g] + (i[\"xyz\"] == \"xyz\"[i]);\n}\n";
+
+    if let Some(code) = run_through_c("c_index_commute", source, &[]) {
+        assert_eq!(code, 15);
+    }
+}
+
+const PACKED_CHARS_SOURCE: &str = "main() {
+    auto s[] \"hello\";
+    if (s[1] != 'e' | 4[s] != 'o' | s[5] != 0)
+        return 1;
+    s[1] = 'a' + 256;
+    if (s[1] != 'a' | s[2] != 'l')
+        return 2;
+    s[0]++;
+    return s[0];
+}
+";
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn packed_chars_index_string_buffers_by_byte() {
+    assert_eq!(run_x86_64_with("packed_chars", PACKED_CHARS_SOURCE, &["--packed-chars"]), 'i' as i32);
+}
+
+#[test]
+fn packed_chars_in_c_output() {
+    if let Some(code) = run_through_c("packed_chars_c", PACKED_CHARS_SOURCE, &["--packed-chars"]) {
+        assert_eq!(code, 'i' as i32);
+    }
+}
+
+#[test]
+fn pragma_once_includes_a_file_once() {
+    let dir = scratch("pragma_once");
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    fs::write(dir.join("lib/once.b"), "#pragma once\nonce 1;\n").unwrap();
+    fs::write(dir.join("lib/twice.b"), "twice 2;\n").unwrap();
+
+    // The same file through another path is still the same file.
+    let source = "#include \"lib/once.b\"\n#include \"lib/../lib/once.b\"\n#include \"lib/twice.b\"\n#include \"lib/twice.b\"\n";
+    let res = compile("pragma_once", source, &[]);
+
+    assert!(!res.stderr.contains("'once'"), "{}", res.stderr);
+    assert!(res.stderr.contains("redefinition of global 'twice'"), "{}", res.stderr);
+}
+
+#[test]
+fn unknown_pragma_is_an_error() {
+    let res = compile("pragma_unknown", "#pragma pack\nx 1;\n", &[]);
+
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("in.b:1: error unknown pragma 'pack'"), "{}", res.stderr);
+}
+
+#[test]
+fn wordsize_builtin_is_not_an_lvalue() {
+    let res = compile("wordsize_lvalue", "main() {\n    __wordsize = 2;\n    return __wordsize;\n}\n", &[]);
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("in.b:2: error '__wordsize' is not an lvalue"), "{}", res.stderr);
+    assert!(!res.stderr.contains("in.b:3:"), "{}", res.stderr);
+
+    let res = compile("wordsize_pedantic", "main() {\n    return __wordsize;\n}\n", &["--pedantic"]);
+    assert!(res.stderr.contains("in.b:2: error feature not allowed in pedantic mode: the '__wordsize' builtin"), "{}", res.stderr);
+}
+
+#[test]
+fn trace_parse_logs_balanced_entries_and_exits() {
+    let res = compile("trace_parse", "x 1;\n", &["--trace-parse"]);
+    assert_eq!(res.code, 0, "{}", res.stderr);
+
+    let lines: Vec<&str> = res.stderr.lines().collect();
+    assert_eq!(lines[..3], ["parse_decl at 'x' line 1", "  continue_parse_var_decl at '1' line 1", "    continue_parse_var at '1' line 1"]);
+    assert_eq!(lines.last(), Some(&"end parse_decl"));
+    assert_eq!(lines.iter().filter(|line| line.contains(" at '")).count(), lines.iter().filter(|line| line.trim_start().starts_with("end ")).count());
+
+    let res = compile("trace_parse", "x 1;\n", &[]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn auto_list_declares_each_local() {
+    let source = "main() {\n    auto a 2, v[2] 3, 4, s[] \"hi\", b;\n    b = 5;\n    return a + v[0] + v[1] + (s[0] & 255) - 'h' + b;\n}\n";
+
+    assert_eq!(run_x86_64("auto_list", source), 14);
+}
+
+#[test]
+fn missing_paren_points_at_the_opener() {
+    let source = "main() {\n    return f(1,\n        (2 + 3;\n}\n";
+    let res = compile("unclosed_paren", source, &[]);
+
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("in.b:3: error expected ')' after expression."), "{}", res.stderr);
+
+    let note = &res.stderr[res.stderr.find("in.b:3: note unclosed '(' opened here.").expect(&res.stderr)..];
+    assert_eq!(caret_column(note), "        (".len() + 1);
+}
+
+#[test]
+fn missing_brace_points_at_the_block() {
+    let res = compile("unclosed_block", "main() {\n    if (1) {\n        return 1;\n", &[]);
+
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("in.b:2: note unclosed '{' opened here."), "{}", res.stderr);
+}
+
+#[test]
+fn initializers_must_fold_to_constants() {
+    let source = "g 1 << 4;\nv[2] 2 * 3, -1;\nh 1 + g;\nmain() {\n    auto a (2 + 3) * 2;\n    auto b a;\n    return b;\n}\n";
+    let res = compile("constant_initializers", source, &[]);
+
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("in.b:3: error initializer is not a constant"), "{}", res.stderr);
+    // Locals are initialized at run time, `auto b a` is fine.
+    assert_eq!(res.stderr.matches("error initializer").count(), 1, "{}", res.stderr);
+
+    let res = compile("constant_initializers", "g 1 << 4;\nv[2] 2 * 3, -1;\n", &[]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+
+    let code = fs::read_to_string(res.dir.join("out")).unwrap();
+    assert!(code.contains("g:\n\t.long 16\n"), "{}", code);
+    assert!(code.contains("\t.long 6\n\t.long -1\n"), "{}", code);
+}
+
+#[test]
+fn callgraph_has_an_edge_per_direct_call() {
+    let source = "fp 0;\nleaf() {\n    return 1;\n}\nmid(x) {\n    extern leaf;\n    return leaf() + leaf();\n}\n\
+        main() {\n    extern mid;\n    extern leaf;\n    extern fp;\n    fp();\n    return mid(leaf());\n}\n";
+    let res = compile("callgraph", source, &["--emit=callgraph"]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+
+    // Duplicate calls are one edge, calls through data are unknown.
+    let expected = "digraph callgraph {\n    \"leaf\";\n    \"mid\";\n    \"main\";\n    \"mid\" -> \"leaf\";\n    \"main\" -> \"mid\";\n    \"main\" -> \"leaf\";\n}\n";
+    assert_eq!(fs::read_to_string(res.dir.join("out")).unwrap(), expected);
+}
+
+#[test]
+fn unknown_emit_kind_is_a_usage_error() {
+    let res = compile("emit_unknown", "x 1;\n", &["--emit=llvm"]);
+
+    assert_eq!(res.code, 1);
+    assert!(res.stderr.contains("expected 'asm', 'c', 'callgraph', 'bin' or 'tokens-count' after '--emit='"), "{}", res.stderr);
+}
+
+#[test]
+fn bom_is_not_part_of_the_first_line() {
+    let res = compile("bom", "\u{feff}main() {\n    return x;\n}\n", &[]);
+    assert!(res.stderr.contains("in.b:2: error undefinded reference to 'x'"), "{}", res.stderr);
+
+    let res = compile("bom", "\u{feff}main( {\n}\n", &[]);
+    assert!(res.stderr.contains("in.b:1: error"), "{}", res.stderr);
+    assert!(res.stderr.contains(" 1 | main( {\n"), "{}", res.stderr);
+    assert_eq!(caret_column(&res.stderr), "main( {".len() + 1);
+}
+
+#[test]
+fn include_nesting_is_limited() {
+    let dir = scratch("include_depth");
+    fs::write(dir.join("a.b"), "#include \"b.b\"\n").unwrap();
+    fs::write(dir.join("b.b"), "x 1;\n").unwrap();
+    fs::write(dir.join("self.b"), "#include \"self.b\"\n").unwrap();
+
+    // `in.b` includes `a.b` which includes `b.b`, two levels.
+    let res = compile("include_depth", "#include \"a.b\"\n", &["--max-include-depth", "2"]);
+    assert_eq!(res.code, 0, "{}", res.stderr);
+
+    let res = compile("include_depth", "#include \"a.b\"\n", &["--max-include-depth", "1"]);
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("a.b:1: error include nesting too deep"), "{}", res.stderr);
+
+    // A file including itself stops at the default limit.
+    let res = compile("include_depth", "#include \"self.b\"\n", &[]);
+    assert_eq!(res.code, 2);
+    assert_eq!(res.stderr.matches("include nesting too deep").count(), 1, "{}", res.stderr);
+
+    let res = compile("include_depth", "x 1;\n", &["--max-include-depth", "many"]);
+    assert_eq!(res.code, 1);
+    assert!(res.stderr.contains("expected number after '--max-include-depth'"), "{}", res.stderr);
+}
+
+#[test]
+fn too_large_literal_is_reported_at_the_literal() {
+    let res = compile("literal_too_large", "main() {\n    return 1 + 99999999999;\n}\n", &[]);
+
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("in.b:2: error integer literal is too large"), "{}", res.stderr);
+    assert_eq!(caret_column(&res.stderr), "    return 1 + 9".len() + 1);
+
+    // The most negative word is a literal, one less is not.
+    let res = compile("literal_range", "a -2147483648;\nb 2147483647;\n", &[]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+
+    let res = compile("literal_range", "a -2147483649;\n", &[]);
+    assert!(res.stderr.contains("in.b:1: error integer literal is too large"), "{}", res.stderr);
+}
+
+#[test]
+fn only_validate_writes_no_output() {
+    let dir = scratch("only_validate");
+    let input = dir.join("in.b");
+    fs::write(&input, "x 1;\nmain() {\n    extern x;\n    return x;\n}\n").unwrap();
+
+    let res = run(dir.clone(), &["--only-validate".to_string(), input.display().to_string()]);
+    assert_eq!(res.code, 0, "{}", res.stderr);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+    let res = compile("only_validate_errors", "x 1;\nx 2;\nmain() {\n    return y;\n}\n", &["--only-validate"]);
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("in.b:2: error redefinition of global 'x'"), "{}", res.stderr);
+    assert!(res.stderr.contains("in.b:4: error undefinded reference to 'y'"), "{}", res.stderr);
+    assert!(!res.dir.join("out").exists());
+}
+
+#[test]
+fn ast_dump_keeps_literal_spelling() {
+    let res = compile("ast_dump", "main() {\n    return 'a' + 007;\n}\n", &["--dump-ast"]);
+    assert_eq!(res.code, 0, "{}", res.stderr);
+    assert!(res.stdout.contains("(return (+ 'a' 007))"), "{}", res.stdout);
+}
+
+#[test]
+fn optimized_ast_dump_shows_folded_expressions() {
+    let res = compile("ast_dump_optimized", "main() {\n    return 2 * 3 + 1;\n}\n", &["--dump-ast=optimized"]);
+    assert_eq!(res.code, 0, "{}", res.stderr);
+    assert!(res.stdout.contains("(return 7)"), "{}", res.stdout);
+}
+
+#[test]
+fn ast_graphviz_renders_one_function() {
+    let source = "f() {\n    return 1;\n}\nmain(a) {\n    return a + \"x\";\n}\n";
+
+    let res = compile("ast_graphviz", source, &["--ast-graphviz", "main"]);
+    assert_eq!(res.code, 0, "{}", res.stderr);
+    assert_eq!(res.stdout, concat!(
+        "digraph ast {\n",
+        "    node [shape=box];\n",
+        "    n0 [label=\"function main(a)\"];\n",
+        "    n1 [label=\"block\"];\n",
+        "    n0 -> n1;\n",
+        "    n2 [label=\"return\"];\n",
+        "    n1 -> n2;\n",
+        "    n3 [label=\"+\"];\n",
+        "    n2 -> n3;\n",
+        "    n4 [label=\"a\"];\n",
+        "    n3 -> n4;\n",
+        "    n5 [label=\"\\\"x\\\"\"];\n",
+        "    n3 -> n5;\n",
+        "}\n"
+    ));
+
+    let res = compile("ast_graphviz", source, &["--ast-graphviz", "g"]);
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("function 'g' is not defined"), "{}", res.stderr);
+}
+
+#[test]
+fn escaped_strings_are_quoted_in_assembly() {
+    let source = "s \"h\\\"i\\n\";\n";
+
+    let res = compile("strings_escaped", source, &["--strings=escaped"]);
+    assert_eq!(res.code, 0, "{}", res.stderr);
+    let asm = fs::read_to_string(res.dir.join("out")).unwrap();
+    assert!(asm.contains("\t.ascii \"h\\\"i\\012\\000\"\n"), "{}", asm);
+
+    let res = compile("strings_bytes", source, &[]);
+    let asm = fs::read_to_string(res.dir.join("out")).unwrap();
+    assert!(asm.contains("\t.byte 104, 34, 105, 10, 0\n"), "{}", asm);
+
+    let res = compile("strings_unknown", source, &["--strings=utf16"]);
+    assert_eq!(res.code, 1);
+    assert!(res.stderr.contains("expected 'bytes' or 'escaped' after '--strings='"), "{}", res.stderr);
+}
+
+#[test]
+fn keep_going_writes_the_declarations_without_errors() {
+    let source = "x y;\nbroken() {\n    return z;\n}\ny x;\nmain() {\n    return 1 +;\n}\ngood() {\n    return 7;\n}\n";
+
+    let res = compile("keep_going", source, &["--keep-going"]);
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("compilation failed with 4 errors"), "{}", res.stderr);
+
+    // The globals in the cycle are still written out, as zero.
+    let asm = fs::read_to_string(res.dir.join("out")).unwrap();
+    assert!(asm.contains("x:\n\t.long 0\n"), "{}", asm);
+    assert!(asm.contains("good:"), "{}", asm);
+    assert!(!asm.contains("broken:"), "{}", asm);
+    assert!(!asm.contains("main:"), "{}", asm);
+
+    let res = compile("keep_going_off", source, &[]);
+    assert_eq!(res.code, 2);
+    assert!(!res.dir.join("out").exists());
+}
+
+#[test]
+fn arguments_after_double_dash_are_files() {
+    let dir = scratch("double_dash");
+    fs::write(dir.join("--stats"), "main() {\n    return 0;\n}\n").unwrap();
+
+    let res = Command::new(env!("CARGO_BIN_EXE_blang")).current_dir(&dir).args(["--", "--stats", "-out"]).output().unwrap();
+    assert!(res.status.success(), "{}", String::from_utf8_lossy(&res.stderr));
+    assert!(dir.join("-out").exists());
+
+    let res = Command::new(env!("CARGO_BIN_EXE_blang")).current_dir(&dir).args(["--stats", "-out"]).output().unwrap();
+    assert_eq!(res.status.code(), Some(1));
+}
+
+#[test]
+fn invalid_digit_is_reported_with_the_radix() {
+    let cases = [
+        ("08", "invalid octal digit '8'", "    return 08"),
+        ("09", "invalid octal digit '9'", "    return 09"),
+        ("0xG", "invalid hexadecimal digit 'G'", "    return 0xG"),
+        ("12ab", "invalid decimal digit 'a'", "    return 12a"),
+        ("0x", "expected hexadecimal digits after '0x'", "    return 0")
+    ];
+
+    for (literal, msg, prefix) in cases {
+        let res = compile("invalid_digit", &format!("main() {{\n    return {};\n}}\n", literal), &[]);
+        assert_eq!(res.code, 2);
+        assert!(res.stderr.contains(&format!("in.b:2: error {}.", msg)), "{}", res.stderr);
+        assert_eq!(caret_column(&res.stderr), prefix.len() + 1, "{}", res.stderr);
+    }
+
+    let res = compile("valid_octal", "main() {\n    return 0777 - 0x1ff;\n}\n", &[]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn x86_64_main_receives_argc_and_argv() {
+    let source = "main(argc, argv) {\n    return argc * 10 + (argv[argc] == 0);\n}\n";
+    let res = compile("x86_64_main", source, &["--word-size", "8"]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+
+    let (asm, exe) = (res.dir.join("out.s"), res.dir.join("out.exe"));
+    fs::rename(res.dir.join("out"), &asm).unwrap();
+
+    let output = Command::new("cc").arg("-no-pie").arg("-o").arg(&exe).arg(&asm).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    assert_eq!(Command::new(&exe).args(["a", "b"]).status().unwrap().code(), Some(31));
+}
+
+#[test]
+fn expected_expression_names_the_token() {
+    let cases = [
+        ("return );", "expected expression, found ')'."),
+        ("return / 2;", "expected expression, found '/' (binary operator without a left operand)."),
+        ("return {;", "expected expression, found '{'."),
+        ("return ];", "expected expression, found ']'."),
+        ("return", "expected expression, found '}'.")
+    ];
+
+    for (stmt, msg) in cases {
+        let res = compile("expected_expression", &format!("main() {{\n    {}\n}}\n", stmt), &[]);
+
+        assert_eq!(res.code, 2);
+        assert!(res.stderr.contains(msg), "{}", res.stderr);
+    }
+}
+
+#[test]
+fn binary_output_starts_with_the_header() {
+    let res = compile("emit_bin", "main() {\n    return 42;\n}\n", &["--emit=bin"]);
+    assert_eq!(res.code, 0, "{}", res.stderr);
+
+    let image = fs::read(res.dir.join("out")).unwrap();
+    assert_eq!(image[..8], *b"BTOY\x08\0\0\0");
+
+    let res = compile("emit_bin_64", "main() {\n    return 42;\n}\n", &["--emit=bin", "--word-size", "8"]);
+    assert_eq!(res.code, 1);
+    assert!(res.stderr.contains("'--emit=bin' supports only word size 4"), "{}", res.stderr);
+
+    let res = compile("emit_bin_asm", "main() {\n    asm(\"nop\");\n}\n", &["--emit=bin"]);
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("error: inline assembly can't be assembled for the toy machine."), "{}", res.stderr);
+}
+
+#[test]
+fn statements_at_file_scope() {
+    for source in ["if (1) x;\n", "{ x; }\n", "return 0;\n"] {
+        let res = compile("file_scope_statements", source, &[]);
+
+        assert_eq!(res.code, 2);
+        assert!(res.stderr.contains("in.b:1: error statements are not allowed at file scope"), "{}", res.stderr);
+    }
+
+    let res = compile("file_scope_other", "+ 1;\n", &[]);
+    assert!(res.stderr.contains("in.b:1: error expected declaration"), "{}", res.stderr);
+}
+
+#[test]
+fn profile_prints_every_phase() {
+    let res = compile("profile", "main() {\n    return 1 + 2;\n}\n", &["--profile"]);
+    assert_eq!(res.code, 0, "{}", res.stderr);
+
+    let phases: Vec<&str> = res.stderr.lines().map(|line| line.split_whitespace().next().unwrap()).collect();
+    assert_eq!(phases, ["scanning", "parsing", "validation", "optimization", "codegen", "total"]);
+    assert!(res.stderr.lines().all(|line| line.ends_with(" ms")), "{}", res.stderr);
+
+    let res = compile("profile", "main() {\n    return 1 + 2;\n}\n", &[]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+}
+
+#[test]
+fn output_is_identical_across_runs() {
+    let source = "zeta 1;\nalpha[2] \"a\", \"b\";\nmid &zeta;\nf() {\n    return 1;\n}\nmain() {\n    extern f;\n    return f();\n}\nbeta 3;\n";
+
+    for args in [&[][..], &["--emit-c"], &["--emit=bin"], &["--emit=callgraph"]] {
+        let first = compile("deterministic", source, args);
+        assert_eq!(first.code, 0, "{}", first.stderr);
+        let expected = fs::read(first.dir.join("out")).unwrap();
+
+        for _ in 0..5 {
+            let res = compile("deterministic", source, args);
+            assert_eq!(fs::read(res.dir.join("out")).unwrap(), expected, "{:?}", args);
+        }
+    }
+
+    // Globals are in source order.
+    let res = compile("deterministic_order", source, &[]);
+    let asm = fs::read_to_string(res.dir.join("out")).unwrap();
+    let labels: Vec<usize> = ["zeta:", "alpha:", "mid:", "beta:", "f:", "main:"].iter().map(|label| asm.find(label).unwrap()).collect();
+    assert!(labels[..4].is_sorted() && labels[4] < labels[5], "{}", asm);
+}
+
+#[test]
+fn line_and_file_builtins_fold_at_the_use() {
+    let source = "#define HERE __line\nmain() {\n    auto name __file;\n    return HERE;\n}\n";
+    let res = compile("position_builtins", source, &["--dump-ast=optimized"]);
+    assert_eq!(res.code, 0, "{}", res.stderr);
+    assert!(res.stdout.contains("(return 4)"), "{}", res.stdout);
+    assert!(res.stdout.contains(&format!("(auto (var name {:?}))", res.dir.join("in.b").display().to_string())), "{}", res.stdout);
+
+    let res = compile("line_lvalue", "main() {\n    __line = 2;\n}\n", &[]);
+    assert!(res.stderr.contains("in.b:2: error '__line' is not an lvalue"), "{}", res.stderr);
+
+    let res = compile("file_pedantic", "main() {\n    return __file;\n}\n", &["--pedantic"]);
+    assert!(res.stderr.contains("in.b:2: error feature not allowed in pedantic mode: the '__file' builtin"), "{}", res.stderr);
+}
+
+#[test]
+fn index_offset_overflow() {
+    let source = "main(p) {\n    return p[536870912] + 536870912[p] + p[536870911];\n}\n";
+
+    let res = compile("index_offset_overflow", source, &["--word-size", "4", "--only-validate"]);
+    assert_eq!(res.code, 0);
+    assert_eq!(res.stderr.matches("offset of index 536870912 overflows a 32-bit word").count(), 2, "{}", res.stderr);
+    assert!(!res.stderr.contains("index 536870911"), "{}", res.stderr);
+
+    let res = compile("index_offset_overflow", source, &["--word-size", "8", "--only-validate"]);
+    assert_eq!(res.code, 0);
+    assert!(!res.stderr.contains("overflows"), "{}", res.stderr);
+}
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn assemble_writes_an_object_file() {
+    let res = compile("assemble", "main() {\n    return 7;\n}\n", &["--assemble", "--word-size", "8"]);
+    assert_eq!(res.code, 0, "{}", res.stderr);
+
+    let object = res.dir.join("out");
+    assert_eq!(fs::read(&object).unwrap()[..4], *b"\x7fELF");
+
+    let exe = res.dir.join("out.exe");
+    let output = Command::new("cc").arg("-no-pie").arg("-o").arg(&exe).arg(&object).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(Command::new(&exe).status().unwrap().code(), Some(7));
+}
+
+#[test]
+fn assembler_failures_are_reported() {
+    let source = "main() {\n    return 7;\n}\n";
+
+    let res = compile("assembler_fails", source, &["--assemble", "--assembler", "false"]);
+    assert_eq!(res.code, 1);
+    assert!(res.stderr.contains("error: 'false' failed with exit status: 1"), "{}", res.stderr);
+
+    let res = compile("assembler_missing", source, &["--assemble", "--assembler", "/nonexistent/as"]);
+    assert_eq!(res.code, 1);
+    assert!(res.stderr.contains("error: cannot run '/nonexistent/as'"), "{}", res.stderr);
+
+    let res = compile("assemble_c", source, &["--assemble", "--emit-c"]);
+    assert_eq!(res.code, 1);
+    assert!(res.stderr.contains("'--assemble' needs assembly output"), "{}", res.stderr);
+}
+
+#[test]
+fn too_many_params_warns_above_the_limit() {
+    let source = "f(a, b, c) {\n    return a;\n}\ng(a, b) {\n    return b;\n}\n";
+
+    let res = compile("too_many_params", source, &["-Wtoo-many-params=2"]);
+    assert_eq!(res.code, 0, "{}", res.stderr);
+    assert!(res.stderr.contains("in.b:1: warning function 'f' has 3 parameters, more than 2."), "{}", res.stderr);
+    assert!(!res.stderr.contains("'g'"), "{}", res.stderr);
+
+    let res = compile("too_many_params_off", source, &[]);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+
+    let res = compile("too_many_params_bad", source, &["-Wtoo-many-params=many"]);
+    assert_eq!(res.code, 1);
+    assert!(res.stderr.contains("expected number after '-Wtoo-many-params='"), "{}", res.stderr);
+}
+
+#[test]
+fn warnings_are_turned_off_by_category() {
+    let source = "main(a) {\n    while (0)\n        a = a << 40;\n    return a;\n}\n";
+
+    let res = compile("warning_categories", source, &[]);
+    assert!(res.stderr.contains("loop condition is always false"), "{}", res.stderr);
+    assert!(res.stderr.contains("shift amount out of range"), "{}", res.stderr);
+
+    let res = compile("warning_categories", source, &["-Wno-constant-condition"]);
+    assert!(!res.stderr.contains("loop condition"), "{}", res.stderr);
+    assert!(res.stderr.contains("shift amount out of range"), "{}", res.stderr);
+
+    let res = compile("warning_categories", source, &["-w"]);
+    assert_eq!(res.code, 0);
+    assert!(res.stderr.is_empty(), "{}", res.stderr);
+
+    // Errors are still reported.
+    let res = compile("warning_categories", "main() {\n    while (0);\n    return x;\n}\n", &["-w"]);
+    assert_eq!(res.code, 2);
+    assert!(!res.stderr.contains("loop condition"), "{}", res.stderr);
+    assert!(res.stderr.contains("'x'"), "{}", res.stderr);
+
+    let res = compile("warning_categories", source, &["-Wno-everything"]);
+    assert_eq!(res.code, 1);
+    assert!(res.stderr.contains("unknown warning option"), "{}", res.stderr);
+}
+
+#[test]
+fn show_categories_names_the_diagnostics() {
+    let source = "main() {\n    while (0)\n        break 2;\n    return x;\n}\n";
+
+    let res = compile("show_categories", source, &["--show-categories"]);
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("in.b:2: warning loop condition is always false [constant-condition]."), "{}", res.stderr);
+    assert!(res.stderr.contains("in.b:3: error break 2 exits more loops than the 1 enclosing it [loop-control]."), "{}", res.stderr);
+    assert!(res.stderr.contains("in.b:4: error undefinded reference to 'x' [undefined-reference]."), "{}", res.stderr);
+
+    let res = compile("show_categories", source, &[]);
+    assert!(!res.stderr.contains("[constant-condition]"), "{}", res.stderr);
+}
+
+#[test]
+fn failed_lookahead_rewinds_tokens() {
+    let source = "main() {\n    auto x 1;\n    auto v[] 1, x + 1, 3;\n    return v[1];\n}\n";
+    let res = compile("lookahead_rewind", source, &["--dump-ast"]);
+
+    assert_eq!(res.code, 0, "{}", res.stderr);
+    assert!(res.stdout.contains("(auto (vector v _ 1 (+ x 1) 3))"), "{}", res.stdout);
+}
+
+#[test]
+fn c_style_initializer_is_reported_once() {
+    let res = compile("c_style_initializer", "x = 5;\n", &[]);
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("in.b:1: error unexpected '=' in declaration; initializer follows the name directly"), "{}", res.stderr);
+
+    // The variable is still declared, its uses are not reported.
+    let source = "main() {\n    auto a 1, b = 2;\n    auto v[2] = 3, 4;\n    return a + b + v[1];\n}\n";
+    let res = compile("c_style_initializer", source, &[]);
+    assert_eq!(res.code, 2);
+    assert_eq!(res.stderr.matches("unexpected '=' in declaration").count(), 2, "{}", res.stderr);
+    assert!(res.stderr.contains("compilation failed with 2 errors"), "{}", res.stderr);
+}
+
+#[test]
+fn lookahead_reports_errors_once() {
+    let res = compile("lookahead_error", "main() {\n    auto v[] 1, @;\n    return 0;\n}\n", &[]);
+
+    assert_eq!(res.code, 2);
+    assert_eq!(res.stderr.matches("unrecognized character").count(), 1, "{}", res.stderr);
+    assert!(res.stderr.contains("compilation failed with 1 error"), "{}", res.stderr);
+}
+
+#[test]
+fn tokens_count_counts_without_compiling() {
+    // Not compiled, `y` is undefined.
+    let res = compile("tokens_count", "main() {\n    return y;\n}\n", &["--emit=tokens-count"]);
+
+    assert_eq!(res.code, 0, "{}", res.stderr);
+    assert!(res.stdout.starts_with("8 tokens in 25 bytes\n"), "{}", res.stdout);
+    assert!(res.stdout.contains(" tokens/s\n"), "{}", res.stdout);
+    assert!(!res.dir.join("out").exists());
+}
+
+const LOOP_LEVELS_SOURCE: &str = "main() {
+    auto i;
+    auto j;
+    auto n;
+    i = 0;
+    n = 0;
+    while (i < 5) {
+        i++;
+        j = 0;
+        while (1) {
+            j++;
+            if (j == i)
+                continue 2;
+            if (i == 4)
+                break 2;
+            n++;
+        }
+    }
+    return n * 10 + i;
+}
+";
+
+#[test]
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn break_and_continue_exit_several_loops() {
+    // Inner iterations before `continue 2`: 0 + 1 + 2, then `break 2` at i = 4.
+    assert_eq!(run_x86_64("loop_levels", LOOP_LEVELS_SOURCE), 34);
+}
+
+#[test]
+fn break_and_continue_exit_several_loops_in_c() {
+    if let Some(code) = run_through_c("loop_levels_c", LOOP_LEVELS_SOURCE, &[]) {
+        assert_eq!(code, 34);
+    }
+}
+
+#[test]
+fn loop_levels_are_checked() {
+    let res = compile("loop_levels_too_many", "main() {\n    while (1)\n        break 2;\n}\n", &[]);
+    assert_eq!(res.code, 2);
+    assert!(res.stderr.contains("in.b:3: error break 2 exits more loops than the 1 enclosing it"), "{}", res.stderr);
+
+    let res = compile("loop_levels_zero", "main() {\n    while (1)\n        continue 0;\n}\n", &[]);
+    assert!(res.stderr.contains("in.b:3: error expected a positive number of loops"), "{}", res.stderr);
+
+    let res = compile("loop_levels_pedantic", "main() {\n    while (1)\n        break 1;\n}\n", &["--pedantic"]);
+    assert!(res.stderr.contains("feature not allowed in pedantic mode: 'break N' and 'continue N'"), "{}", res.stderr);
+}